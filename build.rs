@@ -0,0 +1,28 @@
+//! Puts the git commit into the version string (WINBOAT_VERSION), so a deployed server.exe
+//! can be matched to the source it was built from. Builds outside a git checkout get the
+//! plain package version.
+
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let version = std::env::var("CARGO_PKG_VERSION").unwrap_or_default();
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=10", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|commit| !commit.is_empty());
+    match commit {
+        Some(commit) => println!("cargo:rustc-env=WINBOAT_VERSION={} ({})", version, commit),
+        None => println!("cargo:rustc-env=WINBOAT_VERSION={}", version),
+    }
+
+    // Rebuild when the checked-out commit moves; a missing path would rerun this every build
+    for path in [".git/HEAD", ".git/refs", ".git/packed-refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
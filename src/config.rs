@@ -0,0 +1,264 @@
+//! Optional `winboat.toml` configuration file.
+//!
+//! The file holds the same settings as the WINBOAT_* variables, grouped by side. It is the
+//! lowest layer: a variable set in the environment or an .env file wins over the file, and
+//! command-line flags win over both. Loading it only fills in the variables that are still
+//! unset, so every setting is still read from its WINBOAT_* variable. The variables the file
+//! filled in are remembered: `reload` takes them back before reading the env files and this
+//! file again, so an edit to the file replaces its old values, and a variable the user set
+//! still wins over it.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// File name looked for in the .env directories when `--config` isn't given.
+pub const DEFAULT_FILE_NAME: &str = "winboat.toml";
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSection,
+    pub client: ClientSection,
+    pub bootstrap: BootstrapSection,
+    pub keepalive: KeepaliveSection,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub port: Option<u16>,
+    pub bind_addr: Option<String>,
+    pub socket: Option<String>,
+    pub auth_token: Option<String>,
+    pub admin_token: Option<String>,
+    pub dangerous_patterns: Option<Vec<String>>,
+    pub allowed_cwds: Option<Vec<String>>,
+    pub allowlist: Option<String>,
+    pub post_hook: Option<String>,
+    pub max_concurrent_per_identity: Option<u32>,
+    pub max_concurrent: Option<u32>,
+    pub busy_wait: Option<u64>,
+    pub job_memory_mb: Option<u64>,
+    pub job_max_processes: Option<u32>,
+    pub max_rate_per_conn: Option<u64>,
+    pub buf_size: Option<usize>,
+    pub request_log: Option<String>,
+    pub queue_file: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSection {
+    pub port: Option<u16>,
+    pub socket: Option<String>,
+    pub auth_token: Option<String>,
+    pub admin_token: Option<String>,
+    pub tls: Option<bool>,
+    pub tls_ca: Option<String>,
+    pub tls_server_name: Option<String>,
+    pub buf_size: Option<usize>,
+    /// `name = "command"` pairs, as WINBOAT_ALIAS_<NAME>
+    pub aliases: BTreeMap<String, String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootstrapSection {
+    /// `winrm`, `ssh` or `off`, as WINBOAT_BOOTSTRAP
+    pub transport: Option<String>,
+    pub exe_path: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub ssh_port: Option<u16>,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub log_path: Option<String>,
+    pub err_path: Option<String>,
+    pub bind_addr: Option<String>,
+    pub timeout: Option<u64>,
+    pub post_wait: Option<u64>,
+    pub max_attempts: Option<u32>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepaliveSection {
+    /// Server heartbeat interval
+    pub secs: Option<u64>,
+    pub idle: Option<u64>,
+    pub interval: Option<u64>,
+    pub count: Option<u32>,
+}
+
+/// Which side's sections apply; the other side's settings are left alone.
+#[derive(Clone, Copy)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+impl Config {
+    /// Reads `path`, or the first `winboat.toml` found in `dirs` when no path is given.
+    /// A missing default file is not an error; a missing explicit one is.
+    pub fn load(path: Option<&Path>, dirs: &[PathBuf]) -> Result<Option<(Self, PathBuf)>> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match dirs.iter().map(|dir| dir.join(DEFAULT_FILE_NAME)).find(|path| path.is_file()) {
+                Some(path) => path,
+                None => return Ok(None),
+            },
+        };
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let config = toml::from_str(&text).with_context(|| format!("Invalid config file {}", path.display()))?;
+        Ok(Some((config, path)))
+    }
+
+    /// Sets the WINBOAT_* variable of every value in the file that the environment doesn't
+    /// already define, and returns the ones it set.
+    fn apply_to_env(&self, role: Role) -> Vec<String> {
+        let mut defaults = Defaults::default();
+        self.fill_defaults(role, &mut defaults);
+        defaults.set
+    }
+
+    fn fill_defaults(&self, role: Role, defaults: &mut Defaults) {
+        let keepalive = &self.keepalive;
+        defaults.set("WINBOAT_KEEPALIVE_SECS", keepalive.secs);
+        defaults.set("WINBOAT_KEEPALIVE_IDLE", keepalive.idle);
+        defaults.set("WINBOAT_KEEPALIVE_INTERVAL", keepalive.interval);
+        defaults.set("WINBOAT_KEEPALIVE_COUNT", keepalive.count);
+
+        match role {
+            Role::Server => {
+                let server = &self.server;
+                defaults.set("WINBOAT_SERVER_PORT", server.port);
+                defaults.set("WINBOAT_BIND_ADDR", server.bind_addr.as_ref());
+                defaults.set("WINBOAT_SOCKET", server.socket.as_ref());
+                defaults.set("WINBOAT_AUTH_TOKEN", server.auth_token.as_ref());
+                defaults.set("WINBOAT_ADMIN_TOKEN", server.admin_token.as_ref());
+                defaults.set("WINBOAT_DANGEROUS_PATTERNS", server.dangerous_patterns.as_ref().map(|p| p.join(",")));
+                defaults.set("WINBOAT_ALLOWED_CWDS", server.allowed_cwds.as_ref().map(|c| c.join(",")));
+                defaults.set("WINBOAT_ALLOWLIST", server.allowlist.as_ref());
+                defaults.set("WINBOAT_POST_HOOK", server.post_hook.as_ref());
+                defaults.set("WINBOAT_MAX_CONCURRENT_PER_IDENTITY", server.max_concurrent_per_identity);
+                defaults.set("WINBOAT_MAX_CONCURRENT", server.max_concurrent);
+                defaults.set("WINBOAT_BUSY_WAIT", server.busy_wait);
+                defaults.set("WINBOAT_JOB_MEMORY_MB", server.job_memory_mb);
+                defaults.set("WINBOAT_JOB_MAX_PROCESSES", server.job_max_processes);
+                defaults.set("WINBOAT_MAX_RATE_PER_CONN", server.max_rate_per_conn);
+                defaults.set("WINBOAT_BUF_SIZE", server.buf_size);
+                defaults.set("WINBOAT_REQUEST_LOG", server.request_log.as_ref());
+                defaults.set("WINBOAT_QUEUE_FILE", server.queue_file.as_ref());
+                defaults.set("WINBOAT_TLS_CERT", server.tls_cert.as_ref());
+                defaults.set("WINBOAT_TLS_KEY", server.tls_key.as_ref());
+            }
+            Role::Client => {
+                let client = &self.client;
+                defaults.set("WINBOAT_CLIENT_PORT", client.port);
+                defaults.set("WINBOAT_SOCKET", client.socket.as_ref());
+                defaults.set("WINBOAT_AUTH_TOKEN", client.auth_token.as_ref());
+                defaults.set("WINBOAT_ADMIN_TOKEN", client.admin_token.as_ref());
+                defaults.set("WINBOAT_TLS", client.tls.map(|on| if on { "1" } else { "0" }));
+                defaults.set("WINBOAT_TLS_CA", client.tls_ca.as_ref());
+                defaults.set("WINBOAT_TLS_SERVER_NAME", client.tls_server_name.as_ref());
+                defaults.set("WINBOAT_BUF_SIZE", client.buf_size);
+                for (name, command) in &client.aliases {
+                    let var = format!("WINBOAT_ALIAS_{}", name.to_ascii_uppercase().replace('-', "_"));
+                    defaults.set(&var, Some(command));
+                }
+
+                let bootstrap = &self.bootstrap;
+                defaults.set("WINBOAT_BOOTSTRAP", bootstrap.transport.as_ref());
+                defaults.set("WINBOAT_EXE_PATH", bootstrap.exe_path.as_ref());
+                defaults.set("WINBOAT_HOST", bootstrap.host.as_ref());
+                defaults.set("WINBOAT_PORT", bootstrap.port);
+                defaults.set("WINBOAT_SSH_PORT", bootstrap.ssh_port);
+                defaults.set("WINBOAT_USER", bootstrap.user.as_ref());
+                defaults.set("WINBOAT_PASS", bootstrap.pass.as_ref());
+                defaults.set("WINBOAT_LOG_PATH", bootstrap.log_path.as_ref());
+                defaults.set("WINBOAT_ERR_PATH", bootstrap.err_path.as_ref());
+                defaults.set("WINBOAT_BIND_ADDR", bootstrap.bind_addr.as_ref());
+                defaults.set("WINBOAT_BOOTSTRAP_TIMEOUT", bootstrap.timeout);
+                defaults.set("WINBOAT_BOOTSTRAP_POSTWAIT", bootstrap.post_wait);
+                defaults.set("WINBOAT_MAX_ATTEMPTS", bootstrap.max_attempts);
+            }
+        }
+    }
+}
+
+/// What the file put in the environment: the `--config` path it came from, if one was
+/// given, and the variables it set, as opposed to ones that were there before it.
+struct Applied {
+    path: Option<PathBuf>,
+    vars: Vec<String>,
+}
+
+static APPLIED: Mutex<Applied> = Mutex::new(Applied { path: None, vars: Vec::new() });
+
+/// Loads the file as `Config::load` does and fills in the variables it sets that are still
+/// unset. Returns the file's path, if there was one.
+pub fn load_into_env(path: Option<&Path>, dirs: &[PathBuf], role: Role) -> Result<Option<PathBuf>> {
+    let loaded = Config::load(path, dirs)?;
+    let mut applied = APPLIED.lock().unwrap();
+    applied.path = path.map(Path::to_path_buf);
+    applied.vars = loaded.as_ref().map(|(config, _)| config.apply_to_env(role)).unwrap_or_default();
+    Ok(loaded.map(|(_, path)| path))
+}
+
+/// Loads the file again for `reload`: the same `--config` path, or the default file. Once
+/// it has parsed, the variables the last load set are taken back, `reload_env_files` runs,
+/// and the file fills in what is still unset, as at startup. A file that doesn't parse
+/// leaves everything as it was.
+pub fn reload_into_env(dirs: &[PathBuf], role: Role, reload_env_files: impl FnOnce()) -> Result<Option<PathBuf>> {
+    let mut applied = APPLIED.lock().unwrap();
+    let loaded = Config::load(applied.path.as_deref(), dirs)?;
+    for name in applied.vars.drain(..) {
+        std::env::remove_var(name);
+    }
+    reload_env_files();
+    applied.vars = loaded.as_ref().map(|(config, _)| config.apply_to_env(role)).unwrap_or_default();
+    Ok(loaded.map(|(_, path)| path))
+}
+
+/// The variables one `apply_to_env` set.
+#[derive(Default)]
+struct Defaults {
+    set: Vec<String>,
+}
+
+impl Defaults {
+    fn set(&mut self, name: &str, value: Option<impl ToString>) {
+        if let Some(value) = value {
+            if std::env::var_os(name).is_none() {
+                std::env::set_var(name, value.to_string());
+                self.set.push(name.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_record_only_the_variables_they_set() {
+        std::env::set_var("WINBOAT_TEST_FROM_USER", "user");
+        std::env::remove_var("WINBOAT_TEST_FROM_FILE");
+        let mut defaults = Defaults::default();
+        defaults.set("WINBOAT_TEST_FROM_USER", Some("file"));
+        defaults.set("WINBOAT_TEST_FROM_FILE", Some("file"));
+        defaults.set("WINBOAT_TEST_UNSET", None::<&str>);
+
+        assert_eq!(defaults.set, ["WINBOAT_TEST_FROM_FILE"]);
+        assert_eq!(std::env::var("WINBOAT_TEST_FROM_USER").unwrap(), "user");
+        assert_eq!(std::env::var("WINBOAT_TEST_FROM_FILE").unwrap(), "file");
+        assert!(std::env::var_os("WINBOAT_TEST_UNSET").is_none());
+    }
+}
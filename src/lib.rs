@@ -0,0 +1,5911 @@
+//! WinBoat Bridge: runs commands on a Windows machine from Linux over TCP.
+//!
+//! The `winboat-bridge` binary is a thin command-line wrapper around this crate:
+//! `run_server` is the Windows side, `run_command` the Linux side. Both read their settings
+//! from the WINBOAT_* variables, so call `load_settings` first to pick up .env files and
+//! `winboat.toml` the way the binary does.
+
+use clap::ValueEnum;
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::pin::Pin;
+use std::task::Poll;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, Semaphore};
+use std::env;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// `println!` for server diagnostics that drops the line instead of panicking when stdout
+/// can't be written (e.g. the redirected log file's disk is full). Secrets are masked, see
+/// `redact_secrets`.
+macro_rules! log_println {
+    ($($arg:tt)*) => {{
+        use std::io::Write;
+        let _ = writeln!(std::io::stdout(), "{}", $crate::redact_secrets(&format!($($arg)*)));
+    }};
+}
+
+/// `eprintln!` counterpart of `log_println!`.
+macro_rules! log_eprintln {
+    ($($arg:tt)*) => {{
+        use std::io::Write;
+        let _ = writeln!(std::io::stderr(), "{}", $crate::redact_secrets(&format!($($arg)*)));
+    }};
+}
+
+mod config;
+pub use config::Role;
+mod logging;
+#[cfg(feature = "queue")]
+mod queue;
+#[cfg(feature = "tls")]
+mod tls;
+
+#[cfg(target_os = "windows")]
+mod win_job {
+    use winapi::um::jobapi2::{CreateJobObjectW, AssignProcessToJobObject, SetInformationJobObject, TerminateJobObject};
+    use winapi::um::winnt::{JobObjectExtendedLimitInformation, JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE, HANDLE};
+    use winapi::um::winnt::{JOB_OBJECT_LIMIT_ACTIVE_PROCESS, JOB_OBJECT_LIMIT_PROCESS_MEMORY};
+    use winapi::um::winnt::{JobObjectCpuRateControlInformation, JOBOBJECT_CPU_RATE_CONTROL_INFORMATION, JOB_OBJECT_CPU_RATE_CONTROL_ENABLE, JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP};
+    use std::ptr;
+    use std::mem;
+    use anyhow::Result;
+    use super::JobLimits;
+
+    // Returns the Job Handle. The Job Object is closed when the handle is dropped (if not leaked),
+    // but we want it to persist until we drop it or the process ends.
+    // Actually, if we drop the handle, and LIMIT_KILL_ON_JOB_CLOSE is set, the process dies?
+    // Yes, "If the job has the JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE flag, closing the last handle to the job object terminates all processes associated with the job."
+    // So we need to keep this handle alive as long as the child is alive.
+    pub struct JobHandle(HANDLE);
+    
+    // Send/Sync for Arc? HANDLE is raw pointer basically.
+    unsafe impl Send for JobHandle {}
+    unsafe impl Sync for JobHandle {}
+
+    impl Drop for JobHandle {
+        fn drop(&mut self) {
+            unsafe { winapi::um::handleapi::CloseHandle(self.0); }
+        }
+    }
+
+    impl JobHandle {
+        /// Terminates every process in the job right away, without waiting for the handle to close.
+        pub fn terminate(&self) {
+            unsafe { TerminateJobObject(self.0, 1); }
+        }
+
+        /// Hard-caps the CPU time of all processes in the job to `percent` (1-100) of the machine.
+        pub fn set_cpu_rate_limit(&self, percent: u8) -> Result<()> {
+            unsafe {
+                let mut info: JOBOBJECT_CPU_RATE_CONTROL_INFORMATION = mem::zeroed();
+                info.ControlFlags = JOB_OBJECT_CPU_RATE_CONTROL_ENABLE | JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP;
+                // Expressed in hundredths of a percent
+                *info.u.CpuRate_mut() = u32::from(percent) * 100;
+
+                let ret = SetInformationJobObject(
+                    self.0,
+                    JobObjectCpuRateControlInformation,
+                    &mut info as *mut _ as *mut _,
+                    mem::size_of_val(&info) as u32,
+                );
+                if ret == 0 {
+                    return Err(anyhow::anyhow!("Failed to set job CPU rate limit"));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Puts the process in a new job that kills everything in it when the handle closes,
+    /// with the optional `limits` on top.
+    pub fn assign_to_new_job(process_handle: std::os::windows::io::RawHandle, limits: &JobLimits) -> Result<JobHandle> {
+        unsafe {
+            let job = CreateJobObjectW(ptr::null_mut(), ptr::null());
+            if job.is_null() {
+                 return Err(anyhow::anyhow!("Failed to create job object"));
+            }
+            
+            let handle_wrapper = JobHandle(job);
+
+            let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+            info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+            if let Some(bytes) = limits.process_memory {
+                info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+                info.ProcessMemoryLimit = bytes;
+            }
+            if let Some(count) = limits.active_processes {
+                info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_ACTIVE_PROCESS;
+                info.BasicLimitInformation.ActiveProcessLimit = count;
+            }
+
+            let ret = SetInformationJobObject(
+                job,
+                JobObjectExtendedLimitInformation,
+                &mut info as *mut _ as *mut _,
+                mem::size_of_val(&info) as u32,
+            );
+            
+            if ret == 0 {
+                 return Err(anyhow::anyhow!("Failed to set job info"));
+            }
+
+            let ret = AssignProcessToJobObject(job, process_handle as HANDLE);
+             if ret == 0 {
+                 return Err(anyhow::anyhow!("Failed to assign process to job"));
+            }
+            
+            Ok(handle_wrapper)
+        }
+    }
+}
+
+/// Package version plus the git commit it was built from, when known (see build.rs).
+pub const VERSION: &str = env!("WINBOAT_VERSION");
+
+/// Version of the wire protocol this build speaks. The server announces it in its
+/// `READY <version>` line, and the client answers with a `PROTO <version>` line naming the
+/// lower of its own and the server's, which both ends then speak. A change an older peer
+/// would misread gets the next version and a `Protocol` method the code checks.
+const PROTOCOL_VERSION: u32 = 2;
+
+/// Prefix of the line the client answers the server's READY with.
+const PROTO_PREFIX: &str = "PROTO ";
+
+/// Protocol version agreed for one connection, see `PROTOCOL_VERSION`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Protocol(u32);
+
+impl Protocol {
+    /// Peers from before versioning: the server sends a bare `READY`, the client no `PROTO` line.
+    const LEGACY: Self = Self(0);
+    const CURRENT: Self = Self(PROTOCOL_VERSION);
+
+    /// What this build speaks with a peer announcing `theirs`.
+    fn negotiate(theirs: u32) -> Self {
+        Self(theirs.min(PROTOCOL_VERSION))
+    }
+
+    /// Version 1: an `OPT` line the server doesn't know is refused rather than ignored, so a
+    /// newer client never gets output in a format it didn't ask for.
+    fn refuses_unknown_options(self) -> bool {
+        self.0 >= 1
+    }
+
+    /// Version 2: the `__EXIT__` line ends with `run_ms=<n>`, how long the command ran. An
+    /// older client would take it for the exit reason.
+    fn reports_run_time(self) -> bool {
+        self.0 >= 2
+    }
+}
+
+/// Options sent by the client as `OPT <name>` lines ahead of the command.
+#[derive(Default, Clone)]
+pub struct RequestOptions {
+    /// Run the command even if it matches WINBOAT_DANGEROUS_PATTERNS
+    pub confirm_destructive: bool,
+    /// Report the spawned argv in an `__INVOCATION__` line before the output
+    pub show_invocation: bool,
+    /// The body is a JSON request and the response uses length-prefixed JSON events
+    pub json: bool,
+    /// Console window title, applied with cmd's `title` builtin (ignored elsewhere)
+    pub title: Option<String>,
+    /// Name of a Windows named pipe that also receives the output
+    pub output_pipe: Option<String>,
+    /// Who the client says it is; logged for attribution only, never trusted
+    pub identity: Option<String>,
+    /// Directory to run the command in, instead of the server's own
+    pub cwd: Option<String>,
+    /// Run the command in a fresh temporary directory
+    pub scratch_dir: bool,
+    /// Keep the scratch directory after the command ends
+    pub keep_scratch: bool,
+    /// Report the output byte counts in a `__STATS__` line after the output
+    pub stats: bool,
+    /// Put the command's stdout on the server's clipboard when it ends
+    pub to_clipboard: bool,
+    /// CPU cap for the command's Job Object, in percent (1-100)
+    pub cpu_percent: Option<u8>,
+    /// Send the child's stderr to the null device instead of a pipe
+    pub no_stderr: bool,
+    /// Send the child's stdout to the null device instead of a pipe
+    pub stderr_only: bool,
+    /// Add the command to the persistent queue instead of running it
+    pub enqueue: bool,
+    /// Number of leading output chunks the server coalesces into one write
+    pub initial_batch: Option<u16>,
+    /// Send output in whole lines, see `forward_lines`
+    pub line_buffered: bool,
+    /// Report the command's exit code in an `__EXIT__` line at the very end
+    pub exit_code: bool,
+    /// Send output and report lines as frames tagged with their stream (see `encode_frame`)
+    pub framed: bool,
+    /// Kill the command after this many seconds
+    pub timeout: Option<u64>,
+    /// Kill the command once it has produced this many bytes of output, stdout and stderr together
+    pub max_output: Option<u64>,
+    /// Shell to run the command line with, instead of the platform's own (`Shell::DEFAULT`)
+    pub shell: Option<Shell>,
+    /// Start the command with only `CLEAN_ENV_KEEP` from the server's environment
+    pub clean_env: bool,
+    /// Feed the client's stdin frames to the command (framed requests only)
+    pub stdin: bool,
+    /// Shared secret sent in an `AUTH` line ahead of the options; never logged
+    pub auth_token: Option<String>,
+    /// Operator secret for the control requests that stop commands or the server, sent in
+    /// an `OPT admin-token` line; never logged
+    pub admin_token: Option<String>,
+    /// `KEY=VALUE` variables set for the command, sent as `ENV` lines after the options
+    pub env: Vec<String>,
+    /// Keep the connection open after the command for more `CMD` lines, each answered up to
+    /// its `__EXIT__` line and run with these same options
+    pub session: bool,
+    /// Keep the command running if the connection drops, so the client can reconnect and
+    /// resume its output (see `RESUME_PREFIX`)
+    pub resumable: bool,
+}
+
+/// Prefix of the line the server sends ahead of the output when `show_invocation` is requested.
+const INVOCATION_PREFIX: &str = "__INVOCATION__ ";
+
+/// Prefix of the line reporting the directory the command runs in, sent with `show_invocation`.
+const CWD_PREFIX: &str = "__CWD__ ";
+
+/// Prefix of the line the server sends ahead of the output when `scratch_dir` is requested.
+const SCRATCH_PREFIX: &str = "__SCRATCH__ ";
+
+/// Prefix of the line the server sends after the output when `stats` is requested.
+const STATS_PREFIX: &str = "__STATS__ ";
+
+/// Prefix of the line naming the token to resume a `resumable` command's output with; it
+/// comes before the output.
+const RESUME_TOKEN_PREFIX: &str = "__RESUME__ ";
+
+/// Prefix of the line the server sends after the output when `max_output` cut it short.
+const TRUNCATED_PREFIX: &str = "__TRUNCATED__ ";
+
+/// Prefix of the last line the server sends when `exit_code` is requested.
+const EXIT_PREFIX: &str = "__EXIT__ ";
+
+impl RequestOptions {
+    fn encode(&self) -> String {
+        let mut header = String::new();
+        if let Some(token) = &self.auth_token {
+            header.push_str(&format!("AUTH {}\n", token.trim()));
+        }
+        if let Some(token) = &self.admin_token {
+            header.push_str(&format!("OPT admin-token {}\n", token.trim()));
+        }
+        if self.confirm_destructive {
+            header.push_str("OPT confirm-destructive\n");
+        }
+        if self.show_invocation {
+            header.push_str("OPT show-invocation\n");
+        }
+        if self.json {
+            header.push_str("OPT json\n");
+        }
+        if let Some(title) = &self.title {
+            header.push_str(&format!("OPT title {}\n", title.replace(['\r', '\n'], " ")));
+        }
+        if let Some(name) = &self.output_pipe {
+            header.push_str(&format!("OPT output-pipe {}\n", name));
+        }
+        if let Some(identity) = &self.identity {
+            header.push_str(&format!("OPT identity {}\n", identity.replace(['\r', '\n'], " ")));
+        }
+        if let Some(cwd) = &self.cwd {
+            header.push_str(&format!("OPT cwd {}\n", cwd.replace(['\r', '\n'], " ")));
+        }
+        if self.scratch_dir {
+            header.push_str("OPT scratch-dir\n");
+        }
+        if self.keep_scratch {
+            header.push_str("OPT keep-scratch\n");
+        }
+        if self.stats {
+            header.push_str("OPT stats\n");
+        }
+        if self.to_clipboard {
+            header.push_str("OPT to-clipboard\n");
+        }
+        if let Some(percent) = self.cpu_percent {
+            header.push_str(&format!("OPT cpu-percent {}\n", percent));
+        }
+        if self.no_stderr {
+            header.push_str("OPT no-stderr\n");
+        }
+        if self.stderr_only {
+            header.push_str("OPT stderr-only\n");
+        }
+        if self.enqueue {
+            header.push_str("OPT enqueue\n");
+        }
+        if let Some(chunks) = self.initial_batch {
+            header.push_str(&format!("OPT initial-batch {}\n", chunks));
+        }
+        if self.line_buffered {
+            header.push_str("OPT line-buffered\n");
+        }
+        if self.exit_code {
+            header.push_str("OPT exit-code\n");
+        }
+        if self.framed {
+            header.push_str("OPT framed\n");
+        }
+        if let Some(secs) = self.timeout {
+            header.push_str(&format!("OPT timeout {}\n", secs));
+        }
+        if let Some(bytes) = self.max_output {
+            header.push_str(&format!("OPT max-output {}\n", bytes));
+        }
+        if let Some(shell) = self.shell {
+            header.push_str(&format!("OPT shell {}\n", shell.name()));
+        }
+        if self.clean_env {
+            header.push_str("OPT clean-env\n");
+        }
+        if self.stdin {
+            header.push_str("OPT stdin\n");
+        }
+        if self.session {
+            header.push_str("OPT session\n");
+        }
+        if self.resumable {
+            header.push_str("OPT resumable\n");
+        }
+        for assignment in &self.env {
+            header.push_str(&format!("ENV {}\n", assignment));
+        }
+        header
+    }
+}
+
+/// Largest request the server accepts. Well above what Windows allows for a command line
+/// (32767 characters), so only runaway or hostile requests hit it.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+/// Size of each read while a request comes in.
+const REQUEST_READ_CHUNK: usize = 4096;
+
+/// How long the server waits for more of a request that isn't terminated yet, from a client
+/// that negotiated no protocol version. Those clients predate the newline terminator and send
+/// the command bare, so it is taken as complete then. A versioned client always ends its
+/// request, so the server waits for the end however long it takes to arrive.
+const REQUEST_IDLE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Reads a whole request, even when it arrives split over several TCP segments.
+/// `versioned` says the client already negotiated a protocol version (a session's later
+/// requests, which have no `PROTO` line); otherwise a `PROTO` line starting the request does.
+/// Returns an empty request, or just the `PROTO` line, if the client disconnected without
+/// sending one, and with the request anything the client sent right after it (e.g. the first
+/// stdin frames). A request cut off at `MAX_REQUEST_LEN` is returned as is, and so is one
+/// whose client stopped before its end; see `request_truncated` and `request_unterminated`.
+async fn read_request<S: AsyncRead + Unpin>(socket: &mut S, versioned: bool) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut request = Vec::new();
+    let mut buf = [0; REQUEST_READ_CHUNK];
+    while request_end(&request).is_none() && request.len() < MAX_REQUEST_LEN {
+        let limit = (MAX_REQUEST_LEN - request.len()).min(REQUEST_READ_CHUNK);
+        // Also waits while the `PROTO` line itself may still be coming
+        let n = if versioned || request.starts_with(PROTO_PREFIX.as_bytes()) || PROTO_PREFIX.as_bytes().starts_with(&request) {
+            socket.read(&mut buf[..limit]).await?
+        } else {
+            match tokio::time::timeout(REQUEST_IDLE_TIMEOUT, socket.read(&mut buf[..limit])).await {
+                Ok(n) => n?,
+                Err(_) => break,
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let extra = match request_end(&request) {
+        Some(end) => request.split_off(end),
+        None => Vec::new(),
+    };
+    Ok((request, extra))
+}
+
+/// Whether `read_request` stopped at the size limit before the request was complete.
+fn request_truncated(request: &[u8]) -> bool {
+    request.len() >= MAX_REQUEST_LEN && request_end(request).is_none()
+}
+
+/// Whether a versioned client (see `read_request`) closed the connection partway through a
+/// request. The part that came might be a different command than the whole, so it never runs.
+fn request_unterminated(request: &[u8], versioned: bool) -> bool {
+    (versioned || request.starts_with(PROTO_PREFIX.as_bytes()))
+        && !skip_protocol_line(request).is_empty()
+        && request_end(request).is_none()
+        && !request_truncated(request)
+}
+
+/// Refusal of a request `request_unterminated` caught.
+const INCOMPLETE_REQUEST: &str = "Incomplete request: the connection ended before the request's final newline, so nothing was run.";
+
+/// Longest `discard_pending_input` keeps reading.
+const DISCARD_MAX_TIME: Duration = Duration::from_secs(2);
+
+/// Reads and drops the rest of an oversized request, so closing the socket afterwards
+/// doesn't reset the connection before the client has read the refusal.
+async fn discard_pending_input(socket: &mut Connection) {
+    let started = Instant::now();
+    let mut buf = [0; REQUEST_READ_CHUNK];
+    while started.elapsed() < DISCARD_MAX_TIME {
+        match tokio::time::timeout(REQUEST_IDLE_TIMEOUT, socket.read(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => {}
+            _ => break,
+        }
+    }
+}
+
+/// Where a request ends, once it is complete: after the newline ending the command that
+/// follows the option and `ENV` lines or, for JSON requests, after the JSON document.
+fn request_end(request: &[u8]) -> Option<usize> {
+    let mut rest = skip_protocol_line(request);
+    let mut json = false;
+    if rest.starts_with(b"AUTH ") {
+        let end = rest.iter().position(|b| *b == b'\n')?;
+        rest = &rest[end + 1..];
+    }
+    while rest.starts_with(b"OPT ") || rest.starts_with(b"ENV ") {
+        let end = rest.iter().position(|b| *b == b'\n')?;
+        json |= rest[..end].trim_ascii() == b"OPT json";
+        rest = &rest[end + 1..];
+    }
+    let offset = request.len() - rest.len();
+    if json {
+        let mut documents = serde_json::Deserializer::from_slice(rest).into_iter::<serde::de::IgnoredAny>();
+        match documents.next() {
+            Some(Ok(_)) => Some(offset + documents.byte_offset()),
+            _ => None,
+        }
+    } else {
+        rest.iter().position(|b| *b == b'\n').map(|end| offset + end + 1)
+    }
+}
+
+/// A request split into its parts by `parse_request`.
+struct ParsedRequest {
+    /// From the client's `PROTO` line; `Protocol::LEGACY` when it sent none
+    protocol: Protocol,
+    options: RequestOptions,
+    body: String,
+    /// Names of the `OPT` lines this server doesn't know
+    unknown_options: Vec<String>,
+}
+
+/// `request` after the client's `PROTO` line, once that line is complete. A client sends it
+/// as soon as it has read READY, so it can arrive well before the request proper.
+fn skip_protocol_line(request: &[u8]) -> &[u8] {
+    match request.strip_prefix(PROTO_PREFIX.as_bytes()) {
+        Some(line) => line.iter().position(|b| *b == b'\n').map_or(request, |end| &line[end + 1..]),
+        None => request,
+    }
+}
+
+/// Splits a raw request into its protocol line, option lines and the command itself.
+/// Requests without option lines (older clients) are returned unchanged.
+fn parse_request(raw: &str) -> ParsedRequest {
+    let mut options = RequestOptions::default();
+    let mut unknown_options = Vec::new();
+    let mut rest = raw;
+    let mut protocol = Protocol::LEGACY;
+    if let Some(proto_line) = rest.strip_prefix(PROTO_PREFIX) {
+        let (version, tail) = proto_line.split_once('\n').unwrap_or((proto_line, ""));
+        match version.trim().parse::<u32>() {
+            Ok(version) => protocol = Protocol::negotiate(version),
+            Err(_) => log_eprintln!("Ignoring invalid protocol version: {}", version.trim()),
+        }
+        rest = tail;
+    }
+    if let Some(auth_line) = rest.strip_prefix("AUTH ") {
+        let (token, tail) = auth_line.split_once('\n').unwrap_or((auth_line, ""));
+        options.auth_token = Some(token.trim().to_string());
+        rest = tail;
+    }
+    while let Some(option_line) = rest.strip_prefix("OPT ") {
+        let (line, tail) = option_line.split_once('\n').unwrap_or((option_line, ""));
+        let (name, value) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        match name {
+            "confirm-destructive" => options.confirm_destructive = true,
+            "show-invocation" => options.show_invocation = true,
+            "json" => options.json = true,
+            "title" => options.title = Some(value.to_string()),
+            "output-pipe" => options.output_pipe = Some(value.to_string()),
+            "identity" => options.identity = Some(value.to_string()),
+            "cwd" => options.cwd = Some(value.to_string()),
+            "scratch-dir" => options.scratch_dir = true,
+            "keep-scratch" => options.keep_scratch = true,
+            "stats" => options.stats = true,
+            "to-clipboard" => options.to_clipboard = true,
+            "cpu-percent" => match value.parse::<u8>() {
+                Ok(percent) if (1..=100).contains(&percent) => options.cpu_percent = Some(percent),
+                _ => log_eprintln!("Ignoring invalid cpu-percent value: {}", value),
+            },
+            "no-stderr" => options.no_stderr = true,
+            "stderr-only" => options.stderr_only = true,
+            "enqueue" => options.enqueue = true,
+            "initial-batch" => match value.parse::<u16>() {
+                Ok(chunks) => options.initial_batch = Some(chunks.min(MAX_INITIAL_BATCH)),
+                Err(_) => log_eprintln!("Ignoring invalid initial-batch value: {}", value),
+            },
+            "line-buffered" => options.line_buffered = true,
+            "exit-code" => options.exit_code = true,
+            "framed" => options.framed = true,
+            "shell" => match Shell::from_str(value, true) {
+                Ok(shell) => options.shell = Some(shell),
+                Err(_) => log_eprintln!("Ignoring unknown shell: {}", value),
+            },
+            "max-output" => match value.parse::<u64>() {
+                Ok(bytes) => options.max_output = Some(bytes),
+                Err(_) => log_eprintln!("Ignoring invalid max-output value: {}", value),
+            },
+            "timeout" => match value.parse::<u64>() {
+                Ok(secs) => options.timeout = (secs > 0).then_some(secs),
+                Err(_) => log_eprintln!("Ignoring invalid timeout value: {}", value),
+            },
+            "clean-env" => options.clean_env = true,
+            "stdin" => options.stdin = true,
+            "session" => options.session = true,
+            "resumable" => options.resumable = true,
+            "admin-token" => options.admin_token = Some(value.to_string()),
+            other => unknown_options.push(other.to_string()),
+        }
+        rest = tail;
+    }
+    while let Some(env_line) = rest.strip_prefix("ENV ") {
+        let (assignment, tail) = env_line.split_once('\n').unwrap_or((env_line, ""));
+        options.env.push(assignment.trim_end_matches('\r').to_string());
+        rest = tail;
+    }
+    ParsedRequest { protocol, options, body: rest.trim().to_string(), unknown_options }
+}
+
+/// Splits an `ENV` line's `KEY=VALUE`. The value runs to the end of the line, spaces
+/// included; one pair of surrounding quotes is removed, as in an .env file.
+fn parse_env_assignment(assignment: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = assignment.split_once('=') else {
+        return Err(format!("Invalid ENV line '{}': expected KEY=VALUE", assignment));
+    };
+    let key = key.trim();
+    if key.is_empty() || key.contains(char::is_whitespace) || assignment.contains('\0') {
+        return Err(format!("Invalid ENV line '{}': the name must be non-empty, without spaces or NUL", assignment));
+    }
+    let value = ['"', '\'']
+        .iter()
+        .find_map(|quote| value.strip_prefix(*quote).and_then(|v| v.strip_suffix(*quote)))
+        .unwrap_or(value);
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// What to run for a request, however the request was encoded.
+struct CommandSpec {
+    command_line: String,
+    cwd: Option<String>,
+    env: HashMap<String, String>,
+    timeout: Option<Duration>,
+}
+
+impl CommandSpec {
+    fn new(command_line: String) -> Self {
+        Self {
+            command_line,
+            cwd: None,
+            env: HashMap::new(),
+            timeout: None,
+        }
+    }
+}
+
+/// Protocol version reported in the `started` event of the JSON variant.
+const JSON_PROTOCOL_VERSION: u32 = 1;
+
+/// Request body of the JSON protocol variant, selected with `OPT json`.
+#[derive(Deserialize)]
+struct JsonRequest {
+    command: String,
+    #[serde(default)]
+    cwd: Option<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Seconds; absent or 0 means no timeout
+    #[serde(default)]
+    timeout: u64,
+    #[serde(default)]
+    confirm_destructive: bool,
+    #[serde(default)]
+    scratch_dir: bool,
+    #[serde(default)]
+    keep_scratch: bool,
+    #[serde(default)]
+    clean_env: bool,
+}
+
+impl From<JsonRequest> for CommandSpec {
+    fn from(request: JsonRequest) -> Self {
+        Self {
+            command_line: request.command.trim().to_string(),
+            cwd: request.cwd,
+            env: request.env,
+            timeout: (request.timeout > 0).then(|| Duration::from_secs(request.timeout)),
+        }
+    }
+}
+
+/// Response events of the JSON protocol variant.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum JsonEvent<'a> {
+    Started {
+        version: u32,
+        pid: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        scratch_dir: Option<&'a str>,
+    },
+    Stdout { data: &'a str },
+    Stderr { data: &'a str },
+    Exit {
+        code: Option<i32>,
+        timed_out: bool,
+        reason: Option<&'a str>,
+        stdout_bytes: u64,
+        stderr_bytes: u64,
+        /// From starting the command to its exit
+        run_ms: u64,
+    },
+    Error { message: &'a str },
+}
+
+impl<'a> JsonEvent<'a> {
+    fn output(stream: OutputStream, data: &'a str) -> Self {
+        match stream {
+            OutputStream::Stdout => JsonEvent::Stdout { data },
+            OutputStream::Stderr => JsonEvent::Stderr { data },
+        }
+    }
+}
+
+/// Encodes an event as a u32 big-endian length followed by the JSON document.
+fn encode_json_event(event: &JsonEvent) -> Vec<u8> {
+    let payload = serde_json::to_vec(event).expect("JSON events always serialize");
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+async fn write_json_event<W: AsyncWrite + Unpin>(writer: &mut W, event: &JsonEvent<'_>) -> std::io::Result<()> {
+    writer.write_all(&encode_json_event(event)).await
+}
+
+/// Appends `data` to `pending` and returns the longest prefix that is complete UTF-8,
+/// keeping a trailing partial character for the next chunk. Invalid bytes are replaced.
+fn take_utf8(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+    let complete = match std::str::from_utf8(pending) {
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        _ => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..complete]).into_owned();
+    pending.drain(..complete);
+    text
+}
+
+/// Builds the text handed to the shell. A requested title is applied by prefixing cmd's
+/// `title` builtin; other shells run the command unchanged.
+fn shell_command_text(command_line: &str, shell: Shell, options: &RequestOptions) -> String {
+    match &options.title {
+        Some(title) if shell == Shell::Cmd => {
+            // Escape cmd metacharacters so the title can't chain extra commands
+            let mut escaped = String::with_capacity(title.len());
+            for c in title.chars() {
+                if matches!(c, '^' | '&' | '|' | '<' | '>') {
+                    escaped.push('^');
+                }
+                escaped.push(c);
+            }
+            format!("title {} && {}", escaped, command_line)
+        }
+        _ => command_line.to_string(),
+    }
+}
+
+/// Name of the local user, sent as the default request identity.
+pub fn local_username() -> Option<String> {
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .ok()
+        .filter(|name| !name.trim().is_empty())
+}
+
+/// Shared secret the client presents to the server, from WINBOAT_AUTH_TOKEN.
+pub fn client_auth_token() -> Option<String> {
+    env::var("WINBOAT_AUTH_TOKEN").ok().map(|t| t.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+/// Operator secret the client presents with control requests, from WINBOAT_ADMIN_TOKEN.
+pub fn client_admin_token() -> Option<String> {
+    env::var("WINBOAT_ADMIN_TOKEN").ok().map(|t| t.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+/// Directories searched for env files, in the order they are tried.
+fn env_dir_candidates() -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+
+    // 1. Current working directory
+    if let Ok(cwd) = std::env::current_dir() {
+        candidates.push(cwd);
+    }
+
+    if let Ok(exe_path) = std::env::current_exe() {
+        if let Some(exe_dir) = exe_path.parent() {
+            // 2. Executable directory
+            candidates.push(exe_dir.to_path_buf());
+
+            // 3. Project root: if we're in target/release or target/debug, go up two levels
+            if exe_dir.ends_with("release") || exe_dir.ends_with("debug") {
+                if let Some(project_root) = exe_dir.parent().and_then(|target_dir| target_dir.parent()) {
+                    candidates.push(project_root.to_path_buf());
+                }
+            }
+        }
+    }
+
+    candidates
+}
+
+/// Env files layered in order, later ones overriding earlier ones: the comma-separated
+/// WINBOAT_ENV_FILES if set, otherwise `.env`, `.env.local` and, when WINBOAT_ENV is set,
+/// `.env.<WINBOAT_ENV>`. Relative names are resolved in the directory being searched.
+fn env_file_layers() -> Vec<String> {
+    if let Ok(files) = env::var("WINBOAT_ENV_FILES") {
+        return files
+            .split(',')
+            .map(str::trim)
+            .filter(|file| !file.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+
+    let mut layers = vec![".env".to_string(), ".env.local".to_string()];
+    if let Some(name) = env::var("WINBOAT_ENV").ok().filter(|name| !name.trim().is_empty()) {
+        layers.push(format!(".env.{}", name.trim()));
+    }
+    layers
+}
+
+/// Loads the settings for one side of the bridge: the env files, then `config_path` (or the
+/// default `winboat.toml`) for whatever they left unset. Call it before `run_server` or
+/// `run_command`, which read their settings from the environment.
+pub fn load_settings(config_path: Option<&Path>, role: Role) -> Result<()> {
+    load_env_files(false);
+    if let Some(path) = config::load_into_env(config_path, &env_dir_candidates(), role)? {
+        log_eprintln!("[DEBUG] Loaded config from: {}", path.display());
+    }
+    Ok(())
+}
+
+/// Reads the env files and the config file again for `reload`, with the same precedence as
+/// `load_settings`: values the config file set before give way to its new ones, while
+/// variables set outside it keep winning.
+fn reload_settings() -> Result<()> {
+    let reload_env_files = || {
+        load_env_files(true);
+    };
+    if let Some(path) = config::reload_into_env(&env_dir_candidates(), Role::Server, reload_env_files)? {
+        log_eprintln!("[DEBUG] Reloaded config from: {}", path.display());
+    }
+    Ok(())
+}
+
+/// Loads the env file layers from the first directory where any of them loads, and returns
+/// the files used. Variables already set in the process win over file values, unless
+/// `override_existing` is set, which is how `reload` picks up edits.
+fn load_env_files(override_existing: bool) -> Vec<PathBuf> {
+    let layers = env_file_layers();
+    let dirs = env_dir_candidates();
+
+    for dir in &dirs {
+        let mut values = HashMap::new();
+        let mut loaded = Vec::new();
+        for layer in &layers {
+            let path = dir.join(layer);
+            if !path.exists() {
+                continue;
+            }
+            match dotenvy::from_path_iter(&path).and_then(|pairs| pairs.collect::<Result<Vec<_>, _>>()) {
+                Ok(pairs) => {
+                    log_eprintln!("[DEBUG] Loaded .env from: {}", path.display());
+                    // Later layers override earlier ones
+                    values.extend(pairs);
+                    loaded.push(path);
+                }
+                Err(e) => {
+                    log_eprintln!("[DEBUG] Failed to load .env from {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        if !loaded.is_empty() {
+            for (key, value) in values {
+                if override_existing || env::var_os(&key).is_none() {
+                    env::set_var(key, value);
+                }
+            }
+            return loaded;
+        }
+    }
+
+    log_eprintln!("[WARNING] No .env file found in any of these locations:");
+    for dir in &dirs {
+        for layer in &layers {
+            log_eprintln!("  - {}", dir.join(layer).display());
+        }
+    }
+    log_eprintln!("Using defaults or system environment variables.");
+    Vec::new()
+}
+
+/// Server settings read from the environment. The live copy is swapped atomically by the
+/// `reload` command; each connection works on the snapshot it took when it started.
+struct ServerConfig {
+    dangerous_patterns: Vec<String>,
+    max_rate_per_conn: Option<u64>,
+    keepalive: KeepaliveConfig,
+    /// Output silence after which a framed connection gets an empty report frame; `None` disables it
+    heartbeat: Option<Duration>,
+    /// Canonical directory roots commands may run in; empty means anywhere
+    allowed_cwds: Vec<PathBuf>,
+    /// Shell command run after every command, see `run_post_hook`
+    post_hook: Option<String>,
+    max_concurrent_per_identity: Option<usize>,
+    /// How long a request waits for a free command slot before it gets `BUSY`; zero refuses at once
+    busy_wait: Duration,
+    /// Shared secret every request must present in its `AUTH` line; `None` leaves the server open
+    auth_token: Option<String>,
+    /// Secret the control requests that stop commands or the server must present on top of
+    /// `auth_token`; `None` leaves them to every client
+    admin_token: Option<String>,
+    /// Entries of the WINBOAT_ALLOWLIST file; `None` when no allowlist is configured
+    allowlist: Option<Vec<String>>,
+    /// Resource caps for every command's Job Object (Windows only)
+    job_limits: JobLimits,
+    /// Read size for command output and the client socket, see `buf_size_from_env`
+    buf_size: usize,
+}
+
+/// Optional resource caps applied to the Job Object of every command, on top of
+/// kill-on-close, so a runaway command can't take the whole VM down.
+#[derive(Clone, Copy, Default)]
+struct JobLimits {
+    /// Most memory one process of the command may commit, in bytes (WINBOAT_JOB_MEMORY_MB)
+    process_memory: Option<usize>,
+    /// Most processes the command may have running at once, itself included (WINBOAT_JOB_MAX_PROCESSES)
+    active_processes: Option<u32>,
+}
+
+impl JobLimits {
+    fn from_env() -> Self {
+        Self {
+            process_memory: env::var("WINBOAT_JOB_MEMORY_MB")
+                .ok()
+                .and_then(|mb| mb.trim().parse::<usize>().ok())
+                .filter(|mb| *mb > 0)
+                .map(|mb| mb.saturating_mul(1024 * 1024)),
+            active_processes: env::var("WINBOAT_JOB_MAX_PROCESSES")
+                .ok()
+                .and_then(|n| n.trim().parse::<u32>().ok())
+                .filter(|n| *n > 0),
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        self.process_memory.is_some() || self.active_processes.is_some()
+    }
+}
+
+impl std::fmt::Display for JobLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut limits = Vec::new();
+        if let Some(bytes) = self.process_memory {
+            limits.push(format!("{} MiB of memory per process", bytes / (1024 * 1024)));
+        }
+        if let Some(count) = self.active_processes {
+            limits.push(format!("{} processes", count));
+        }
+        write!(f, "{}", limits.join(", "))
+    }
+}
+
+impl ServerConfig {
+    fn from_env() -> Self {
+        let dangerous_patterns = env::var("WINBOAT_DANGEROUS_PATTERNS")
+            .map(|patterns| {
+                patterns
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|p| !p.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let max_rate_per_conn = env::var("WINBOAT_MAX_RATE_PER_CONN")
+            .ok()
+            .and_then(|r| r.parse::<u64>().ok())
+            .filter(|r| *r > 0);
+
+        let allowed_cwds = env::var("WINBOAT_ALLOWED_CWDS")
+            .map(|roots| {
+                roots
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|r| !r.is_empty())
+                    .map(|root| {
+                        // A root that can't be resolved stays as written, so it matches nothing
+                        // rather than disappearing and leaving the list open
+                        std::fs::canonicalize(root).unwrap_or_else(|e| {
+                            log_eprintln!("[WARNING] WINBOAT_ALLOWED_CWDS entry '{}' can't be resolved: {}", root, e);
+                            PathBuf::from(root)
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            dangerous_patterns,
+            max_rate_per_conn,
+            keepalive: KeepaliveConfig::from_env(),
+            heartbeat: Some(
+                env::var("WINBOAT_KEEPALIVE_SECS")
+                    .ok()
+                    .and_then(|secs| secs.trim().parse::<u64>().ok())
+                    .unwrap_or(DEFAULT_HEARTBEAT_SECS),
+            )
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+            allowed_cwds,
+            post_hook: env::var("WINBOAT_POST_HOOK").ok().filter(|h| !h.trim().is_empty()),
+            max_concurrent_per_identity: env::var("WINBOAT_MAX_CONCURRENT_PER_IDENTITY")
+                .ok()
+                .and_then(|n| n.trim().parse::<usize>().ok())
+                .filter(|n| *n > 0),
+            busy_wait: Duration::from_secs(
+                env::var("WINBOAT_BUSY_WAIT")
+                    .ok()
+                    .and_then(|secs| secs.trim().parse::<u64>().ok())
+                    .unwrap_or(DEFAULT_BUSY_WAIT_SECS),
+            ),
+            auth_token: env::var("WINBOAT_AUTH_TOKEN").ok().map(|t| t.trim().to_string()).filter(|t| !t.is_empty()),
+            admin_token: env::var("WINBOAT_ADMIN_TOKEN").ok().map(|t| t.trim().to_string()).filter(|t| !t.is_empty()),
+            allowlist: env::var("WINBOAT_ALLOWLIST").ok().filter(|p| !p.trim().is_empty()).map(|path| load_allowlist(&path)),
+            job_limits: JobLimits::from_env(),
+            buf_size: buf_size_from_env(),
+        }
+    }
+
+    /// Whether a command may run in `dir`. The path is canonicalized first, so `..` and
+    /// symlinks can't escape an allowed root.
+    fn is_cwd_allowed(&self, dir: &Path) -> bool {
+        if self.allowed_cwds.is_empty() {
+            return true;
+        }
+        match std::fs::canonicalize(dir) {
+            Ok(dir) => self.allowed_cwds.iter().any(|root| dir.starts_with(root)),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the first dangerous pattern found in the command (case-insensitive).
+    fn match_dangerous_pattern(&self, command_line: &str) -> Option<&str> {
+        let command = command_line.to_lowercase();
+        self.dangerous_patterns
+            .iter()
+            .find(|p| command.contains(&p.to_lowercase()))
+            .map(String::as_str)
+    }
+
+    /// Whether the allowlist lets the command run in `shell` (case-insensitive). An entry
+    /// ending in `*` allows every command starting with the rest of it, as long as what follows
+    /// has none of the shell's metacharacters: `ipconfig*` must not also allow
+    /// `ipconfig & del ...`. Any other entry allows only that exact command; a lone `*` allows
+    /// everything.
+    fn is_allowed(&self, command_line: &str, shell: Shell) -> bool {
+        let Some(allowlist) = &self.allowlist else {
+            return true;
+        };
+        let command = command_line.trim().to_lowercase();
+        allowlist.iter().any(|entry| {
+            let entry = entry.to_lowercase();
+            match entry.strip_suffix('*') {
+                Some("") => true,
+                Some(prefix) => command.strip_prefix(prefix).is_some_and(|rest| !rest.contains(shell.metacharacters())),
+                None => command == entry,
+            }
+        })
+    }
+
+    /// Whether the request may set `name` in the command's environment. Under an allowlist,
+    /// PATH and PATHEXT are off limits: they decide which program an allowed name starts.
+    fn allows_env(&self, name: &str) -> bool {
+        let Some(allowlist) = &self.allowlist else {
+            return true;
+        };
+        allowlist.iter().any(|entry| entry == "*") || !(name.eq_ignore_ascii_case("PATH") || name.eq_ignore_ascii_case("PATHEXT"))
+    }
+}
+
+/// Reads the allowlist file: one entry per line, blank lines and `#` comments skipped. A file
+/// that can't be read gives an empty list, so the server refuses everything rather than
+/// silently running without the allowlist.
+fn load_allowlist(path: &str) -> Vec<String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect(),
+        Err(e) => {
+            log_eprintln!("[WARNING] WINBOAT_ALLOWLIST file '{}' can't be read, denying all commands: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Size of the read buffers on the output path when WINBOAT_BUF_SIZE isn't set.
+const DEFAULT_BUF_SIZE: usize = 64 * 1024;
+/// Bounds WINBOAT_BUF_SIZE is clamped to.
+const BUF_SIZE_RANGE: std::ops::RangeInclusive<usize> = 1024..=16 * 1024 * 1024;
+
+/// Bytes read at a time from the command's pipes, the client socket and, on the client, the
+/// server socket (WINBOAT_BUF_SIZE). Bigger reads mean fewer frames and syscalls for bulk
+/// output; interactive output still goes out as soon as it is read.
+fn buf_size_from_env() -> usize {
+    match env::var("WINBOAT_BUF_SIZE") {
+        Ok(value) => parse_buf_size(&value).unwrap_or_else(|| {
+            log_eprintln!("[WARNING] Invalid WINBOAT_BUF_SIZE '{}', using {} bytes", value, DEFAULT_BUF_SIZE);
+            DEFAULT_BUF_SIZE
+        }),
+        Err(_) => DEFAULT_BUF_SIZE,
+    }
+}
+
+/// A WINBOAT_BUF_SIZE value in bytes, clamped to `BUF_SIZE_RANGE`; `None` if it isn't a number.
+fn parse_buf_size(value: &str) -> Option<usize> {
+    let size = value.trim().parse::<usize>().ok()?;
+    Some(size.clamp(*BUF_SIZE_RANGE.start(), *BUF_SIZE_RANGE.end()))
+}
+
+/// Seconds of output silence before a heartbeat frame when WINBOAT_KEEPALIVE_SECS isn't set.
+const DEFAULT_HEARTBEAT_SECS: u64 = 30;
+
+/// OS-level TCP keepalive settings, applied on both ends so a peer that vanished (e.g. a
+/// NAT or firewall dropped the mapping) is detected even while no output is flowing.
+#[derive(Clone, Copy)]
+struct KeepaliveConfig {
+    idle: Duration,
+    interval: Duration,
+    count: u32,
+}
+
+impl KeepaliveConfig {
+    fn from_env() -> Self {
+        let secs = |var: &str, default: u64| {
+            env::var(var)
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .filter(|v| *v > 0)
+                .map_or(Duration::from_secs(default), Duration::from_secs)
+        };
+        let count = env::var("WINBOAT_KEEPALIVE_COUNT")
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .filter(|v| *v > 0)
+            .unwrap_or(5);
+
+        Self {
+            idle: secs("WINBOAT_KEEPALIVE_IDLE", 60),
+            interval: secs("WINBOAT_KEEPALIVE_INTERVAL", 10),
+            count,
+        }
+    }
+
+    fn apply(&self, socket: &TcpStream) -> std::io::Result<()> {
+        let keepalive = socket2::TcpKeepalive::new()
+            .with_time(self.idle)
+            .with_interval(self.interval);
+        // Windows always sends 10 probes; the count is not configurable there
+        #[cfg(not(windows))]
+        let keepalive = keepalive.with_retries(self.count);
+        socket2::SockRef::from(socket).set_tcp_keepalive(&keepalive)
+    }
+}
+
+impl std::fmt::Display for KeepaliveConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "idle {}s, interval {}s, {} probes",
+            self.idle.as_secs(),
+            self.interval.as_secs(),
+            self.count
+        )
+    }
+}
+
+/// How `run_server` listens. WINBOAT_SERVER_PORT and WINBOAT_BIND_ADDR still apply on top.
+pub struct ServerOptions {
+    /// Port used when WINBOAT_SERVER_PORT isn't set
+    pub port: u16,
+    /// Address to listen on; WINBOAT_BIND_ADDR, else 127.0.0.1, when `None`
+    pub bind: Option<IpAddr>,
+    /// Stop after one connection has been handled
+    pub once: bool,
+    /// Check at startup that closing a Job Object kills its processes (Windows)
+    pub verify_job: bool,
+    /// When the port is taken, kill whatever listens on it, not only an older winboat-bridge (Windows)
+    pub force_kill: bool,
+    /// Gets the address once the server listens on TCP: the library's `LISTENING` line, for
+    /// finding the port the OS picked for port 0
+    pub listening: Option<tokio::sync::oneshot::Sender<SocketAddr>>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self { port: 5330, bind: None, once: false, verify_job: false, force_kill: false, listening: None }
+    }
+}
+
+/// Runs the server until it is told to quit (or, with `once`, after one connection), then
+/// waits for the running commands as a graceful shutdown does.
+pub async fn run_server(options: ServerOptions) -> Result<()> {
+    let ServerOptions { port, bind, once, verify_job, force_kill, listening } = options;
+
+    // Force UTF-8 code page on Windows
+    #[cfg(target_os = "windows")]
+    {
+        let _ = Command::new("cmd").args(["/C", "chcp 65001"]).output().await;
+    }
+
+    if verify_job {
+        #[cfg(target_os = "windows")]
+        match verify_job_cleanup().await {
+            Ok(()) => log_println!("[verify-job] Job Object cleanup works: the test process was terminated with its job."),
+            Err(e) => log_eprintln!(
+                "[WARNING] [verify-job] Job Object cleanup is not working here: {:#}. Stopped or disconnected commands may leave processes running.",
+                e
+            ),
+        }
+        #[cfg(not(target_os = "windows"))]
+        log_println!("[verify-job] Skipped: Job Objects only exist on Windows.");
+    }
+
+    let actual_port = env::var("WINBOAT_SERVER_PORT")
+        .ok()
+        .and_then(|p| p.parse::<u16>().ok())
+        .unwrap_or(port);
+    
+    // Loopback only unless asked otherwise, so the bridge isn't exposed on every interface
+    let ip = match bind {
+        Some(ip) => ip,
+        None => env_bind_addr()?.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+    };
+    let addr = SocketAddr::new(ip, actual_port).to_string();
+
+    // Same-host deployments can skip TCP: no port is taken and the file's permissions decide who connects
+    let socket_path = socket_path_from_env()?;
+    let (listener, bound_port) = match &socket_path {
+        #[cfg(unix)]
+        Some(path) => {
+            let listener = bind_unix_socket(path)?;
+            log_println!("Server listening on unix:{}", path.display());
+            (Listener::Unix(listener), None)
+        }
+        _ => {
+            let listener = bind_tcp_listener(&addr, actual_port, force_kill).await?;
+            // With port 0 the OS picks one; the LISTENING line is how a supervising process
+            // finds out which
+            let local_addr = listener.local_addr().context("Failed to read the listening address")?;
+            log_println!("Server listening on {}", local_addr);
+            log_println!("{}{}", LISTENING_PREFIX, local_addr.port());
+            if let Some(listening) = listening {
+                let _ = listening.send(local_addr);
+            }
+            (Listener::Tcp(listener), Some(local_addr.port()))
+        }
+    };
+    // The marker is how the bootstrap guard finds a local server by port
+    let _marker = bound_port.map(InstanceMarker::create);
+
+    // Persistent Server Mode
+    let state = Arc::new(ServerState {
+        shutdown_signal: Notify::new(),
+        config: ArcSwap::from_pointee(ServerConfig::from_env()),
+        commands: CommandRegistry::default(),
+        active_connections: AtomicUsize::new(0),
+        connections_idle: Notify::new(),
+        draining: AtomicBool::new(false),
+        draining_signal: Notify::new(),
+        next_request_id: AtomicU64::new(0),
+        resumable: ResumableOutputs::default(),
+        request_log: logging::RequestLog::from_env()?,
+        identity_slots: Mutex::default(),
+        command_slots: command_slots_from_env()?,
+        #[cfg(feature = "queue")]
+        queue: Arc::new(queue::JobQueue::load(queue::queue_file_path())?),
+        #[cfg(feature = "tls")]
+        tls: tls::acceptor_from_env()?,
+    });
+    #[cfg(feature = "tls")]
+    if state.tls.is_some() {
+        if socket_path.is_some() {
+            anyhow::bail!("TLS (WINBOAT_TLS_CERT) can't be used with a Unix socket (WINBOAT_SOCKET)");
+        }
+        log_println!("TLS enabled, plain connections will be refused");
+    }
+    #[cfg(not(feature = "tls"))]
+    if env::var_os("WINBOAT_TLS_CERT").is_some() {
+        anyhow::bail!("WINBOAT_TLS_CERT is set, but this server was built without TLS support (feature \"tls\")");
+    }
+    if socket_path.is_none() {
+        log_println!("TCP keepalive: {}", state.config.load().keepalive);
+    }
+    if let Some(heartbeat) = state.config.load().heartbeat {
+        log_println!("Heartbeat: after {}s without output (framed clients)", heartbeat.as_secs());
+    }
+    if let Some(log) = &state.request_log {
+        log_println!("Request log: {}", log.path().display());
+    }
+    match &state.command_slots {
+        Some(slots) => log_println!("Concurrent commands: up to {}", slots.available_permits()),
+        None => log_println!("Concurrent commands: unlimited"),
+    }
+    let job_limits = state.config.load().job_limits;
+    if job_limits.is_set() {
+        #[cfg(target_os = "windows")]
+        log_println!("Job limits per command: {}", job_limits);
+        #[cfg(not(target_os = "windows"))]
+        log_eprintln!("[WARNING] Job limits ({}) only apply to Windows servers and are ignored here", job_limits);
+    }
+
+    #[cfg(feature = "queue")]
+    {
+        let queue = state.queue.clone();
+        tokio::spawn(async move {
+            let result = supervise("job queue", || {
+                let queue = queue.clone();
+                async move { queue.run().await }
+            })
+            .await;
+            if let Err(e) = result {
+                log_eprintln!("[queue] Worker stopped, queued jobs will not run: {:#}", e);
+            }
+        });
+    }
+
+    // Ctrl+C, or SIGTERM from a service manager, goes through the same graceful shutdown as
+    // `ctrl shutdown` instead of killing the server with its commands' clients mid-output
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let signal = termination_signal().await;
+            log_println!("{} received, shutting down...", signal);
+            state.shutdown_signal.notify_one();
+            // The handler replaced the default one, so a second signal has to cut the wait short
+            let signal = termination_signal().await;
+            let killed = state.commands.kill_all();
+            log_eprintln!("{} received again, killed {} running command(s).", signal, killed);
+        });
+    }
+
+    let listener = Arc::new(listener);
+    supervise("accept loop", || accept_loop(listener.clone(), state.clone(), once)).await?;
+
+    drain_connections(&state).await;
+    if let Some(path) = &socket_path {
+        let _ = std::fs::remove_file(path);
+    }
+    log_println!("Server shutting down.");
+    Ok(())
+}
+
+/// Line the server prints once it listens on TCP, followed by the port, e.g. `LISTENING 5330`.
+const LISTENING_PREFIX: &str = "LISTENING ";
+
+/// Binds the TCP listener on `addr`. On Windows a port still held by an earlier server is
+/// reclaimed (see `kill_listener_on_port_windows`); port 0 asks the OS for a free one and
+/// never goes through that.
+async fn bind_tcp_listener(addr: &str, port: u16, force_kill: bool) -> Result<TcpListener> {
+    // Bind with Windows-friendly recovery on AddrInUse (os error 10048)
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) if e.kind() == ErrorKind::AddrInUse && port != 0 => {
+            #[cfg(target_os = "windows")]
+            {
+                let listeners = find_port_listeners_windows(port).await.unwrap_or_default();
+                log_eprintln!("{}", describe_bind_conflict(addr, &listeners));
+                log_eprintln!("Port {} already in use. Attempting to terminate existing listener and retry...", port);
+                kill_listener_on_port_windows(port, force_kill).await?;
+                
+                // Wait a bit more for socket to be fully released
+                log_println!("Waiting additional 1 second for socket release...");
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                
+                match TcpListener::bind(addr).await {
+                    Ok(l) => l,
+                    Err(e2) if e2.kind() == ErrorKind::AddrInUse => {
+                        let listeners = find_port_listeners_windows(port).await.unwrap_or_default();
+                        return Err(anyhow::anyhow!(
+                            "Port {} is still in use after kill attempt ({}). Please close the existing process and retry. Underlying error: {}",
+                            port,
+                            describe_bind_conflict(addr, &listeners),
+                            e2
+                        ));
+                    }
+                    Err(e2) => return Err(e2.into()),
+                }
+            }
+            #[cfg(not(target_os = "windows"))]
+            {
+                let _ = (port, force_kill);
+                return Err(e.into());
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+    Ok(listener)
+}
+
+/// WINBOAT_SOCKET: the path of a Unix socket to use instead of TCP, on both sides.
+fn socket_path_from_env() -> Result<Option<PathBuf>> {
+    match env::var_os("WINBOAT_SOCKET").filter(|path| !path.is_empty()) {
+        None => Ok(None),
+        Some(path) if cfg!(unix) => Ok(Some(PathBuf::from(path))),
+        Some(_) => anyhow::bail!("WINBOAT_SOCKET is set, but Unix sockets are only supported on Linux and macOS"),
+    }
+}
+
+/// Binds the Unix socket at `path`. A socket file left behind by a server that didn't
+/// shut down cleanly is replaced; one a live server still answers on is not.
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", path.display());
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            anyhow::bail!("Another server is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path).with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    tokio::net::UnixListener::bind(path).with_context(|| format!("Failed to listen on {}", path.display()))
+}
+
+/// What the server accepts connections on.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// The next connection, with TCP keepalive enabled on TCP ones. Unix socket peers have
+    /// no address; they are on this host, so they are given the loopback address with port 0.
+    async fn accept(&self, state: &ServerState) -> std::io::Result<(Connection, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, peer) = listener.accept().await?;
+                if let Err(e) = state.config.load().keepalive.apply(&socket) {
+                    log_eprintln!("[WARNING] Failed to enable TCP keepalive: {}", e);
+                }
+                Ok((Connection::Plain(socket), peer))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Connection::Unix(socket), SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)))
+            }
+        }
+    }
+}
+
+/// Commands the server runs at once when WINBOAT_MAX_CONCURRENT isn't set.
+const DEFAULT_MAX_CONCURRENT: usize = 8;
+
+/// Seconds a request waits for a command slot when WINBOAT_BUSY_WAIT isn't set.
+const DEFAULT_BUSY_WAIT_SECS: u64 = 10;
+
+/// The server-wide command slots from WINBOAT_MAX_CONCURRENT, or `None` when it is 0.
+fn command_slots_from_env() -> Result<Option<Semaphore>> {
+    let limit = match env::var("WINBOAT_MAX_CONCURRENT") {
+        Ok(value) => value
+            .trim()
+            .parse::<usize>()
+            .with_context(|| format!("WINBOAT_MAX_CONCURRENT must be a number (0 = unlimited), got '{}'", value))?,
+        Err(_) => DEFAULT_MAX_CONCURRENT,
+    };
+    Ok((limit > 0).then(|| Semaphore::new(limit)))
+}
+
+/// WINBOAT_BIND_ADDR, if set.
+fn env_bind_addr() -> Result<Option<IpAddr>> {
+    match env::var("WINBOAT_BIND_ADDR") {
+        Ok(value) if !value.trim().is_empty() => value.trim().parse().map(Some).with_context(|| {
+            format!("Invalid WINBOAT_BIND_ADDR '{}': expected an IP address such as 127.0.0.1 or 0.0.0.0", value.trim())
+        }),
+        _ => Ok(None),
+    }
+}
+
+/// Accepts connections and serves each one on its own task, until shutdown is requested.
+async fn accept_loop(listener: Arc<Listener>, state: Arc<ServerState>, once: bool) -> Result<()> {
+    loop {
+        tokio::select! {
+            _ = state.shutdown_signal.notified() => {
+                log_println!("Shutdown signal received. stopping server.");
+                break;
+            }
+            accept_result = listener.accept(&state) => {
+                match accept_result {
+                    Ok((socket, peer)) => {
+                        let connection = tokio::spawn(serve_connection(socket, peer, state.clone()));
+                        if once {
+                            let _ = connection.await;
+                            log_println!("Connection handled, stopping server (--once).");
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        log_eprintln!("Accept error: {}", e);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Waits for Ctrl+C or, on Unix, SIGTERM, and names the one that arrived. A handler that
+/// can't be installed is reported and never fires.
+async fn termination_signal() -> &'static str {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log_eprintln!("[WARNING] Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => tokio::select! {
+                _ = ctrl_c => "Ctrl+C",
+                _ = sigterm.recv() => "SIGTERM",
+            },
+            Err(e) => {
+                log_eprintln!("[WARNING] Failed to listen for SIGTERM: {}", e);
+                ctrl_c.await;
+                "Ctrl+C"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        ctrl_c.await;
+        "Ctrl+C"
+    }
+}
+
+/// How long shutdown waits for in-flight connections before killing their commands.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+/// How long shutdown then waits for the killed commands' connections to wind down.
+const SHUTDOWN_KILL_WAIT: Duration = Duration::from_secs(5);
+
+/// Lets the connections still open at shutdown finish, so clients get their whole output
+/// and exit code. Commands still running after `SHUTDOWN_GRACE` are killed, as with `kill`.
+async fn drain_connections(state: &ServerState) {
+    state.draining.store(true, Ordering::Relaxed);
+    state.draining_signal.notify_waiters();
+    let active = state.active_connections.load(Ordering::Relaxed);
+    if active == 0 {
+        return;
+    }
+    log_println!("Waiting up to {}s for {} connection(s) to finish...", SHUTDOWN_GRACE.as_secs(), active);
+    if wait_for_idle(state, SHUTDOWN_GRACE).await {
+        return;
+    }
+    let killed = state.commands.kill_all();
+    log_eprintln!("Grace period over, killed {} running command(s).", killed);
+    if !wait_for_idle(state, SHUTDOWN_KILL_WAIT).await {
+        log_eprintln!(
+            "[WARNING] {} connection(s) still open, closing them.",
+            state.active_connections.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Waits until no connection is active. Returns false if `limit` passed first.
+async fn wait_for_idle(state: &ServerState, limit: Duration) -> bool {
+    let idle = async {
+        loop {
+            // Registered before the check, so a connection closing in between still wakes it
+            let notified = state.connections_idle.notified();
+            if state.active_connections.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    };
+    tokio::time::timeout(limit, idle).await.is_ok()
+}
+
+/// Restarts of a supervised task allowed before the server gives up.
+const SUPERVISOR_MAX_RESTARTS: u32 = 5;
+/// A task that ran this long before panicking starts over with a clean restart count.
+const SUPERVISOR_STABLE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Runs a critical server task, restarting it with a growing delay if it panics, so an
+/// isolated bug doesn't stop the server. Errors and normal returns are passed through.
+async fn supervise<F, Fut>(name: &str, mut start: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<()>> + Send + 'static,
+{
+    let mut restarts = 0;
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        let started = Instant::now();
+        let panic = match tokio::spawn(start()).await {
+            Ok(result) => return result,
+            Err(e) if e.is_panic() => e.into_panic(),
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("{} was cancelled", name))),
+        };
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+
+        if started.elapsed() >= SUPERVISOR_STABLE_PERIOD {
+            restarts = 0;
+            backoff = Duration::from_millis(100);
+        }
+        restarts += 1;
+        if restarts > SUPERVISOR_MAX_RESTARTS {
+            return Err(anyhow::anyhow!("{} panicked {} times in a row, giving up: {}", name, restarts, message));
+        }
+        log_eprintln!(
+            "[supervisor] {} panicked: {}. Restarting in {}ms ({}/{})",
+            name,
+            message,
+            backoff.as_millis(),
+            restarts,
+            SUPERVISOR_MAX_RESTARTS
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(10));
+    }
+}
+
+/// Startup self-test for `--verify-job`: puts a long-running process in a new job, closes
+/// the job handle and checks that the process is gone, as every command relies on this.
+#[cfg(target_os = "windows")]
+async fn verify_job_cleanup() -> Result<()> {
+    let mut child = Command::new("ping")
+        .args(["-n", "30", "127.0.0.1"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to start the test process")?;
+    let handle = child.raw_handle().context("The test process exited too early")?;
+    let job = win_job::assign_to_new_job(handle, &JobLimits::default())?;
+    drop(job);
+
+    match tokio::time::timeout(Duration::from_secs(2), child.wait()).await {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            let _ = child.kill().await;
+            Err(anyhow::anyhow!("the test process was still running 2s after its job handle was closed"))
+        }
+    }
+}
+
+/// File a running server keeps in the temp directory (`winboat-bridge-<port>.pid`, holding its
+/// PID), so a client on the same machine can tell that a server is already up locally.
+/// Removed when the server stops; a stale one from a crash is ignored as its PID is gone.
+struct InstanceMarker {
+    path: PathBuf,
+}
+
+impl InstanceMarker {
+    const PREFIX: &'static str = "winboat-bridge-";
+
+    fn create(port: u16) -> Self {
+        let path = env::temp_dir().join(format!("{}{}.pid", Self::PREFIX, port));
+        if let Err(e) = std::fs::write(&path, format!("{}\n", std::process::id())) {
+            log_eprintln!("[WARNING] Failed to write instance marker {}: {}", path.display(), e);
+        }
+        Self { path }
+    }
+
+    /// Returns the PID and port of a live server on this machine, if any.
+    fn find_live() -> Option<(u32, u16)> {
+        std::fs::read_dir(env::temp_dir()).ok()?.flatten().find_map(|entry| {
+            let name = entry.file_name();
+            let port = name.to_str()?.strip_prefix(Self::PREFIX)?.strip_suffix(".pid")?.parse().ok()?;
+            let pid = std::fs::read_to_string(entry.path()).ok()?.trim().parse().ok()?;
+            (pid != std::process::id() && is_process_alive(pid)).then_some((pid, port))
+        })
+    }
+}
+
+impl Drop for InstanceMarker {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn is_process_alive(pid: u32) -> bool {
+    // Signal 0 only checks that the process exists
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(target_os = "windows")]
+fn is_process_alive(pid: u32) -> bool {
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::minwinbase::STILL_ACTIVE;
+    use winapi::um::processthreadsapi::{GetExitCodeProcess, OpenProcess};
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            return false;
+        }
+        let mut code = 0;
+        let alive = GetExitCodeProcess(handle, &mut code) != 0 && code == STILL_ACTIVE;
+        CloseHandle(handle);
+        alive
+    }
+}
+
+/// How long the server waits for the READY handshake to be written.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State shared by the accept loop and every connection.
+struct ServerState {
+    shutdown_signal: Notify,
+    /// Live configuration, swapped by `reload`
+    config: ArcSwap<ServerConfig>,
+    commands: CommandRegistry,
+    /// Connections currently being served, handshake included
+    active_connections: AtomicUsize,
+    /// Fires when `active_connections` drops to zero
+    connections_idle: Notify,
+    /// Set once shutdown starts draining connections; sessions waiting for their next
+    /// command close then instead of holding the shutdown up
+    draining: AtomicBool,
+    /// Fires, for every waiter, when `draining` is set
+    draining_signal: Notify,
+    /// Source of the request ids in the request log
+    next_request_id: AtomicU64,
+    resumable: ResumableOutputs,
+    /// Set when WINBOAT_REQUEST_LOG is; read once at startup, not on `reload`
+    request_log: Option<logging::RequestLog>,
+    /// Commands running per client identity (or peer IP when none was sent)
+    identity_slots: Mutex<HashMap<String, usize>>,
+    /// Server-wide cap on running commands (WINBOAT_MAX_CONCURRENT); read once at startup,
+    /// not on `reload`. `None` means unlimited
+    command_slots: Option<Semaphore>,
+    #[cfg(feature = "queue")]
+    queue: Arc<queue::JobQueue>,
+    /// Set when WINBOAT_TLS_CERT/KEY are; read once at startup, not on `reload`
+    #[cfg(feature = "tls")]
+    tls: Option<tokio_rustls::TlsAcceptor>,
+}
+
+impl ServerState {
+    /// Adds a line for `request` to the request log, if one is configured.
+    fn record(&self, request: u64, event: &str, fields: serde_json::Value) {
+        if let Some(log) = &self.request_log {
+            log.record(request, event, fields);
+        }
+    }
+}
+
+/// Counts a connection as active until dropped, whichever way the connection ends.
+struct ConnectionGuard<'a> {
+    state: &'a ServerState,
+}
+
+impl<'a> ConnectionGuard<'a> {
+    fn new(state: &'a ServerState) -> Self {
+        state.active_connections.fetch_add(1, Ordering::Relaxed);
+        Self { state }
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        if self.state.active_connections.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.state.connections_idle.notify_waiters();
+        }
+    }
+}
+
+/// One of an identity's concurrent command slots, given back when dropped.
+struct IdentitySlot<'a> {
+    state: &'a ServerState,
+    identity: String,
+}
+
+impl<'a> IdentitySlot<'a> {
+    /// Takes a slot for `identity`, or returns `None` if it already holds `limit` of them.
+    fn acquire(state: &'a ServerState, identity: &str, limit: Option<usize>) -> Option<Self> {
+        let mut slots = state.identity_slots.lock().unwrap();
+        let count = slots.entry(identity.to_string()).or_insert(0);
+        if limit.is_some_and(|limit| *count >= limit) {
+            return None;
+        }
+        *count += 1;
+        Some(Self { state, identity: identity.to_string() })
+    }
+}
+
+impl Drop for IdentitySlot<'_> {
+    fn drop(&mut self) {
+        let mut slots = self.state.identity_slots.lock().unwrap();
+        if let Some(count) = slots.get_mut(&self.identity) {
+            *count -= 1;
+            if *count == 0 {
+                slots.remove(&self.identity);
+            }
+        }
+    }
+}
+
+/// Takes one of the server-wide command slots, waiting up to `wait` for one to free up.
+async fn acquire_command_slot<'a>(slots: &'a Semaphore, wait: Duration, command_line: &str) -> Option<tokio::sync::SemaphorePermit<'a>> {
+    if let Ok(permit) = slots.try_acquire() {
+        return Some(permit);
+    }
+    if wait.is_zero() {
+        return None;
+    }
+    log_println!("[limit] All command slots are in use, waiting up to {}s: {}", wait.as_secs(), command_line);
+    tokio::time::timeout(wait, slots.acquire()).await.ok()?.ok()
+}
+
+/// Commands currently running on this server, keyed by the id logged when they start.
+#[derive(Default)]
+struct CommandRegistry {
+    next_id: AtomicU64,
+    running: Mutex<HashMap<u64, RunningCommand>>,
+}
+
+struct RunningCommand {
+    command_line: String,
+    kill: Arc<Notify>,
+}
+
+/// Keeps a command listed in the registry until dropped, whichever way the connection ends.
+struct Registration<'a> {
+    registry: &'a CommandRegistry,
+    id: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.registry.running.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl CommandRegistry {
+    /// Lists a command; the returned `Notify` fires when an operator asks to kill it.
+    fn register(&self, command_line: &str) -> (Registration<'_>, Arc<Notify>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let kill = Arc::new(Notify::new());
+        let command = RunningCommand {
+            command_line: command_line.to_string(),
+            kill: kill.clone(),
+        };
+        self.running.lock().unwrap().insert(id, command);
+        (Registration { registry: self, id }, kill)
+    }
+
+    /// Asks the command with `id` to stop. Returns false if no such command is running.
+    fn kill(&self, id: u64) -> bool {
+        match self.running.lock().unwrap().get(&id) {
+            Some(command) => {
+                log_println!("Killing command #{}: {}", id, command.command_line);
+                command.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Asks every running command to stop and returns how many there were.
+    fn kill_all(&self) -> usize {
+        let running = self.running.lock().unwrap();
+        for (id, command) in running.iter() {
+            log_println!("Killing command #{}: {}", id, command.command_line);
+            command.kill.notify_one();
+        }
+        running.len()
+    }
+}
+
+/// Most of a resumable command's output kept for a client that reconnects. While a
+/// connection is attached, a full buffer holds the command up until it has been sent more;
+/// with none attached, the oldest bytes are dropped and can no longer be resumed from.
+const RESUME_BUFFER_MAX: usize = 16 * 1024 * 1024;
+/// How long a resumable command's output stays available after the command ended, and how
+/// long a command keeps running with no connection attached to it.
+const RESUME_KEEP: Duration = Duration::from_secs(300);
+/// Largest piece of buffered output sent in one write.
+const RESUME_CHUNK: usize = 64 * 1024;
+
+/// Output of the resumable commands (`OPT resumable`) by token. The command writes into the
+/// buffer, and each connection that asks for it, the original one or a `RESUME`, is sent
+/// from there, so the command keeps running when its client goes away.
+#[derive(Default)]
+struct ResumableOutputs {
+    outputs: Mutex<HashMap<String, Arc<ResumableOutput>>>,
+}
+
+#[derive(Default)]
+struct ResumableOutput {
+    buffer: Mutex<ResumeBuffer>,
+    /// Fires, for every waiter, when output is added or the output is complete
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct ResumeBuffer {
+    data: std::collections::VecDeque<u8>,
+    /// Offset of `data[0]` in the whole output
+    start: u64,
+    /// Set once the command's response, `__EXIT__` line included, is complete
+    finished: bool,
+    /// Offset each attached connection has been sent up to, by reader id
+    readers: HashMap<u64, u64>,
+    next_reader: u64,
+    /// When the last attached connection went away; `None` while one is attached
+    detached_since: Option<Instant>,
+    /// The command's writer, waiting for the readers to make room
+    writer: Option<std::task::Waker>,
+}
+
+impl ResumableOutputs {
+    /// Starts an output and returns its token, which the client sends back to resume.
+    fn open(&self) -> Result<(String, Arc<ResumableOutput>)> {
+        let token = resume_token()?;
+        let output = Arc::new(ResumableOutput::default());
+        self.outputs.lock().unwrap().insert(token.clone(), output.clone());
+        Ok((token, output))
+    }
+
+    fn get(&self, token: &str) -> Option<Arc<ResumableOutput>> {
+        self.outputs.lock().unwrap().get(token).cloned()
+    }
+
+    fn remove(&self, token: &str) {
+        self.outputs.lock().unwrap().remove(token);
+    }
+}
+
+impl ResumableOutput {
+    /// Adds as much of `bytes` as fits, or waits for room. Only bytes every attached
+    /// connection has been sent make room; with none attached, the oldest bytes go.
+    fn poll_push(&self, cx: &mut std::task::Context<'_>, bytes: &[u8]) -> Poll<usize> {
+        if bytes.is_empty() {
+            return Poll::Ready(0);
+        }
+        let mut buffer = self.buffer.lock().unwrap();
+        let droppable = match buffer.readers.values().min() {
+            Some(&sent) => (sent - buffer.start) as usize,
+            None => buffer.data.len(),
+        };
+        let room = (RESUME_BUFFER_MAX - buffer.data.len() + droppable).min(bytes.len());
+        if room == 0 {
+            buffer.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        buffer.data.extend(&bytes[..room]);
+        let excess = buffer.data.len().saturating_sub(RESUME_BUFFER_MAX);
+        if excess > 0 {
+            buffer.data.drain(..excess);
+            buffer.start += excess as u64;
+        }
+        drop(buffer);
+        self.changed.notify_waiters();
+        Poll::Ready(room)
+    }
+
+    fn finish(&self) {
+        self.buffer.lock().unwrap().finished = true;
+        self.changed.notify_waiters();
+    }
+
+    /// Attaches a connection that is to be sent the output from `offset` on. Until the
+    /// returned reader is dropped, the bytes it hasn't been sent are kept.
+    fn attach(self: &Arc<Self>, offset: u64) -> Result<ResumeReader, String> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let end = buffer.start + buffer.data.len() as u64;
+        if offset < buffer.start {
+            return Err(format!("The output before byte {} is no longer available.", buffer.start));
+        }
+        if offset > end {
+            return Err(format!("Byte {} is past the end of the output ({} bytes so far).", offset, end));
+        }
+        let id = buffer.next_reader;
+        buffer.next_reader += 1;
+        buffer.readers.insert(id, offset);
+        buffer.detached_since = None;
+        Ok(ResumeReader { output: self.clone(), id, offset })
+    }
+
+    /// Up to `RESUME_CHUNK` bytes from `offset` on, and whether the output is complete.
+    /// `offset` is never before the buffer's start while its reader is attached.
+    fn read_from(&self, offset: u64) -> (Vec<u8>, bool) {
+        let buffer = self.buffer.lock().unwrap();
+        let from = (offset - buffer.start) as usize;
+        let chunk = buffer.data.range(from..).take(RESUME_CHUNK).copied().collect();
+        (chunk, buffer.finished)
+    }
+
+    /// Resolves once no connection has been attached for `RESUME_KEEP`.
+    async fn abandoned(&self) {
+        loop {
+            let detached_for = self.buffer.lock().unwrap().detached_since.map(|since| since.elapsed());
+            match detached_for {
+                Some(elapsed) if elapsed >= RESUME_KEEP => return,
+                Some(elapsed) => tokio::time::sleep(RESUME_KEEP - elapsed).await,
+                None => tokio::time::sleep(RESUME_KEEP).await,
+            }
+        }
+    }
+}
+
+/// A connection attached to a resumable output, and how far it has been sent.
+struct ResumeReader {
+    output: Arc<ResumableOutput>,
+    id: u64,
+    offset: u64,
+}
+
+impl ResumeReader {
+    fn advance(&mut self, sent: usize) {
+        self.offset += sent as u64;
+        let mut buffer = self.output.buffer.lock().unwrap();
+        buffer.readers.insert(self.id, self.offset);
+        if let Some(writer) = buffer.writer.take() {
+            writer.wake();
+        }
+    }
+}
+
+impl Drop for ResumeReader {
+    fn drop(&mut self) {
+        let mut buffer = self.output.buffer.lock().unwrap();
+        buffer.readers.remove(&self.id);
+        if buffer.readers.is_empty() {
+            buffer.detached_since = Some(Instant::now());
+        }
+        if let Some(writer) = buffer.writer.take() {
+            writer.wake();
+        }
+    }
+}
+
+/// A token naming a resumable command's output: 128 bits from the OS's random source, so
+/// other clients can't guess it.
+fn resume_token() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow::anyhow!("Failed to generate a resume token: {}", e))?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Sends a resumable command's output to an attached connection until it is complete (then
+/// the connection is closed) or the connection fails.
+async fn stream_resumable_output<W: AsyncWrite + Unpin>(mut reader: ResumeReader, mut socket: W) -> Result<()> {
+    let output = reader.output.clone();
+    loop {
+        // Registered before reading, so output added in between still wakes this up
+        let changed = output.changed.notified();
+        let (chunk, finished) = output.read_from(reader.offset);
+        if chunk.is_empty() {
+            if finished {
+                socket.shutdown().await?;
+                return Ok(());
+            }
+            changed.await;
+            continue;
+        }
+        socket.write_all(&chunk).await?;
+        socket.flush().await?;
+        reader.advance(chunk.len());
+    }
+}
+
+/// Prefix of a request line naming a command to run. A line without either prefix (from
+/// an older client) is a command too.
+const COMMAND_PREFIX: &str = "CMD ";
+/// The command of a plain request: what follows `CMD `, or the whole line from an older
+/// client. The request is trimmed, so `CMD ` with nothing after it arrives as a bare `CMD`.
+fn command_body(body: String) -> String {
+    match body.strip_prefix(COMMAND_PREFIX) {
+        Some(command) => command.to_string(),
+        None if body == COMMAND_PREFIX.trim_end() => String::new(),
+        None => body,
+    }
+}
+
+/// Prefix of a request line asking the server itself for something, see `ControlRequest`.
+const CONTROL_PREFIX: &str = "CTRL ";
+/// Prefix of a request line picking up a resumable command's output: `RESUME <token> <offset>`,
+/// the offset counting the bytes received after the `__RESUME__` line.
+const RESUME_PREFIX: &str = "RESUME ";
+
+/// Requests for the server itself, sent as `CTRL <verb>` in place of a command line, so
+/// they can't collide with a program of the same name.
+enum ControlRequest {
+    Shutdown,
+    Reload,
+    Kill(u64),
+    KillAll,
+    Version,
+    Ping,
+    #[cfg(feature = "queue")]
+    JobStatus(u64),
+}
+
+impl ControlRequest {
+    /// Whether the request stops commands or changes the server, so WINBOAT_ADMIN_TOKEN
+    /// guards it.
+    fn needs_admin(&self) -> bool {
+        matches!(self, ControlRequest::Shutdown | ControlRequest::Reload | ControlRequest::Kill(_) | ControlRequest::KillAll)
+    }
+}
+
+/// Parses the verb (and argument) after `CTRL `; verbs are case-insensitive.
+fn parse_control_request(request: &str) -> Option<ControlRequest> {
+    let request = request.trim().to_ascii_lowercase();
+    let (verb, arg) = request.split_once(' ').unwrap_or((&request, ""));
+    let id = || arg.trim().trim_start_matches('#').parse().ok();
+    match verb {
+        "shutdown" => Some(ControlRequest::Shutdown),
+        "reload" => Some(ControlRequest::Reload),
+        "kill" => id().map(ControlRequest::Kill),
+        "kill-all" => Some(ControlRequest::KillAll),
+        "version" => Some(ControlRequest::Version),
+        "ping" => Some(ControlRequest::Ping),
+        #[cfg(feature = "queue")]
+        "job" => id().map(ControlRequest::JobStatus),
+        _ => None,
+    }
+}
+
+/// Carries out a control request. With `exit_code`, the reply ends in an `__EXIT__` line
+/// like a command's output: 0, or 1 when the command or job it names doesn't exist.
+async fn run_control_request(control: ControlRequest, socket: &mut Connection, state: &ServerState, options: &RequestOptions) -> Result<()> {
+    let mut code = 0;
+    match control {
+        ControlRequest::Shutdown => {
+            log_println!("Shutdown requested. notifying shutdown.");
+            // Answered before the listener goes away, so the client sees a clean exit
+            if options.exit_code {
+                socket.write_all(&encode_report(options, &exit_line(0, None, None))).await?;
+            }
+            state.shutdown_signal.notify_one();
+            return Ok(());
+        }
+        ControlRequest::Reload => {
+            // New connections get the new settings; in-flight ones keep their snapshot
+            log_println!("Reload requested. Re-reading configuration.");
+            let reply = match reload_settings() {
+                Ok(()) => {
+                    state.config.store(Arc::new(ServerConfig::from_env()));
+                    "Configuration reloaded.\n".to_string()
+                }
+                Err(e) => {
+                    log_eprintln!("[WARNING] Reload failed, keeping the current configuration: {:#}", e);
+                    code = 1;
+                    format!("Reload failed, the configuration is unchanged: {:#}\n", e)
+                }
+            };
+            socket.write_all(&encode_reply(options, OutputStream::Stdout, &reply)).await?;
+        }
+        ControlRequest::Kill(id) => {
+            let reply = if state.commands.kill(id) {
+                format!("Command #{} killed.\n", id)
+            } else {
+                code = 1;
+                format!("No running command #{}.\n", id)
+            };
+            socket.write_all(&encode_reply(options, OutputStream::Stdout, &reply)).await?;
+        }
+        ControlRequest::KillAll => {
+            let count = state.commands.kill_all();
+            let reply = format!("Killed {} running command(s).\n", count);
+            socket.write_all(&encode_reply(options, OutputStream::Stdout, &reply)).await?;
+        }
+        ControlRequest::Version => {
+            let reply = format!("winboat-bridge server {}\n", VERSION);
+            socket.write_all(&encode_reply(options, OutputStream::Stdout, &reply)).await?;
+        }
+        ControlRequest::Ping => {
+            socket.write_all(&encode_reply(options, OutputStream::Stdout, PONG_REPLY)).await?;
+        }
+        #[cfg(feature = "queue")]
+        ControlRequest::JobStatus(id) => {
+            let reply = match state.queue.get(id) {
+                Some(job) => queue::describe_job(&job),
+                None => {
+                    code = 1;
+                    format!("No queued job #{}.\n", id)
+                }
+            };
+            socket.write_all(&encode_reply(options, OutputStream::Stdout, &reply)).await?;
+        }
+    }
+    if options.exit_code {
+        socket.write_all(&encode_report(options, &exit_line(code, None, None))).await?;
+    }
+    Ok(())
+}
+
+/// A client-server connection: plain TCP, or TLS over it when configured (`tls` feature),
+/// or a Unix socket (WINBOAT_SOCKET).
+enum Connection {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Where a command's response is written: the connection, or for a resumable command the
+/// buffer the connections are sent from.
+enum ResponseWriter {
+    Socket(tokio::io::WriteHalf<Connection>),
+    Resumable(Arc<ResumableOutput>),
+}
+
+impl AsyncWrite for ResponseWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ResponseWriter::Socket(writer) => Pin::new(writer).poll_write(cx, buf),
+            ResponseWriter::Resumable(output) => output.poll_push(cx, buf).map(Ok),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ResponseWriter::Socket(writer) => Pin::new(writer).poll_flush(cx),
+            ResponseWriter::Resumable(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ResponseWriter::Socket(writer) => Pin::new(writer).poll_shutdown(cx),
+            ResponseWriter::Resumable(output) => {
+                output.finish();
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+/// Sends the READY handshake on an accepted socket, then handles its request.
+async fn serve_connection(socket: Connection, peer: SocketAddr, state: Arc<ServerState>) {
+    let _active = ConnectionGuard::new(&state);
+
+    // Handshake: TLS if enabled, then send READY. A peer that connects and goes away (or
+    // never reads) must not keep this task alive, so the whole handshake is bounded.
+    let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        #[cfg(feature = "tls")]
+        let mut socket = match (&state.tls, socket) {
+            (Some(acceptor), Connection::Plain(socket)) => Connection::Tls(Box::new(acceptor.accept(socket).await?.into())),
+            (_, socket) => socket,
+        };
+        #[cfg(not(feature = "tls"))]
+        let mut socket = socket;
+        socket.write_all(format!("READY {}\n", PROTOCOL_VERSION).as_bytes()).await?;
+        socket.flush().await?;
+        Ok::<_, std::io::Error>(socket)
+    })
+    .await;
+    let outcome = match handshake {
+        Ok(Ok(socket)) => Ok(socket),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no progress after {}s", HANDSHAKE_TIMEOUT.as_secs())),
+    };
+    let socket = match outcome {
+        Ok(socket) => socket,
+        Err(reason) => {
+            log_eprintln!(
+                "Connection from {} closed during handshake: {} ({} active)",
+                peer,
+                reason,
+                state.active_connections.load(Ordering::Relaxed) - 1
+            );
+            return;
+        }
+    };
+
+    let request_id = state.next_request_id.fetch_add(1, Ordering::Relaxed) + 1;
+    if let Err(e) = handle_connection(socket, peer, state.clone(), request_id).await {
+        log_eprintln!("Connection from {} closed with error: {:#}", peer, e);
+        state.record(request_id, "error", serde_json::json!({ "message": format!("{:#}", e) }));
+    }
+}
+
+/// A socket listening on a port, as reported by netstat.
+#[cfg(any(target_os = "windows", test))]
+struct PortListener {
+    local_addr: String,
+    pid: u32,
+}
+
+/// Lists the sockets listening on `port` together with their owning PID.
+#[cfg(target_os = "windows")]
+async fn find_port_listeners_windows(port: u16) -> Result<Vec<PortListener>> {
+    // Parsed column by column rather than filtered with findstr, which also matches other
+    // ports containing the number and connections whose remote end uses it
+    let out = Command::new("netstat")
+        .args(["-a", "-n", "-o", "-p", "TCP"])
+        .output()
+        .await
+        .context("Failed to run netstat to locate PID")?;
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let mut listeners = Vec::new();
+    for line in stdout.lines() {
+        if let Some(listener) = parse_netstat_listener(line, port) {
+            log_println!("[netstat] {}", line.trim());
+            listeners.push(listener);
+        }
+    }
+    Ok(listeners)
+}
+
+/// Parses one `netstat -a -n -o` line into a listener on `port`, e.g.
+/// `TCP    0.0.0.0:5330   0.0.0.0:0   LISTENING   12345`. Only a TCP line in the LISTENING
+/// state whose local address (the second column) has exactly `port` after its last `:`
+/// matches, so port 533 doesn't pick up 5330 and a connection to a remote :5330 is skipped.
+#[cfg(any(target_os = "windows", test))]
+fn parse_netstat_listener(line: &str, port: u16) -> Option<PortListener> {
+    let columns: Vec<&str> = line.split_whitespace().collect();
+    let [proto, local_addr, _foreign_addr, state, pid, ..] = columns[..] else {
+        return None;
+    };
+    if !proto.eq_ignore_ascii_case("TCP") || state != "LISTENING" {
+        return None;
+    }
+    // IPv6 addresses are bracketed ([::]:5330), so the last colon always precedes the port
+    let (_, local_port) = local_addr.rsplit_once(':')?;
+    if local_port != port.to_string() {
+        return None;
+    }
+    Some(PortListener {
+        local_addr: local_addr.to_string(),
+        pid: pid.parse::<u32>().ok()?,
+    })
+}
+
+/// Explains why binding `bind_addr` failed, telling a process already on the same address
+/// apart from one holding the port on another interface (e.g. 127.0.0.1 vs 0.0.0.0),
+/// which Windows also reports as a plain AddrInUse.
+#[cfg(target_os = "windows")]
+fn describe_bind_conflict(bind_addr: &str, listeners: &[PortListener]) -> String {
+    if listeners.is_empty() {
+        return format!(
+            "Cannot bind {}: the port is in use but netstat shows no listening process (the socket may still be closing)",
+            bind_addr
+        );
+    }
+
+    let holders: Vec<String> = listeners
+        .iter()
+        .map(|listener| {
+            if listener.local_addr == bind_addr {
+                format!("PID {} is already listening on {}", listener.pid, listener.local_addr)
+            } else {
+                format!(
+                    "PID {} holds the port on a different interface ({}), which conflicts with {}",
+                    listener.pid, listener.local_addr, bind_addr
+                )
+            }
+        })
+        .collect();
+    format!("Cannot bind {}: {}", bind_addr, holders.join("; "))
+}
+
+/// Image name of process `pid` as tasklist reports it (e.g. `winboat-bridge.exe`), or
+/// `None` when no such process is running.
+#[cfg(target_os = "windows")]
+async fn process_image_name_windows(pid: u32) -> Result<Option<String>> {
+    let out = Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/FO", "CSV", "/NH"])
+        .output()
+        .await
+        .with_context(|| format!("Failed to run tasklist for PID {}", pid))?;
+    // "winboat-bridge.exe","1234","Console","1","10,000 K"; an INFO line when nothing matches
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    Ok(stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix('"')?.split_once('"'))
+        .map(|(name, _)| name.to_string()))
+}
+
+/// Whether `image` is a winboat-bridge server: the usual executable name, or this one's if
+/// it was renamed.
+#[cfg(target_os = "windows")]
+fn is_bridge_image(image: &str) -> bool {
+    let own = env::current_exe().ok().and_then(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()));
+    image.eq_ignore_ascii_case("winboat-bridge.exe") || own.is_some_and(|own| image.eq_ignore_ascii_case(&own))
+}
+
+/// Kills the processes listening on `port` so the server can bind it. Unless `force` is set,
+/// only winboat-bridge processes (typically a previous server instance) are killed; anything
+/// else is left running with a warning, as it may be an unrelated service.
+#[cfg(target_os = "windows")]
+async fn kill_listener_on_port_windows(port: u16, force: bool) -> Result<()> {
+    // Find PID(s) listening on a port and terminate them.
+    let listeners = find_port_listeners_windows(port).await?;
+    let mut pids: Vec<u32> = listeners.iter().map(|listener| listener.pid).collect();
+    pids.sort_unstable();
+    pids.dedup();
+
+    // If nothing found, maybe the port was released in the meantime.
+    if pids.is_empty() {
+        log_println!("[kill_listener] No listening PIDs found in netstat output for port {}", port);
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        return Ok(());
+    }
+
+    if !force {
+        let mut ours = Vec::new();
+        for pid in pids {
+            match process_image_name_windows(pid).await {
+                Ok(Some(image)) if is_bridge_image(&image) => ours.push(pid),
+                Ok(Some(image)) => log_eprintln!(
+                    "[kill_listener] PID {} on port {} is {}, not winboat-bridge: left running (use --force-kill to kill it anyway)",
+                    pid, port, image
+                ),
+                // Gone since netstat ran: nothing to kill
+                Ok(None) => {}
+                Err(e) => log_eprintln!(
+                    "[kill_listener] Can't tell which program PID {} is ({:#}): left running (use --force-kill to kill it anyway)",
+                    pid, e
+                ),
+            }
+        }
+        pids = ours;
+        if pids.is_empty() {
+            return Ok(());
+        }
+    }
+
+    log_println!("[kill_listener] PIDs to kill: {:?}", pids);
+    for pid in pids {
+        let kill = Command::new("taskkill")
+            .args(["/F", "/PID", &pid.to_string()])
+            .output()
+            .await
+            .with_context(|| format!("Failed to run taskkill for PID {}", pid))?;
+
+        if !kill.status.success() {
+            let stderr = String::from_utf8_lossy(&kill.stderr);
+            // If it already exited between netstat and taskkill, treat as non-fatal.
+            log_eprintln!("[kill_listener] Warning: taskkill failed for PID {}: {}", pid, stderr.trim());
+        } else {
+            let stdout_kill = String::from_utf8_lossy(&kill.stdout);
+            log_println!("[kill_listener] taskkill success for PID {}: {}", pid, stdout_kill.trim());
+        }
+    }
+
+    // Give Windows a moment to release the socket
+    log_println!("[kill_listener] Sleeping 800ms for socket release...");
+    tokio::time::sleep(tokio::time::Duration::from_millis(800)).await;
+    Ok(())
+}
+
+async fn handle_connection(mut socket: Connection, peer: SocketAddr, state: Arc<ServerState>, request_id: u64) -> Result<()> {
+    // Settings stay fixed for this connection even if a reload happens meanwhile
+    let config = state.config.load_full();
+
+    // 1. Read command
+    let (request, early_input) = read_request(&mut socket, false).await?;
+    // Also how a probe (`is_server_alive`, the bootstrap's polling) leaves: after the handshake
+    if skip_protocol_line(&request).is_empty() {
+        return Ok(());
+    }
+    let ParsedRequest { protocol, options, body, unknown_options } = parse_request(&String::from_utf8_lossy(&request));
+
+    // Nothing in the request is acted on, or logged, before the client has authenticated. A
+    // session is checked once, here, with its first request: a wrong token ends it before any
+    // of its commands runs
+    if let Some(expected) = &config.auth_token {
+        let presented = options.auth_token.as_deref();
+        if !presented.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+            let reason = if presented.is_some() { "wrong token" } else { "no token" };
+            log_eprintln!("[auth] Rejected request from {}: {}", peer, reason);
+            state.record(request_id, "rejected", serde_json::json!({ "peer": peer, "reason": "unauthorized" }));
+            send_refusal(&mut socket, &options, "UNAUTHORIZED", Some(UNAUTHORIZED_REASON)).await?;
+            return Ok(());
+        }
+    }
+
+    // A client that agreed on protocol 1 or later counts on every option it sends, so one this
+    // server doesn't know is refused. Older clients get the old behaviour: it is ignored
+    if let Some(name) = unknown_options.first() {
+        if protocol.refuses_unknown_options() {
+            log_eprintln!("Rejected request from {}: unknown option '{}'", peer, name);
+            let message = format!("Unknown request option '{}': the server is older than the client (protocol {}); update it.", name, PROTOCOL_VERSION);
+            send_error(&mut socket, &options, &message).await?;
+            return Ok(());
+        }
+        for name in &unknown_options {
+            log_eprintln!("Ignoring unknown request option: {}", name);
+        }
+    }
+
+    // A cut-off command must never run, it could do something else entirely
+    if request_truncated(&request) {
+        log_eprintln!("Rejected request from {}: longer than {} bytes", peer, MAX_REQUEST_LEN);
+        state.record(request_id, "rejected", serde_json::json!({ "peer": peer, "reason": "too long" }));
+        discard_pending_input(&mut socket).await;
+        let message = format!("Request too long: the limit is {} bytes.", MAX_REQUEST_LEN);
+        send_error(&mut socket, &options, &message).await?;
+        return Ok(());
+    }
+    if request_unterminated(&request, false) {
+        log_eprintln!("Rejected request from {}: the connection ended before the request did", peer);
+        state.record(request_id, "rejected", serde_json::json!({ "peer": peer, "reason": "incomplete" }));
+        send_error(&mut socket, &options, INCOMPLETE_REQUEST).await?;
+        return Ok(());
+    }
+
+    // A client picking up a resumable command's output after losing its connection
+    if let Some(resume) = body.strip_prefix(RESUME_PREFIX).filter(|_| !options.json) {
+        return resume_output(socket, peer, &state, &options, request_id, resume).await;
+    }
+
+    // Control requests (plain requests only; a JSON request always runs its command)
+    let body = match body.strip_prefix(CONTROL_PREFIX) {
+        Some(request) if !options.json => {
+            let Some(control) = parse_control_request(request) else {
+                log_eprintln!("Rejected unknown control request from {}: {}", peer, request.trim());
+                send_error(&mut socket, &options, &format!("Unknown control request: {}", request.trim())).await?;
+                return Ok(());
+            };
+            if let Some(expected) = config.admin_token.as_ref().filter(|_| control.needs_admin()) {
+                let presented = options.admin_token.as_deref();
+                if !presented.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+                    let reason = if presented.is_some() { "wrong admin token" } else { "no admin token" };
+                    log_eprintln!("[auth] Refused control request from {}: {}: {}", peer, request.trim(), reason);
+                    state.record(request_id, "rejected", serde_json::json!({ "peer": peer, "reason": "forbidden", "request": request.trim() }));
+                    send_error(&mut socket, &options, "FORBIDDEN: this control request needs the server's admin token (WINBOAT_ADMIN_TOKEN).").await?;
+                    return Ok(());
+                }
+            }
+            // Pings are health checks, too frequent to be worth a log line
+            if !matches!(control, ControlRequest::Ping) {
+                log_println!("Received control request from {}: {}", peer, request.trim());
+                state.record(
+                    request_id,
+                    "control",
+                    serde_json::json!({ "peer": peer, "identity": options.identity, "request": request.trim() }),
+                );
+            }
+            return run_control_request(control, &mut socket, &state, &options).await;
+        }
+        _ if options.json => body,
+        _ => command_body(body),
+    };
+
+    // A session runs several commands over this connection, each one answered like a
+    // request of its own, so it needs the frames and exit line that mark where a result ends
+    if options.session {
+        let refusal = if options.json || !options.framed || !options.exit_code {
+            Some("A session requires framed output with exit codes (OPT framed, OPT exit-code).")
+        } else if options.stdin || options.enqueue {
+            Some("Stdin forwarding and queueing aren't available in a session.")
+        } else {
+            None
+        };
+        if let Some(message) = refusal {
+            send_error(&mut socket, &options, message).await?;
+            return Ok(());
+        }
+        log_println!("Session opened by {}", peer);
+    }
+    // Resuming means finding where a frame starts and knowing when the response is over
+    if options.resumable {
+        let refusal = if options.json || !options.framed || !options.exit_code {
+            Some("A resumable command requires framed output with exit codes (OPT framed, OPT exit-code).")
+        } else if options.stdin || options.enqueue || options.session {
+            Some("Stdin forwarding, queueing and sessions aren't available for a resumable command.")
+        } else {
+            None
+        };
+        if let Some(message) = refusal {
+            send_error(&mut socket, &options, message).await?;
+            return Ok(());
+        }
+    }
+
+    let (mut body, mut early_input) = (body, early_input);
+    let mut commands = 0;
+    loop {
+        commands += 1;
+        match run_request(socket, early_input, peer, &state, &config, request_id, (protocol, options.clone(), body)).await? {
+            Some(returned) if options.session => socket = returned,
+            _ => return Ok(()),
+        }
+        // The session's next command: a `CMD` line, run with the options of the first request
+        loop {
+            // Registered before the check, so a shutdown starting in between still ends the wait
+            let draining = state.draining_signal.notified();
+            if state.draining.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let (request, extra) = tokio::select! {
+                read = read_request(&mut socket, protocol != Protocol::LEGACY) => read?,
+                _ = draining => {
+                    log_println!("Closing the session from {} for shutdown", peer);
+                    return Ok(());
+                }
+            };
+            // A closed connection or an empty line ends the session
+            if request.iter().all(u8::is_ascii_whitespace) {
+                log_println!("Session from {} closed after {} command(s)", peer, commands);
+                return Ok(());
+            }
+            if request_truncated(&request) {
+                log_eprintln!("Rejected request from {}: longer than {} bytes", peer, MAX_REQUEST_LEN);
+                discard_pending_input(&mut socket).await;
+                let message = format!("Request too long: the limit is {} bytes.", MAX_REQUEST_LEN);
+                send_error(&mut socket, &options, &message).await?;
+                return Ok(());
+            }
+            if request_unterminated(&request, protocol != Protocol::LEGACY) {
+                log_eprintln!("Rejected request from {}: the connection ended before the request did", peer);
+                send_error(&mut socket, &options, INCOMPLETE_REQUEST).await?;
+                return Ok(());
+            }
+            let next = parse_request(&String::from_utf8_lossy(&request)).body;
+            if next.starts_with(CONTROL_PREFIX) {
+                send_error(&mut socket, &options, "Control requests can't be sent within a session.").await?;
+                continue;
+            }
+            body = command_body(next);
+            early_input = extra;
+            break;
+        }
+    }
+}
+
+/// Answers `RESUME <token> <offset>` with the output of that resumable command from `offset` on.
+async fn resume_output(
+    mut socket: Connection,
+    peer: SocketAddr,
+    state: &ServerState,
+    options: &RequestOptions,
+    request_id: u64,
+    resume: &str,
+) -> Result<()> {
+    let (token, offset) = resume.trim().split_once(' ').unwrap_or((resume.trim(), ""));
+    let Ok(offset) = offset.trim().parse::<u64>() else {
+        send_error(&mut socket, options, "Invalid RESUME request: expected RESUME <token> <offset>").await?;
+        return Ok(());
+    };
+    let Some(output) = state.resumable.get(token) else {
+        log_eprintln!("Rejected resume from {}: unknown or expired token", peer);
+        send_error(&mut socket, options, "Nothing to resume: the token is unknown or has expired.").await?;
+        return Ok(());
+    };
+    let reader = match output.attach(offset) {
+        Ok(reader) => reader,
+        Err(message) => {
+            send_error(&mut socket, options, &message).await?;
+            return Ok(());
+        }
+    };
+    log_println!("Client {} resumed an output at byte {}", peer, offset);
+    state.record(request_id, "resumed", serde_json::json!({ "peer": peer, "offset": offset }));
+    if let Err(e) = stream_resumable_output(reader, socket).await {
+        log_eprintln!("Resumed output to {} interrupted: {:#}", peer, e);
+    }
+    Ok(())
+}
+
+/// Runs the command of one request on `socket`, answering it with the output and result.
+/// `request` is the protocol, options and body as `parse_request` returns them. Returns the
+/// connection when a session may carry on with it; `None` once it is closed.
+async fn run_request(
+    mut socket: Connection,
+    early_input: Vec<u8>,
+    peer: SocketAddr,
+    state: &Arc<ServerState>,
+    config: &ServerConfig,
+    request_id: u64,
+    (protocol, mut options, body): (Protocol, RequestOptions, String),
+) -> Result<Option<Connection>> {
+    // `ENV` lines come first; a JSON request's own `env` wins over them
+    let request_env = match options.env.iter().map(|assignment| parse_env_assignment(assignment)).collect::<Result<HashMap<_, _>, _>>() {
+        Ok(env) => env,
+        Err(message) => {
+            log_eprintln!("Rejected request from {}: {}", peer, message);
+            send_error(&mut socket, &options, &message).await?;
+            return Ok(Some(socket));
+        }
+    };
+
+    let mut spec = if options.json {
+        match serde_json::from_str::<JsonRequest>(&body) {
+            Ok(request) => {
+                options.confirm_destructive |= request.confirm_destructive;
+                options.scratch_dir |= request.scratch_dir;
+                options.keep_scratch |= request.keep_scratch;
+                options.clean_env |= request.clean_env;
+                CommandSpec::from(request)
+            }
+            Err(e) => {
+                log_eprintln!("Rejected invalid JSON request: {}", e);
+                send_error(&mut socket, &options, &format!("Invalid JSON request: {}", e)).await?;
+                return Ok(Some(socket));
+            }
+        }
+    } else {
+        CommandSpec {
+            timeout: options.timeout.map(Duration::from_secs),
+            ..CommandSpec::new(body)
+        }
+    };
+    for (key, value) in request_env {
+        spec.env.entry(key).or_insert(value);
+    }
+    // A JSON request's own `cwd` wins over `OPT cwd`
+    if spec.cwd.is_none() {
+        spec.cwd = options.cwd.take();
+    }
+    // An empty command would start the shell with nothing to run, e.g. a bare `cmd /C`
+    if spec.command_line.trim().is_empty() {
+        log_eprintln!("Rejected request from {}: empty command", peer);
+        send_error(&mut socket, &options, "Empty command: there is nothing to run.").await?;
+        return Ok(Some(socket));
+    }
+    let command_line = spec.command_line.as_str();
+    match &options.identity {
+        Some(identity) => log_println!("Received command (as {}): {}", identity, command_line),
+        None => log_println!("Received command: {}", command_line),
+    }
+    state.record(
+        request_id,
+        "command",
+        serde_json::json!({ "peer": peer, "identity": options.identity, "command": command_line }),
+    );
+
+    // Refuse commands the allowlist doesn't cover, and requests that would change which
+    // program an allowed command runs
+    let denied_env = spec.env.keys().find(|name| !config.allows_env(name));
+    if !config.is_allowed(command_line, options.shell.unwrap_or(Shell::DEFAULT)) || denied_env.is_some() {
+        match denied_env {
+            Some(name) => log_eprintln!("[allowlist] Denied command from {} setting {}: {}", peer, name, command_line),
+            None => log_eprintln!("[allowlist] Denied command from {}: {}", peer, command_line),
+        }
+        state.record(request_id, "rejected", serde_json::json!({ "reason": "allowlist" }));
+        send_error(&mut socket, &options, "DENIED").await?;
+        return Ok(Some(socket));
+    }
+
+    // Refuse commands matching a dangerous pattern unless the client confirmed them
+    if !options.confirm_destructive {
+        if let Some(pattern) = config.match_dangerous_pattern(command_line) {
+            log_eprintln!("[guard] Blocked command matching dangerous pattern '{}': {}", pattern, command_line);
+            state.record(request_id, "rejected", serde_json::json!({ "reason": "dangerous pattern", "pattern": pattern }));
+            let message = format!(
+                "Command blocked: it matches the dangerous pattern '{}'. Re-run with --confirm-destructive to execute it.",
+                pattern
+            );
+            send_error(&mut socket, &options, &message).await?;
+            return Ok(Some(socket));
+        }
+    }
+
+    if options.enqueue {
+        // A job is stored as its command line only
+        if !spec.env.is_empty() {
+            send_error(&mut socket, &options, "Environment variables can't be passed to a queued command.").await?;
+            return Ok(Some(socket));
+        }
+        if options.shell.is_some() {
+            send_error(&mut socket, &options, "A queued command always runs with the default shell.").await?;
+            return Ok(Some(socket));
+        }
+        #[cfg(feature = "queue")]
+        match state.queue.enqueue(command_line) {
+            Ok(id) => {
+                log_println!("[queue] Enqueued job #{}", id);
+                let reply = format!("QUEUED {}\n", id);
+                socket.write_all(&encode_reply(&options, OutputStream::Stdout, &reply)).await?;
+                if options.exit_code {
+                    socket.write_all(&encode_report(&options, &exit_line(0, None, None))).await?;
+                }
+            }
+            Err(e) => send_error(&mut socket, &options, &format!("Failed to enqueue command: {:#}", e)).await?,
+        }
+        #[cfg(not(feature = "queue"))]
+        send_error(&mut socket, &options, "This server was built without queue support (feature \"queue\").").await?;
+        return Ok(Some(socket));
+    }
+
+    // Per-identity cap, so one user can't take every slot of a shared server
+    let identity_key = options.identity.clone().unwrap_or_else(|| peer.ip().to_string());
+    let _identity_slot = match IdentitySlot::acquire(state, &identity_key, config.max_concurrent_per_identity) {
+        Some(slot) => slot,
+        None => {
+            log_eprintln!("[limit] {} is already running its maximum number of commands, refusing: {}", identity_key, command_line);
+            send_error(&mut socket, &options, "BUSY").await?;
+            return Ok(Some(socket));
+        }
+    };
+
+    // Server-wide cap, so a burst of clients can't overwhelm the host. Over the limit a
+    // request waits up to WINBOAT_BUSY_WAIT for a slot, then gets BUSY like above
+    let _command_slot = match &state.command_slots {
+        Some(slots) => match acquire_command_slot(slots, config.busy_wait, command_line).await {
+            Some(permit) => Some(permit),
+            None => {
+                log_eprintln!("[limit] All command slots are in use, refusing: {}", command_line);
+                state.record(request_id, "rejected", serde_json::json!({ "reason": "busy" }));
+                send_error(&mut socket, &options, "BUSY").await?;
+                return Ok(Some(socket));
+            }
+        },
+        None => None,
+    };
+
+    // 2. Spawn process
+    let shell = options.shell.unwrap_or(Shell::DEFAULT);
+    let (program, shell_args) = shell.argv();
+
+    // Keep the exact argv so it can be reported back to the client
+    let shell_command = shell_command_text(command_line, shell, &options);
+    let invocation: Vec<&str> = [program].iter().chain(shell_args).copied().chain([shell_command.as_str()]).collect();
+
+    // A stream the client doesn't want goes straight to the null device, with no pipe or reader
+    let output_stdio = |discard: bool| if discard { Stdio::null() } else { Stdio::piped() };
+    let mut command = Command::new(invocation[0]);
+    if options.clean_env {
+        command.env_clear();
+        for name in CLEAN_ENV_KEEP {
+            if let Some(value) = env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+    }
+    command
+        .args(&invocation[1..])
+        .envs(&spec.env)
+        .stdout(output_stdio(options.stderr_only))
+        .stderr(output_stdio(options.no_stderr));
+    if options.stdin {
+        // Input arrives in frames, so the end of it can be told apart from a disconnect
+        if !options.framed {
+            send_error(&mut socket, &options, "Forwarding stdin requires framed output (OPT framed).").await?;
+            return Ok(Some(socket));
+        }
+        command.stdin(Stdio::piped());
+    }
+    if let Some(cwd) = &spec.cwd {
+        if !config.is_cwd_allowed(Path::new(cwd)) {
+            log_eprintln!("[guard] Rejected cwd outside WINBOAT_ALLOWED_CWDS: {}", cwd);
+            send_error(&mut socket, &options, "FORBIDDEN").await?;
+            return Ok(Some(socket));
+        }
+        // Checked here so the client gets a clear message rather than a failed spawn
+        if !Path::new(cwd).is_dir() {
+            send_error(&mut socket, &options, &format!("Working directory not found on the server: {}", cwd)).await?;
+            return Ok(Some(socket));
+        }
+        command.current_dir(cwd);
+    }
+
+    // Optional private working directory, removed once the command is done
+    let scratch_dir = if options.scratch_dir {
+        if spec.cwd.is_some() {
+            send_error(&mut socket, &options, "A scratch directory and a cwd cannot be requested together.").await?;
+            return Ok(Some(socket));
+        }
+        let dir = match tempfile::Builder::new().prefix("winboat-scratch-").tempdir() {
+            Ok(dir) => dir,
+            Err(e) => {
+                let err = anyhow::Error::new(e).context("Failed to create scratch directory");
+                send_error(&mut socket, &options, &format!("{:#}", err)).await?;
+                return Err(err);
+            }
+        };
+        if !config.is_cwd_allowed(dir.path()) {
+            log_eprintln!("[guard] Rejected scratch directory outside WINBOAT_ALLOWED_CWDS: {}", dir.path().display());
+            send_error(&mut socket, &options, "FORBIDDEN").await?;
+            return Ok(Some(socket));
+        }
+        command.current_dir(dir.path());
+        Some(dir)
+    } else {
+        None
+    };
+    let scratch_path = scratch_dir.as_ref().map(|dir| dir.path().display().to_string());
+    // Own process group, so the whole tree can be killed (the Job Object covers this on Windows)
+    #[cfg(unix)]
+    command.process_group(0);
+
+    // Optional copy of the output into a named pipe for local Windows consumers
+    let mut output_pipe = match &options.output_pipe {
+        Some(name) => match open_output_pipe(name) {
+            Ok(pipe) => Some(pipe),
+            Err(e) => {
+                send_error(&mut socket, &options, &format!("{:#}", e)).await?;
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+    if options.to_clipboard && !cfg!(target_os = "windows") {
+        send_error(&mut socket, &options, "--to-clipboard is only supported by Windows servers").await?;
+        return Ok(Some(socket));
+    }
+    if options.cpu_percent.is_some() && !cfg!(target_os = "windows") {
+        send_error(&mut socket, &options, "--cpu-percent is only supported by Windows servers").await?;
+        return Ok(Some(socket));
+    }
+
+    // Setup failures are told to the client too; otherwise it would only see the
+    // connection close, with nothing to say why the command never ran
+    let start_time = Instant::now();
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            // E.g. `--shell pwsh` on a server without it: the request fails, but a session
+            // goes on to its next command
+            let err = anyhow::Error::new(e).context(format!("Failed to start {}", program));
+            log_eprintln!("Request from {} failed: {:#}", peer, err);
+            state.record(request_id, "error", serde_json::json!({ "message": format!("{:#}", err) }));
+            send_error(&mut socket, &options, &format!("{}{:#}", SERVER_ERROR_PREFIX, err)).await?;
+            return Ok(Some(socket));
+        }
+    };
+
+    // On Windows, assign to Job Object
+    #[cfg(target_os = "windows")]
+    let process_tree = {
+        let job = match child.raw_handle() {
+            Some(handle) => win_job::assign_to_new_job(handle, &config.job_limits).and_then(|job| {
+                if let Some(percent) = options.cpu_percent {
+                    job.set_cpu_rate_limit(percent)?;
+                }
+                Ok(job)
+            }),
+            // Should not happen on Windows unless process already exited
+            None => Err(anyhow::anyhow!("Failed to get child process handle")),
+        };
+        match job {
+            Ok(job) => ProcessTree { job },
+            Err(err) => {
+                let _ = child.kill().await;
+                send_error(&mut socket, &options, &format!("{}{:#}", SERVER_ERROR_PREFIX, err)).await?;
+                return Err(err);
+            }
+        }
+    };
+    #[cfg(unix)]
+    let process_tree = ProcessTree { pgid: child.id() };
+
+    // Register the command so an operator can stop it with `kill <id>`
+    let (registration, admin_kill) = state.commands.register(command_line);
+    log_println!("Started command #{} (pid {:?})", registration.id, child.id());
+    if let Some(path) = &scratch_path {
+        log_println!("Command #{} scratch directory: {}", registration.id, path);
+    }
+
+    if options.json {
+        let started = JsonEvent::Started {
+            version: JSON_PROTOCOL_VERSION,
+            pid: child.id(),
+            scratch_dir: scratch_path.as_deref(),
+        };
+        write_json_event(&mut socket, &started).await?;
+    } else if let Some(path) = &scratch_path {
+        let line = format!("{}{}\n", SCRATCH_PREFIX, path);
+        socket.write_all(&encode_report(&options, &line)).await?;
+    }
+
+    if options.show_invocation {
+        let line = format!("{}{:?}\n", INVOCATION_PREFIX, invocation);
+        socket.write_all(&encode_report(&options, &line)).await?;
+        let cwd = match (&scratch_path, &spec.cwd) {
+            (Some(path), _) => Some(PathBuf::from(path)),
+            (None, Some(cwd)) => std::path::absolute(cwd).ok(),
+            (None, None) => env::current_dir().ok(),
+        };
+        if let Some(cwd) = cwd {
+            let line = format!("{}{}\n", CWD_PREFIX, cwd.display());
+            socket.write_all(&encode_report(&options, &line)).await?;
+        }
+    }
+
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let child_stdin = child.stdin.take();
+
+    // A resumable command's response goes to a buffer instead, which is sent on to this
+    // connection and to any that resumes it
+    let resume = if options.resumable {
+        let (token, output) = state.resumable.open()?;
+        let line = format!("{}{}\n", RESUME_TOKEN_PREFIX, token);
+        socket.write_all(&encode_report(&options, &line)).await?;
+        Some((token, output))
+    } else {
+        None
+    };
+
+    // 3. Stream output
+    let (mut socket_reader, socket_writer) = tokio::io::split(socket);
+    let mut socket_writer = match &resume {
+        Some((_, output)) => {
+            // Attached before the command's output comes in, so none of it is dropped unsent
+            let reader = output.attach(0).expect("a new output starts at byte 0");
+            tokio::spawn(async move {
+                let _ = stream_resumable_output(reader, socket_writer).await;
+            });
+            ResponseWriter::Resumable(output.clone())
+        }
+        None => ResponseWriter::Socket(socket_writer),
+    };
+    
+    // Notification to kill child if socket drops
+    let kill_notify = Arc::new(Notify::new());
+    let kill_notify_clone_read = kill_notify.clone();
+    let kill_notify_clone_write = kill_notify.clone();
+    // Fired by the writer once the output reaches `max_output`
+    let output_limit = Arc::new(Notify::new());
+    let output_limit_clone = output_limit.clone();
+
+    // Monitor socket for disconnection (Read EOF), after passing on the client's stdin if it sends it.
+    // Told to stop, it hands the read half back so a session can go on
+    let stop_monitor = Arc::new(Notify::new());
+    let stop_monitor_clone = stop_monitor.clone();
+    let (resumable, command_id, buf_size) = (resume.is_some(), registration.id, config.buf_size);
+    let monitor_handle = tokio::spawn(async move {
+        let mut input = std::io::Cursor::new(early_input).chain(&mut socket_reader);
+        if let Some(pipe) = child_stdin {
+            if forward_stdin(&mut input, pipe).await.is_err() {
+                kill_notify_clone_read.notify_one();
+                return None;
+            }
+        }
+        let mut buf = vec![0; buf_size];
+        // We don't expect any more data from client, so any read returning 0 means EOF (disconnect).
+        loop {
+            tokio::select! {
+                read = input.read(&mut buf) => match read {
+                    Ok(0) | Err(_) if resumable => {
+                        log_println!("Client of command #{} disconnected; the command keeps running for a resume", command_id);
+                        return None;
+                    }
+                    Ok(0) | Err(_) => {
+                        kill_notify_clone_read.notify_one();
+                        return None;
+                    }
+                    Ok(_) => { } // Ignore extra data
+                },
+                _ = stop_monitor_clone.notified() => break,
+            }
+        }
+        drop(input);
+        Some(socket_reader)
+    });
+
+    // Stream stdout and stderr to socket
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<(OutputStream, Vec<u8>)>(32);
+    let line_buffered = options.line_buffered;
+    let stdout_handle = stdout.map(|pipe| spawn_output_reader(pipe, OutputStream::Stdout, line_buffered, buf_size, tx.clone()));
+    let stderr_handle = stderr.map(|pipe| spawn_output_reader(pipe, OutputStream::Stderr, line_buffered, buf_size, tx));
+
+    // Optional per-connection output cap (bytes per second)
+    let mut rate_limiter = config.max_rate_per_conn.map(TokenBucket::new);
+
+    // Write loop: receive from channel, write to socket
+    let json = options.json;
+    let framed = options.framed;
+    let to_clipboard = options.to_clipboard;
+    let initial_batch = options.initial_batch.unwrap_or(0);
+    let max_output = options.max_output;
+    let truncated_line = encode_report(&options, &format!("{}{}\n", TRUNCATED_PREFIX, max_output.unwrap_or(0)));
+    // TCP keepalive probes stop at the first proxy (Docker's userland proxy terminates the
+    // connection), so a command that stays quiet for minutes also gets a heartbeat the
+    // proxies have to carry: an empty report frame, which clients skip
+    let heartbeat = config.heartbeat.filter(|_| framed);
+    let writer_handle = tokio::spawn(async move {
+        let mut throttled = false;
+        // Incomplete UTF-8 sequences carried over between chunks, per stream (JSON only)
+        let mut pending_text = [Vec::new(), Vec::new()];
+        // Bytes produced by the command, per stream
+        let mut byte_counts = [0u64; 2];
+        // Stdout kept for the clipboard, dropped if it outgrows the limit
+        let mut clipboard_text = to_clipboard.then(Vec::new);
+        // The first `initial_batch` frames, collected to go out in a single write
+        let mut batch = Vec::new();
+        let mut batch_left = initial_batch;
+        let mut truncated = false;
+        loop {
+            if truncated {
+                break;
+            }
+            let next = if batch_left > 0 && !batch.is_empty() {
+                // A burst is never held back once the command goes quiet
+                match tokio::time::timeout(INITIAL_BATCH_MAX_WAIT, rx.recv()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        batch_left = 0;
+                        let frame = std::mem::take(&mut batch);
+                        if write_paced(&mut socket_writer, &mut rate_limiter, &mut throttled, &frame).await.is_err() {
+                            kill_notify_clone_write.notify_one();
+                            break;
+                        }
+                        continue;
+                    }
+                }
+            } else if let Some(heartbeat) = heartbeat {
+                match tokio::time::timeout(heartbeat, rx.recv()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        if socket_writer.write_all(&encode_frame(FRAME_CONTROL, b"")).await.is_err() {
+                            kill_notify_clone_write.notify_one();
+                            break;
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                rx.recv().await
+            };
+            let Some((stream, mut data)) = next else {
+                break;
+            };
+            // Output up to the limit is sent whole; once it goes past, the rest is cut and the command stopped
+            if let Some(limit) = max_output {
+                let left = limit.saturating_sub(byte_counts[0] + byte_counts[1]);
+                if data.len() as u64 > left {
+                    data.truncate(left as usize);
+                    truncated = true;
+                }
+            }
+            byte_counts[stream as usize] += data.len() as u64;
+            if let (OutputStream::Stdout, Some(text)) = (stream, clipboard_text.as_mut()) {
+                if text.len() + data.len() <= CLIPBOARD_MAX_BYTES {
+                    text.extend_from_slice(&data);
+                } else {
+                    log_eprintln!("[WARNING] Output exceeds {} bytes, not copying it to the clipboard", CLIPBOARD_MAX_BYTES);
+                    clipboard_text = None;
+                }
+            }
+            // The copy never holds up the client: a consumer that falls behind loses the rest
+            if let Some(pipe) = &output_pipe {
+                match pipe.try_send(data.clone()) {
+                    Ok(()) => {}
+                    Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                        log_eprintln!("[WARNING] Named pipe consumer fell behind, no longer copying output to it");
+                        output_pipe = None;
+                    }
+                    Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => output_pipe = None,
+                }
+            }
+            // Output is passed on byte for byte, so binary output (images, archives) arrives
+            // intact; only JSON events need text, and only they are decoded
+            let frame = if json {
+                let text = take_utf8(&mut pending_text[stream as usize], &data);
+                if text.is_empty() {
+                    continue;
+                }
+                encode_json_event(&JsonEvent::output(stream, &text))
+            } else if framed {
+                encode_frame(stream.frame_id(), &data)
+            } else {
+                data
+            };
+            let frame = if batch_left > 0 {
+                batch.extend_from_slice(&frame);
+                batch_left -= 1;
+                if batch_left > 0 {
+                    continue;
+                }
+                std::mem::take(&mut batch)
+            } else {
+                frame
+            };
+            if write_paced(&mut socket_writer, &mut rate_limiter, &mut throttled, &frame).await.is_err() {
+                kill_notify_clone_write.notify_one();
+                break;
+            }
+        }
+        // A batch still open when the output ended
+        if !batch.is_empty() {
+            let _ = write_paced(&mut socket_writer, &mut rate_limiter, &mut throttled, &batch).await;
+        }
+        for stream in [OutputStream::Stdout, OutputStream::Stderr] {
+            let leftover = std::mem::take(&mut pending_text[stream as usize]);
+            if !leftover.is_empty() {
+                let text = String::from_utf8_lossy(&leftover);
+                let _ = socket_writer.write_all(&encode_json_event(&JsonEvent::output(stream, &text))).await;
+            }
+        }
+        if truncated {
+            output_limit_clone.notify_one();
+            // JSON clients see the `OutputLimit` reason of the exit event instead
+            if !json {
+                let _ = socket_writer.write_all(&truncated_line).await;
+            }
+        }
+        let _ = socket_writer.flush().await;
+        (socket_writer, byte_counts, clipboard_text, truncated)
+    });
+
+    // Wait for child to exit, the kill signal, or the timeout. Output is drained after this,
+    // whichever way it ends
+    let mut timed_out = false;
+    let (status, mut reason) = tokio::select! {
+        status = child.wait() => {
+            // Process finished on its own, possibly by crashing or being killed externally
+            let status = status.ok();
+            let reason = status.as_ref().and_then(abnormal_exit_reason);
+            (status, reason)
+        }
+        _ = kill_notify.notified() => {
+            log_println!("Client disconnected, killing process...");
+            process_tree.kill();
+            let _ = child.kill().await;
+            (None, None)
+        }
+        _ = admin_kill.notified() => {
+            log_println!("Command #{} stopped by operator request, killing process...", registration.id);
+            process_tree.kill();
+            let _ = child.kill().await;
+            (None, Some("KilledByOperator".to_string()))
+        }
+        _ = async {
+            match &resume {
+                Some((_, output)) => output.abandoned().await,
+                None => std::future::pending().await,
+            }
+        } => {
+            log_println!("Command #{} had no client for {}s, killing process...", registration.id, RESUME_KEEP.as_secs());
+            process_tree.kill();
+            let _ = child.kill().await;
+            (None, Some("Abandoned".to_string()))
+        }
+        _ = output_limit.notified() => {
+            log_println!("Command #{} reached the output limit, killing process...", registration.id);
+            process_tree.kill();
+            let _ = child.kill().await;
+            (None, Some("OutputLimit".to_string()))
+        }
+        _ = async {
+            match spec.timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        } => {
+            log_println!("Command timed out, killing process...");
+            timed_out = true;
+            process_tree.kill();
+            let _ = child.kill().await;
+            (None, Some("TimedOut".to_string()))
+        }
+    };
+    let run_time = start_time.elapsed();
+    match (&status, &reason) {
+        (_, Some(reason)) => log_println!("Command #{} ended abnormally: {}", registration.id, reason),
+        (Some(status), None) => log_println!("Command #{} exited with code {:?}", registration.id, status.code()),
+        (None, None) => {}
+    }
+
+    // The child exiting doesn't mean its output has been sent: a fast command can exit with
+    // most of it still in the pipes. The readers run to EOF, the writer drains the channel
+    // they feed and flushes, and only then do the report lines and the shutdown go out
+    for handle in [stdout_handle, stderr_handle].into_iter().flatten() {
+        let _ = handle.await;
+    }
+    // In a session, the read half is taken back before the result goes out: the client sends
+    // its next request as soon as it has the exit line, and the monitor would swallow it
+    let socket_reader = if options.session {
+        stop_monitor.notify_one();
+        monitor_handle.await.ok().flatten()
+    } else {
+        monitor_handle.abort();
+        None
+    };
+    let mut session_writer = None;
+    if let Ok((mut socket_writer, [stdout_bytes, stderr_bytes], clipboard_text, truncated)) = writer_handle.await {
+        // A command that ended on its own just as the limit was reached still had its output cut
+        if truncated && reason.is_none() {
+            reason = Some("OutputLimit".to_string());
+        }
+        if let Some(text) = clipboard_text {
+            let text = String::from_utf8_lossy(&text).into_owned();
+            match tokio::task::spawn_blocking(move || copy_to_clipboard(&text)).await {
+                Ok(Ok(())) => log_println!("Command #{} output copied to the clipboard", registration.id),
+                Ok(Err(e)) => log_eprintln!("[WARNING] Failed to copy output to the clipboard: {:#}", e),
+                Err(e) => log_eprintln!("[WARNING] Failed to copy output to the clipboard: {}", e),
+            }
+        }
+        if json {
+            let exit = JsonEvent::Exit {
+                code: status.and_then(|s| s.code()),
+                timed_out,
+                reason: reason.as_deref(),
+                stdout_bytes,
+                stderr_bytes,
+                run_ms: run_time.as_millis() as u64,
+            };
+            let _ = write_json_event(&mut socket_writer, &exit).await;
+        } else {
+            if options.stats {
+                let line = format!("{}stdout={} stderr={}\n", STATS_PREFIX, stdout_bytes, stderr_bytes);
+                let _ = socket_writer.write_all(&encode_report(&options, &line)).await;
+            }
+            if options.exit_code {
+                let code = reported_exit_code(status.as_ref(), reason.as_deref());
+                let _ = socket_writer.write_all(&encode_report(&options, &exit_line(code, reason.as_deref(), protocol.reports_run_time().then_some(run_time)))).await;
+            }
+        }
+        if options.session {
+            let _ = socket_writer.flush().await;
+            session_writer = Some(socket_writer);
+        } else {
+            // Ends the response (with a TLS close_notify first); the read half alone would keep
+            // the connection open
+            let _ = socket_writer.shutdown().await;
+        }
+    }
+    let socket = match (socket_reader, session_writer) {
+        (Some(reader), Some(ResponseWriter::Socket(writer))) => Some(reader.unsplit(writer)),
+        _ => None,
+    };
+    if let Some((token, output)) = resume {
+        // Already done by the writer's shutdown, unless the writer task failed
+        output.finish();
+        let state = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(RESUME_KEEP).await;
+            state.resumable.remove(&token);
+        });
+    }
+
+    if let Some(dir) = scratch_dir {
+        if options.keep_scratch {
+            log_println!("Command #{} scratch directory kept: {}", registration.id, dir.keep().display());
+        } else if let Err(e) = dir.close() {
+            log_eprintln!("[WARNING] Failed to remove scratch directory {}: {}", scratch_path.unwrap_or_default(), e);
+        }
+    }
+
+    state.record(
+        request_id,
+        "exit",
+        serde_json::json!({
+            "command_id": registration.id,
+            "code": status.and_then(|s| s.code()),
+            "reason": reason,
+            "duration_ms": start_time.elapsed().as_millis() as u64,
+        }),
+    );
+
+    // The client already has its result; the hook runs on its own
+    if let Some(hook) = config.post_hook.clone() {
+        let hook_env = [
+            ("WINBOAT_COMMAND_ID", registration.id.to_string()),
+            ("WINBOAT_COMMAND", command_line.to_string()),
+            ("WINBOAT_EXIT_CODE", status.and_then(|s| s.code()).map(|c| c.to_string()).unwrap_or_default()),
+            ("WINBOAT_EXIT_REASON", reason.unwrap_or_default()),
+        ];
+        tokio::spawn(run_post_hook(hook, registration.id, hook_env));
+    }
+
+    Ok(socket)
+}
+
+/// Server variables a `clean-env` command still gets. Without these, cmd can't find programs
+/// and many Windows components (Winsock, COM, .NET) fail to start.
+#[cfg(target_os = "windows")]
+const CLEAN_ENV_KEEP: &[&str] = &[
+    "SystemRoot", "SystemDrive", "windir", "ComSpec", "PATH", "PATHEXT", "TEMP", "TMP",
+    "NUMBER_OF_PROCESSORS", "PROCESSOR_ARCHITECTURE",
+];
+#[cfg(not(target_os = "windows"))]
+const CLEAN_ENV_KEEP: &[&str] = &["PATH", "HOME", "TMPDIR"];
+
+/// `--shell`: what runs the command line. PowerShell gets it as a `-Command`, so scripts
+/// don't need wrapping in `powershell -Command "..."` and a second round of quoting.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    /// `cmd /C`, the default on Windows
+    Cmd,
+    /// Windows PowerShell
+    Powershell,
+    /// PowerShell 7 and later
+    Pwsh,
+    /// `sh -c`, the default elsewhere
+    Sh,
+    /// `bash -c`
+    Bash,
+}
+
+impl Shell {
+    /// Shell used when the request doesn't name one.
+    #[cfg(target_os = "windows")]
+    pub const DEFAULT: Shell = Shell::Cmd;
+    #[cfg(not(target_os = "windows"))]
+    pub const DEFAULT: Shell = Shell::Sh;
+
+    /// Name sent in the `OPT shell` line, as `--shell` spells it.
+    fn name(self) -> &'static str {
+        match self {
+            Shell::Cmd => "cmd",
+            Shell::Powershell => "powershell",
+            Shell::Pwsh => "pwsh",
+            Shell::Sh => "sh",
+            Shell::Bash => "bash",
+        }
+    }
+
+    /// Characters that chain commands, redirect or expand in this shell's command lines.
+    fn metacharacters(self) -> &'static [char] {
+        match self {
+            Shell::Cmd => &['&', '|', '<', '>', '^', ';', '%', '\n', '\r'],
+            Shell::Powershell | Shell::Pwsh => &['&', '|', '<', '>', ';', '`', '$', '(', ')', '{', '}', '\n', '\r'],
+            Shell::Sh | Shell::Bash => &['&', '|', '<', '>', ';', '`', '$', '(', ')', '\n', '\r'],
+        }
+    }
+
+    /// Program to start and the arguments that go before the command line.
+    fn argv(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            Shell::Cmd => ("cmd", &["/C"]),
+            Shell::Powershell => ("powershell", &["-NoProfile", "-NonInteractive", "-Command"]),
+            Shell::Pwsh => ("pwsh", &["-NoProfile", "-NonInteractive", "-Command"]),
+            Shell::Sh => ("sh", &["-c"]),
+            Shell::Bash => ("bash", &["-c"]),
+        }
+    }
+}
+
+/// Longest a post-command hook may run before it is killed.
+const POST_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs WINBOAT_POST_HOOK for a finished command, with the command's result in its environment.
+/// Failures are only logged.
+async fn run_post_hook(hook: String, command_id: u64, env: [(&'static str, String); 4]) {
+    let (program, shell_args) = Shell::DEFAULT.argv();
+    let mut command = Command::new(program);
+    command
+        .args(shell_args)
+        .arg(&hook)
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+    let result = match command.spawn() {
+        Ok(mut child) => tokio::time::timeout(POST_HOOK_TIMEOUT, child.wait()).await,
+        Err(e) => {
+            log_eprintln!("[WARNING] Post-command hook for command #{} failed to start: {}", command_id, e);
+            return;
+        }
+    };
+    match result {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => log_eprintln!("[WARNING] Post-command hook for command #{} failed: {}", command_id, status),
+        Ok(Err(e)) => log_eprintln!("[WARNING] Post-command hook for command #{} failed: {}", command_id, e),
+        Err(_) => log_eprintln!(
+            "[WARNING] Post-command hook for command #{} killed after {}s",
+            command_id,
+            POST_HOOK_TIMEOUT.as_secs()
+        ),
+    }
+}
+
+/// Describes a termination by signal, e.g. `KilledBySignal(9)` after the OOM killer.
+#[cfg(unix)]
+fn abnormal_exit_reason(status: &std::process::ExitStatus) -> Option<String> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().map(|signal| format!("KilledBySignal({})", signal))
+}
+
+/// Recognizes the NTSTATUS exit codes Windows uses for crashed or forcibly ended processes.
+#[cfg(target_os = "windows")]
+fn abnormal_exit_reason(status: &std::process::ExitStatus) -> Option<String> {
+    let reason = match status.code()? as u32 {
+        0xC0000005 => "CrashedAccessViolation",
+        0xC000001D => "CrashedIllegalInstruction",
+        0xC0000094 => "CrashedIntegerDivideByZero",
+        0xC00000FD => "CrashedStackOverflow",
+        0xC0000374 => "CrashedHeapCorruption",
+        0xC0000409 => "CrashedStackBufferOverrun",
+        0xC0000017 => "CrashedOutOfMemory",
+        0xC000013A => "TerminatedByCtrlC",
+        _ => return None,
+    };
+    Some(reason.to_string())
+}
+
+/// Everything a spawned command started: its Job Object on Windows, its process group
+/// elsewhere. Killing only the shell would leave descendants holding the output pipes open.
+struct ProcessTree {
+    #[cfg(target_os = "windows")]
+    job: win_job::JobHandle,
+    #[cfg(unix)]
+    pgid: Option<u32>,
+}
+
+impl ProcessTree {
+    fn kill(&self) {
+        #[cfg(target_os = "windows")]
+        self.job.terminate();
+        #[cfg(unix)]
+        if let Some(pgid) = self.pgid {
+            // SAFETY: plain syscall; a negative pid targets the process group
+            unsafe {
+                libc::kill(-(pgid as i32), libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Which of the child's pipes a chunk of output came from.
+#[derive(Clone, Copy)]
+enum OutputStream {
+    Stdout = 0,
+    Stderr = 1,
+}
+
+impl OutputStream {
+    fn frame_id(self) -> u8 {
+        match self {
+            OutputStream::Stdout => FRAME_STDOUT,
+            OutputStream::Stderr => FRAME_STDERR,
+        }
+    }
+}
+
+/// Forwards everything read from one of the child's pipes into the output channel until EOF,
+/// as it comes or, with `line_buffered`, in whole lines.
+fn spawn_output_reader<R>(
+    pipe: R,
+    stream: OutputStream,
+    line_buffered: bool,
+    buf_size: usize,
+    tx: tokio::sync::mpsc::Sender<(OutputStream, Vec<u8>)>,
+) -> tokio::task::JoinHandle<()>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut reader = tokio::io::BufReader::with_capacity(buf_size, pipe);
+        if line_buffered {
+            forward_lines(&mut reader, stream, &tx).await;
+            return;
+        }
+        let mut buf = vec![0; buf_size];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) => break, // EOF
+                Ok(n) => {
+                    if tx.send((stream, buf[..n].to_vec())).await.is_err() { break; }
+                }
+                Err(_) => break,
+            }
+        }
+    })
+}
+
+/// Longest a line may grow in line-buffered mode before it is sent anyway (binary output).
+const LINE_BUFFER_MAX: usize = 8 * 1024;
+/// Time without a newline after which line-buffered output is sent anyway, so a prompt that
+/// doesn't end its line still shows up.
+const LINE_FLUSH_DELAY: Duration = Duration::from_millis(50);
+
+/// Line-buffered variant of the reader loop: each chunk ends at a newline or carriage
+/// return, so chunks never split a line. Output without a newline goes out after `LINE_FLUSH_DELAY` of silence or
+/// once it reaches `LINE_BUFFER_MAX`.
+async fn forward_lines<R>(reader: &mut tokio::io::BufReader<R>, stream: OutputStream, tx: &tokio::sync::mpsc::Sender<(OutputStream, Vec<u8>)>)
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let mut pending = Vec::new();
+    loop {
+        // fill_buf keeps what it has read in the reader, so the timeout loses nothing
+        let read = if pending.is_empty() {
+            Ok(reader.fill_buf().await)
+        } else {
+            tokio::time::timeout(LINE_FLUSH_DELAY, reader.fill_buf()).await
+        };
+        let (consumed, line_end) = match read {
+            Ok(Ok([])) | Ok(Err(_)) => break, // EOF
+            Ok(Ok(data)) => {
+                // A carriage return ends a line too: progress bars redraw with it
+                let line_end = data.iter().rposition(|b| matches!(b, b'\n' | b'\r')).map(|i| i + 1);
+                let consumed = line_end.unwrap_or(data.len());
+                pending.extend_from_slice(&data[..consumed]);
+                (consumed, line_end.is_some())
+            }
+            // Quiet with a partial line pending: send it as it is
+            Err(_) => (0, true),
+        };
+        reader.consume(consumed);
+        if (line_end || pending.len() >= LINE_BUFFER_MAX) && tx.send((stream, std::mem::take(&mut pending))).await.is_err() {
+            return;
+        }
+    }
+    if !pending.is_empty() {
+        let _ = tx.send((stream, pending)).await;
+    }
+}
+
+/// Chunks of output waiting for a connected named pipe consumer; when it falls further
+/// behind, the copy stops rather than slowing the command down.
+#[cfg(target_os = "windows")]
+const OUTPUT_PIPE_CHUNKS: usize = 256;
+
+/// Most output queued for a named pipe that nothing has connected to yet.
+#[cfg(target_os = "windows")]
+const OUTPUT_PIPE_QUEUE_MAX: usize = 16 * 1024 * 1024;
+
+/// Creates `\\.\pipe\winboat-<name>` and returns a sender whose chunks are written to it.
+/// Output is queued until a consumer connects; if none connects before the command
+/// finishes, or before `OUTPUT_PIPE_QUEUE_MAX` bytes pile up, the queued output is dropped.
+#[cfg(target_os = "windows")]
+fn open_output_pipe(name: &str) -> Result<tokio::sync::mpsc::Sender<Vec<u8>>> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    validate_pipe_name(name)?;
+    let path = format!(r"\\.\pipe\winboat-{}", name);
+    let pipe = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(&path)
+        .with_context(|| format!("Failed to create named pipe {}", path))?;
+    log_println!("Copying output to named pipe {}", path);
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(OUTPUT_PIPE_CHUNKS);
+    tokio::spawn(async move {
+        let mut queued = Vec::new();
+        let mut queued_bytes = 0;
+        loop {
+            tokio::select! {
+                connected = pipe.connect() => {
+                    if let Err(e) = connected {
+                        log_eprintln!("Named pipe {} connect failed: {}", path, e);
+                        return;
+                    }
+                    break;
+                }
+                chunk = rx.recv() => match chunk {
+                    Some(chunk) => {
+                        queued_bytes += chunk.len();
+                        if queued_bytes > OUTPUT_PIPE_QUEUE_MAX {
+                            log_eprintln!("[WARNING] No consumer connected to {} within {} bytes of output, dropping the copy", path, OUTPUT_PIPE_QUEUE_MAX);
+                            return;
+                        }
+                        queued.push(chunk);
+                    }
+                    None => {
+                        log_println!("No consumer connected to {} before the command finished", path);
+                        return;
+                    }
+                },
+            }
+        }
+
+        let mut pipe = pipe;
+        for chunk in queued {
+            if pipe.write_all(&chunk).await.is_err() {
+                return;
+            }
+        }
+        while let Some(chunk) = rx.recv().await {
+            if pipe.write_all(&chunk).await.is_err() {
+                return;
+            }
+        }
+        let _ = pipe.flush().await;
+    });
+
+    Ok(tx)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn open_output_pipe(name: &str) -> Result<tokio::sync::mpsc::Sender<Vec<u8>>> {
+    validate_pipe_name(name)?;
+    Err(anyhow::anyhow!("--output-pipe is only supported by Windows servers"))
+}
+
+/// Largest stdout that `--to-clipboard` copies; bigger outputs leave the clipboard unchanged.
+const CLIPBOARD_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Replaces the clipboard of the session the server runs in with `text`.
+#[cfg(target_os = "windows")]
+fn copy_to_clipboard(text: &str) -> Result<()> {
+    clipboard_win::set_clipboard_string(text)
+        .map_err(|e| anyhow::anyhow!("Failed to set clipboard: {}", e))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn copy_to_clipboard(_text: &str) -> Result<()> {
+    Err(anyhow::anyhow!("--to-clipboard is only supported by Windows servers"))
+}
+
+/// Pipe names become part of a `\\.\pipe\` path, so keep them to a safe character set.
+fn validate_pipe_name(name: &str) -> Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(anyhow::anyhow!(
+            "Invalid output pipe name '{}': use letters, digits, '-', '_' or '.'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Marks a message about the server failing to run a command, as opposed to a refusal.
+const SERVER_ERROR_PREFIX: &str = "Server error: ";
+
+/// Sends an error to the client in the encoding its request used.
+async fn send_error(socket: &mut Connection, options: &RequestOptions, message: &str) -> Result<()> {
+    send_refusal(socket, options, message, None).await
+}
+
+/// Reason in the `__EXIT__` line of a request refused for a missing or wrong auth token, so
+/// the client can tell it from a command that failed.
+const UNAUTHORIZED_REASON: &str = "Unauthorized";
+
+/// `send_error`, with `reason` after the code in the `__EXIT__` line.
+async fn send_refusal(socket: &mut Connection, options: &RequestOptions, message: &str, reason: Option<&str>) -> Result<()> {
+    if options.json {
+        write_json_event(socket, &JsonEvent::Error { message }).await?;
+    } else {
+        let reply = format!("{}\n", message);
+        // Plain clients have always received refusals on stdout; framed ones get them on stderr
+        let stream = if options.framed { OutputStream::Stderr } else { OutputStream::Stdout };
+        socket.write_all(&encode_reply(options, stream, &reply)).await?;
+        if options.exit_code {
+            socket.write_all(&encode_report(options, &exit_line(1, reason, None))).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Frame ids of the framed output (`OPT framed`). A frame is the id byte, the payload length
+/// as 4 big-endian bytes, then the payload. Control frames carry one report line each
+/// (`__SCRATCH__`, `__INVOCATION__`, `__STATS__`, `__EXIT__`).
+const FRAME_CONTROL: u8 = 0;
+const FRAME_STDOUT: u8 = 1;
+const FRAME_STDERR: u8 = 2;
+/// Sent by the client with `OPT stdin`: input for the command, an empty frame at its end.
+const FRAME_STDIN: u8 = 3;
+
+/// Largest stdin frame the server accepts.
+const MAX_STDIN_FRAME: usize = 64 * 1024;
+
+/// Writes the client's stdin frames into the command's stdin until the client's empty frame
+/// ends the input, which closes it. Fails if the connection breaks or sends garbage.
+async fn forward_stdin<R: AsyncRead + Unpin>(input: &mut R, pipe: tokio::process::ChildStdin) -> std::io::Result<()> {
+    let mut pipe = Some(pipe);
+    loop {
+        let mut header = [0; 5];
+        input.read_exact(&mut header).await?;
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if header[0] != FRAME_STDIN || len > MAX_STDIN_FRAME {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "invalid stdin frame"));
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        let mut data = vec![0; len];
+        input.read_exact(&mut data).await?;
+        // A command that stops reading early just doesn't get the rest
+        if let Some(stdin) = pipe.as_mut() {
+            if stdin.write_all(&data).await.is_err() {
+                pipe = None;
+            }
+        }
+    }
+}
+
+fn encode_frame(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(id);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A report line as sent to a plain client: a control frame if it asked for framing,
+/// the bare line otherwise.
+fn encode_report(options: &RequestOptions, line: &str) -> Vec<u8> {
+    if options.framed {
+        encode_frame(FRAME_CONTROL, line.as_bytes())
+    } else {
+        line.as_bytes().to_vec()
+    }
+}
+
+/// Server-generated text (control replies, refusals) sent to a plain client as if `stream`
+/// of a command had printed it.
+fn encode_reply(options: &RequestOptions, stream: OutputStream, text: &str) -> Vec<u8> {
+    if options.framed {
+        encode_frame(stream.frame_id(), text.as_bytes())
+    } else {
+        text.as_bytes().to_vec()
+    }
+}
+
+/// Compares two secrets without bailing out at the first differing byte, so the time
+/// taken doesn't tell a client how much of its guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The `__EXIT__` trailer line for `code`, followed by the reason when the command didn't
+/// simply exit and by how long it ran, when given (e.g. `__EXIT__ 124 TimedOut run_ms=30002`).
+/// `run_time` is measured on the server with a monotonic clock (`Instant`), from starting the
+/// command to its end, so it doesn't depend on either machine's wall clock; the client never
+/// combines it with times of its own.
+fn exit_line(code: i32, reason: Option<&str>, run_time: Option<Duration>) -> String {
+    let mut line = format!("{}{}", EXIT_PREFIX, code);
+    if let Some(reason) = reason {
+        line.push_str(&format!(" {}", reason));
+    }
+    if let Some(run_time) = run_time {
+        line.push_str(&format!(" {}{}", RUN_MS_FIELD, run_time.as_millis()));
+    }
+    line.push('\n');
+    line
+}
+
+/// Field of the `__EXIT__` line giving how long the command ran, in milliseconds of the
+/// server's monotonic clock (protocol 2).
+const RUN_MS_FIELD: &str = "run_ms=";
+
+/// Exit code reported in the `__EXIT__` line. Commands that didn't exit on their own get
+/// the codes a Unix shell would use: 124 for a timeout, 128 + the signal number for a kill
+/// (9 for `kill <id>`, 1 for a client that went away).
+fn reported_exit_code(status: Option<&std::process::ExitStatus>, reason: Option<&str>) -> i32 {
+    if let Some(code) = status.and_then(|status| status.code()) {
+        return code;
+    }
+    match reason {
+        Some("TimedOut") => 124,
+        Some("KilledByOperator") => 128 + 9,
+        Some("Abandoned") => 128 + 1,
+        Some(reason) => reason
+            .strip_prefix("KilledBySignal(")
+            .and_then(|signal| signal.strip_suffix(')'))
+            .and_then(|signal| signal.parse::<i32>().ok())
+            .map_or(1, |signal| 128 + signal),
+        None => 128 + 1,
+    }
+}
+
+/// Largest `initial-batch` the server accepts, bounding what it buffers per connection.
+const MAX_INITIAL_BATCH: u16 = 256;
+/// How long an open initial batch waits for the next chunk before it is sent as is.
+const INITIAL_BATCH_MAX_WAIT: Duration = Duration::from_millis(50);
+
+/// Writes one frame of output, paced by the connection's rate limit if it has one.
+async fn write_paced<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    rate_limiter: &mut Option<TokenBucket>,
+    throttled: &mut bool,
+    frame: &[u8],
+) -> std::io::Result<()> {
+    // Pacing here backpressures the channel and, through it, the child's pipes
+    if let Some(bucket) = rate_limiter.as_mut() {
+        if bucket.consume(frame.len()).await && !*throttled {
+            *throttled = true;
+            log_println!("Connection output throttled to {} bytes/s", bucket.rate);
+        }
+    }
+    writer.write_all(frame).await
+}
+
+/// Token bucket pacing a stream to `rate` bytes per second, allowing up to one second of burst.
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: tokio::time::Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: tokio::time::Instant::now(),
+        }
+    }
+
+    /// Takes `amount` tokens, sleeping until the bucket covers them. Returns true if it had to wait.
+    async fn consume(&mut self, amount: usize) -> bool {
+        let now = tokio::time::Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.rate as f64);
+        self.last_refill = now;
+
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            return false;
+        }
+
+        let deficit = -self.tokens;
+        tokio::time::sleep(tokio::time::Duration::from_secs_f64(deficit / self.rate as f64)).await;
+        self.tokens = 0.0;
+        self.last_refill = tokio::time::Instant::now();
+        true
+    }
+}
+
+/// Success criteria on the remote output, for tools that print errors but still exit 0.
+/// Lines are matched one at a time as they stream past.
+pub struct OutputChecks {
+    fail_if_match: Option<Regex>,
+    fail_unless_match: Option<Regex>,
+    /// Output after the last newline, not yet matched
+    partial_line: Vec<u8>,
+    failed_line: Option<String>,
+    required_found: bool,
+}
+
+impl OutputChecks {
+    pub fn new(fail_if_match: Option<Regex>, fail_unless_match: Option<Regex>) -> Self {
+        Self {
+            fail_if_match,
+            fail_unless_match,
+            partial_line: Vec::new(),
+            failed_line: None,
+            required_found: false,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.fail_if_match.is_some() || self.fail_unless_match.is_some()
+    }
+
+    fn feed(&mut self, data: &[u8]) {
+        if !self.is_active() {
+            return;
+        }
+        self.partial_line.extend_from_slice(data);
+        while let Some(pos) = self.partial_line.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.partial_line.drain(..=pos).collect();
+            self.check_line(&line);
+        }
+    }
+
+    fn check_line(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        if self.failed_line.is_none() && self.fail_if_match.as_ref().is_some_and(|re| re.is_match(line)) {
+            self.failed_line = Some(line.to_string());
+        }
+        if !self.required_found && self.fail_unless_match.as_ref().is_some_and(|re| re.is_match(line)) {
+            self.required_found = true;
+        }
+    }
+
+    /// Checks the last unterminated line and reports whether the output passed.
+    fn finish(mut self) -> Result<()> {
+        if !self.partial_line.is_empty() {
+            let line = std::mem::take(&mut self.partial_line);
+            self.check_line(&line);
+        }
+        if let (Some(re), Some(line)) = (&self.fail_if_match, &self.failed_line) {
+            anyhow::bail!("Remote output matched --fail-if-match '{}': {}", re, line);
+        }
+        if let Some(re) = &self.fail_unless_match {
+            if !self.required_found {
+                anyhow::bail!("Remote output never matched --fail-unless-match '{}'", re);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Expands a client-side alias: when the first word of `cmd` is `name` and `WINBOAT_ALIAS_<NAME>`
+/// is set, that word is replaced by the alias and any remaining arguments are kept.
+fn expand_alias(cmd: &str) -> String {
+    let cmd = cmd.trim_start();
+    let (name, args) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_')) {
+        return cmd.to_string();
+    }
+    let var = format!("WINBOAT_ALIAS_{}", name.to_ascii_uppercase().replace('-', "_"));
+    match env::var(&var) {
+        Ok(expansion) if !expansion.trim().is_empty() => {
+            let expanded = if args.trim().is_empty() {
+                expansion.trim().to_string()
+            } else {
+                format!("{} {}", expansion.trim(), args.trim())
+            };
+            eprintln!("[alias] {} -> {}", name, redact_secrets(&expanded));
+            expanded
+        }
+        _ => cmd.to_string(),
+    }
+}
+
+/// `--color`: what to do with the VT escape sequences (colors, cursor movement, titles) a
+/// command writes, e.g. when it thinks it runs in a console.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ColorMode {
+    /// Pass them through unchanged
+    Always,
+    /// Remove them
+    Never,
+    /// Pass them through to a terminal, remove them when stdout is redirected
+    Auto,
+}
+
+impl ColorMode {
+    pub fn strips(self, stdout_is_terminal: bool) -> bool {
+        match self {
+            ColorMode::Always => false,
+            ColorMode::Never => true,
+            ColorMode::Auto => !stdout_is_terminal,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VtState {
+    Text,
+    /// After ESC
+    Escape,
+    /// ESC followed by intermediate bytes, e.g. the charset selection `ESC ( B`
+    Intermediate,
+    /// Control sequence (`ESC [`), up to its final byte
+    Csi,
+    /// Operating system command (`ESC ]`, e.g. a window title), up to BEL or `ESC \`
+    Osc,
+    OscEscape,
+}
+
+/// Recognizes VT escape sequences in the output and removes them if asked. The parser state
+/// of each stream carries over between chunks, so a sequence split across two reads is
+/// still handled.
+pub struct VtFilter {
+    strip: bool,
+    state: [VtState; 2],
+    /// Whether any escape sequence went past
+    seen: bool,
+}
+
+impl VtFilter {
+    pub fn new(strip: bool) -> Self {
+        Self { strip, state: [VtState::Text; 2], seen: false }
+    }
+
+    fn filter(&mut self, stream: OutputStream, data: &[u8]) -> Vec<u8> {
+        let state = &mut self.state[stream as usize];
+        let mut output = Vec::with_capacity(data.len());
+        for &byte in data {
+            let in_sequence = *state != VtState::Text || byte == 0x1b;
+            *state = match (*state, byte) {
+                (VtState::Text, 0x1b) => VtState::Escape,
+                (VtState::Text, _) => VtState::Text,
+                (VtState::Escape, b'[') => VtState::Csi,
+                (VtState::Escape, b']') => VtState::Osc,
+                (VtState::Escape, 0x20..=0x2f) => VtState::Intermediate,
+                // Two-byte sequences such as `ESC 7` (save cursor)
+                (VtState::Escape, _) => VtState::Text,
+                (VtState::Intermediate, 0x20..=0x2f) => VtState::Intermediate,
+                (VtState::Intermediate, _) => VtState::Text,
+                // Parameter and intermediate bytes; the final byte (or anything malformed) ends it
+                (VtState::Csi, 0x20..=0x3f) => VtState::Csi,
+                (VtState::Csi, _) => VtState::Text,
+                (VtState::Osc, 0x07 | b'\n') => VtState::Text,
+                (VtState::Osc, 0x1b) => VtState::OscEscape,
+                (VtState::Osc, _) => VtState::Osc,
+                (VtState::OscEscape, _) => VtState::Text,
+            };
+            self.seen |= in_sequence;
+            if !(self.strip && in_sequence) {
+                output.push(byte);
+            }
+        }
+        output
+    }
+
+    /// Note for the `--stats` report, if the output contained escape sequences.
+    fn report(&self) -> &'static str {
+        match (self.seen, self.strip) {
+            (false, _) => "",
+            (true, false) => ", with VT sequences",
+            (true, true) => ", VT sequences removed",
+        }
+    }
+}
+
+/// Files the client writes the command's streams to instead of the console
+/// (`--stdout-file`, `--stderr-file`). A stream without a file still goes to the console.
+#[derive(Debug, Clone, Default)]
+pub struct OutputFiles {
+    pub stdout: Option<PathBuf>,
+    pub stderr: Option<PathBuf>,
+    /// Append to existing files instead of truncating them (`--append`)
+    pub append: bool,
+}
+
+impl OutputFiles {
+    /// Opens (creating, then truncating or appending to) the file of each redirected stream.
+    async fn open(&self) -> Result<[Option<Redirect>; 2]> {
+        Ok([self.open_one(self.stdout.as_deref()).await?, self.open_one(self.stderr.as_deref()).await?])
+    }
+
+    async fn open_one(&self, path: Option<&Path>) -> Result<Option<Redirect>> {
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(self.append)
+            .truncate(!self.append)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open output file {}", path.display()))?;
+        Ok(Some(Redirect { path: path.to_path_buf(), file, written: 0 }))
+    }
+}
+
+/// A stream's output file and how much of the output went into it.
+struct Redirect {
+    path: PathBuf,
+    file: tokio::fs::File,
+    written: u64,
+}
+
+/// Runs `cmd` on the server, bootstrapping it if needed, and streams its output to this
+/// process's stdout and stderr, or to the `files` they are redirected to. Returns the exit
+/// code to leave with: 0 on success, the command's own code (after `exit`'s mapping, see
+/// `ExitHandling`) when it failed and `options.exit_code` is set.
+pub async fn run_command(
+    cmd: &str,
+    options: &RequestOptions,
+    mut checks: OutputChecks,
+    mut vt_filter: VtFilter,
+    hexdump: bool,
+    files: &OutputFiles,
+    exit: &ExitHandling,
+) -> Result<i32> {
+    let request_line = format!("{}{}", COMMAND_PREFIX, expand_alias(cmd));
+    // Opened first, so a path that can't be written fails before anything runs
+    let redirects = files.open().await?;
+    let mut sink = OutputSink::Console { stdout: tokio::io::stdout(), stderr: tokio::io::stderr(), redirects: Box::new(redirects), scrollback: None };
+    let result = exchange(&request_line, options, &mut checks, &mut vt_filter, hexdump, &mut sink).await;
+    // What made it into the files is reported even when the connection failed
+    if let OutputSink::Console { redirects, .. } = &sink {
+        for (name, redirect) in ["stdout", "stderr"].iter().zip(redirects.iter()) {
+            if let Some(redirect) = redirect {
+                sink.report(format_args!("[files] {}: {} bytes written to {}", name, redirect.written, redirect.path.display()));
+            }
+        }
+    }
+    let end = result?;
+
+    checks.finish()?;
+    if !options.exit_code {
+        return Ok(0);
+    }
+    match end.exit_code {
+        Some(code) => Ok(exit.exit_code(code)),
+        None => anyhow::bail!("Connection closed before the server reported the command's exit code"),
+    }
+}
+
+/// Sends a control request (`shutdown`, `reload`, `kill <id>`, `kill-all`, `version`,
+/// `ping`, `job <id>`) and prints the server's reply. Returns the exit code to leave with,
+/// as `run_command` does.
+pub async fn run_control(request: &str, options: &RequestOptions) -> Result<i32> {
+    let request_line = format!("{}{}", CONTROL_PREFIX, request);
+    let mut sink = OutputSink::Console { stdout: tokio::io::stdout(), stderr: tokio::io::stderr(), redirects: Box::default(), scrollback: None };
+    let end = exchange(&request_line, options, &mut OutputChecks::new(None, None), &mut VtFilter::new(false), false, &mut sink).await?;
+    match end.exit_code {
+        Some(code) => Ok(local_exit_code(code)),
+        None if options.exit_code => anyhow::bail!("Connection closed before the server reported the result"),
+        None => Ok(0),
+    }
+}
+
+/// What `--json` prints once the command ends. The schema is stable: fields may be added,
+/// but the existing ones keep their names and meaning.
+#[derive(Serialize)]
+struct JsonReport {
+    /// The command as sent, after alias expansion
+    command: String,
+    /// The command's stdout as UTF-8, invalid bytes replaced with U+FFFD
+    stdout: String,
+    /// The command's stderr, likewise; server refusals such as `DENIED` end up here too
+    stderr: String,
+    /// Exit code reported by the server; null when it reported none
+    exit_code: Option<i32>,
+    /// Why the command ended abnormally (`TimedOut`, `KilledByOperator`...); null otherwise
+    reason: Option<String>,
+    /// From sending the request to the end of the output, in milliseconds, measured on the
+    /// client
+    duration_ms: u64,
+    /// How long the command ran on the server, from start to exit, in milliseconds, measured
+    /// on the server; null when the server didn't report it (an older server)
+    run_ms: Option<u64>,
+    /// False when the server sent its output unframed (an older server), all in `stdout`
+    streams_separated: bool,
+}
+
+/// Runs `cmd` like `run_command`, but collects the output and prints it as one JSON object
+/// (see `JsonReport`) on stdout once the command ends. Returns the exit code to leave with,
+/// as `run_command` does; the report keeps the code the server sent.
+pub async fn run_command_json(cmd: &str, options: &RequestOptions, mut checks: OutputChecks, mut vt_filter: VtFilter, hexdump: bool, exit: &ExitHandling) -> Result<i32> {
+    let command = expand_alias(cmd);
+    let request_line = format!("{}{}", COMMAND_PREFIX, command);
+    let mut sink = OutputSink::Buffer { stdout: Vec::new(), stderr: Vec::new() };
+    let end = exchange(&request_line, options, &mut checks, &mut vt_filter, hexdump, &mut sink).await?;
+    let OutputSink::Buffer { stdout, stderr } = sink else {
+        unreachable!("the sink was created as a buffer");
+    };
+
+    let report = JsonReport {
+        command,
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        exit_code: end.exit_code,
+        reason: end.reason,
+        duration_ms: end.duration.as_millis() as u64,
+        run_ms: end.run_time.map(|run_time| run_time.as_millis() as u64),
+        streams_separated: end.framed,
+    };
+    println!("{}", serde_json::to_string(&report)?);
+
+    checks.finish()?;
+    Ok(end.exit_code.map_or(0, |code| exit.exit_code(code)))
+}
+
+/// Lines of output a session keeps for `/search`; the oldest go first.
+const SCROLLBACK_LINES: usize = 10_000;
+/// Longest line the scrollback keeps; the rest of a longer line is dropped.
+const SCROLLBACK_LINE_LEN: usize = 4096;
+/// A session input line starting with this searches the scrollback instead of running.
+const SEARCH_PREFIX: &str = "/search";
+
+/// The recent output of a session, line by line, so `/search <regex>` can find what an
+/// earlier command printed. Bounded by `SCROLLBACK_LINES` and `SCROLLBACK_LINE_LEN`.
+#[derive(Default)]
+struct Scrollback {
+    /// The command each line came from (1 for the first one) and the line
+    lines: std::collections::VecDeque<(usize, String)>,
+    /// The unfinished last line of stdout and of stderr
+    partial: [Vec<u8>; 2],
+    /// Commands that have ended so far
+    commands: usize,
+}
+
+impl Scrollback {
+    fn record(&mut self, stream: OutputStream, data: &[u8]) {
+        let index = match stream {
+            OutputStream::Stdout => 0,
+            OutputStream::Stderr => 1,
+        };
+        for (i, piece) in data.split(|byte| *byte == b'\n').enumerate() {
+            if i > 0 {
+                let line = std::mem::take(&mut self.partial[index]);
+                self.push(&line);
+            }
+            let room = SCROLLBACK_LINE_LEN.saturating_sub(self.partial[index].len());
+            self.partial[index].extend_from_slice(&piece[..piece.len().min(room)]);
+        }
+    }
+
+    /// Keeps the last line of each stream even if the command didn't end it.
+    fn end_command(&mut self) {
+        for index in 0..2 {
+            if !self.partial[index].is_empty() {
+                let line = std::mem::take(&mut self.partial[index]);
+                self.push(&line);
+            }
+        }
+        self.commands += 1;
+    }
+
+    fn push(&mut self, line: &[u8]) {
+        if self.lines.len() == SCROLLBACK_LINES {
+            self.lines.pop_front();
+        }
+        let line = String::from_utf8_lossy(line);
+        self.lines.push_back((self.commands + 1, line.trim_end_matches('\r').to_string()));
+    }
+
+    fn search<'a>(&'a self, pattern: &'a Regex) -> impl Iterator<Item = &'a (usize, String)> {
+        self.lines.iter().filter(|(_, line)| pattern.is_match(line))
+    }
+}
+
+/// Runs the commands read from stdin, one per line, over a single connection (`OPT session`)
+/// and prints their output as `run_command` does. Blank lines are skipped, and a line
+/// `/search <regex>` lists the lines of earlier output that match instead of running (see
+/// `Scrollback`). A failed command doesn't end the session unless `stop_on_error` is set; a
+/// command failed if its code, after `exit`'s mapping, isn't 0. Returns the exit code to
+/// leave with: the last command's, or the failed one's when the session stopped on it.
+pub async fn run_session(options: &RequestOptions, stop_on_error: bool, mut checks: OutputChecks, mut vt_filter: VtFilter, hexdump: bool, exit: &ExitHandling) -> Result<i32> {
+    let options = RequestOptions { session: true, framed: true, exit_code: true, stdin: false, enqueue: false, resumable: false, ..options.clone() };
+    let scrollback = Some(Box::default());
+    let mut sink = OutputSink::Console { stdout: tokio::io::stdout(), stderr: tokio::io::stderr(), redirects: Box::default(), scrollback };
+    let mut commands = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    // Opened for the first command, so an empty input never connects
+    let mut connection = None;
+    let mut exit_code = 0;
+    while let Some(line) = commands.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(pattern) = line.trim().strip_prefix(SEARCH_PREFIX).filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace)) {
+            sink.search_scrollback(pattern.trim());
+            continue;
+        }
+        let command = expand_alias(&line);
+        let (socket, socket_writer, request) = match &mut connection {
+            Some((socket, socket_writer)) => (socket, socket_writer, format!("{}{}\n", COMMAND_PREFIX, command)),
+            None => {
+                let socket = HexdumpStream::new(connect_to_server(hexdump).await?, hexdump);
+                let (socket, socket_writer) = tokio::io::split(socket);
+                let (socket, socket_writer) = connection.insert((tokio::io::BufReader::with_capacity(buf_size_from_env(), socket), socket_writer));
+                (socket, socket_writer, format!("{}{}{}\n", options.encode(), COMMAND_PREFIX, command))
+            }
+        };
+        socket_writer.write_all(request.as_bytes()).await?;
+        let mut end = ExchangeEnd::new();
+        read_response(socket, &options, &mut checks, &mut vt_filter, &mut sink, &mut end, true).await?;
+        if let OutputSink::Console { scrollback: Some(scrollback), .. } = &mut sink {
+            scrollback.end_command();
+        }
+        let Some(code) = end.exit_code else {
+            anyhow::bail!("Connection closed before the server reported the exit code of: {}", command);
+        };
+        exit_code = exit.exit_code(code);
+        if exit_code != 0 && stop_on_error {
+            sink.report(format_args!("[session] Stopping after a failed command (exit code {}): {}", code, command));
+            break;
+        }
+    }
+    if let Some((_, mut socket_writer)) = connection {
+        let _ = socket_writer.shutdown().await;
+    }
+
+    checks.finish()?;
+    Ok(exit_code)
+}
+
+/// Prints what `run_command` would do for `cmd` without connecting: the request it would
+/// send, auth token redacted, and the bootstrap it would run if no server answered.
+pub fn dry_run(cmd: &str, options: &RequestOptions) -> Result<()> {
+    let connector = ServerConnector::from_env(false)?;
+    let shown = RequestOptions {
+        auth_token: options.auth_token.as_ref().map(|_| REDACTED.to_string()),
+        admin_token: options.admin_token.as_ref().map(|_| REDACTED.to_string()),
+        ..options.clone()
+    };
+    println!("[dry-run] Would connect to {} and send:", connector.addr);
+    println!("{}{}{}", shown.encode(), COMMAND_PREFIX, expand_alias(cmd));
+
+    match bootstrap_plan() {
+        Ok((transport, script)) => {
+            let (args, input) = transport.invocation(&script, true);
+            let argv: Vec<&str> = std::iter::once(transport.program()).chain(args.iter().map(String::as_str)).collect();
+            println!("[dry-run] If no server answers, the bootstrap would run: {:?}", argv);
+            for line in input.iter().flat_map(|input| input.lines()) {
+                println!("[dry-run]   typed into {}: {}", transport.program(), line);
+            }
+            println!("[dry-run] PowerShell script: {}", script);
+        }
+        Err(e) => println!("[dry-run] If no server answers, the bootstrap would fail: {:#}", e),
+    }
+    Ok(())
+}
+
+/// Output and exit code of a command run with `execute_remote`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandResult {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The command's exit code as the server reported it
+    pub exit_code: i32,
+}
+
+/// Runs `cmd` on the server like `run_command`, but collects its output instead of printing
+/// it. The output is kept byte for byte, escape sequences included; report lines requested
+/// in `options` (`stats`, `show_invocation`...) are dropped. A failing command is not an
+/// error: its code is in the result.
+pub async fn execute_remote(cmd: &str, options: &RequestOptions) -> Result<CommandResult> {
+    // Both are needed to tell stdout from stderr and to get the exit code
+    let options = RequestOptions { framed: true, exit_code: true, ..options.clone() };
+    let request_line = format!("{}{}", COMMAND_PREFIX, expand_alias(cmd));
+    let mut sink = OutputSink::Buffer { stdout: Vec::new(), stderr: Vec::new() };
+    let end = exchange(&request_line, &options, &mut OutputChecks::new(None, None), &mut VtFilter::new(false), false, &mut sink).await?;
+    let Some(exit_code) = end.exit_code else {
+        anyhow::bail!("Connection closed before the server reported the command's exit code");
+    };
+    let OutputSink::Buffer { stdout, stderr } = sink else {
+        unreachable!("the sink was created as a buffer");
+    };
+    Ok(CommandResult { stdout, stderr, exit_code })
+}
+
+/// Where `exchange` puts the command's output and the report lines.
+enum OutputSink {
+    /// This process's stdout and stderr, written as the output arrives; a stream with a file
+    /// in `redirects` (stdout, stderr) goes there instead. A session also keeps the output
+    /// in a scrollback
+    Console {
+        stdout: tokio::io::Stdout,
+        stderr: tokio::io::Stderr,
+        redirects: Box<[Option<Redirect>; 2]>,
+        scrollback: Option<Box<Scrollback>>,
+    },
+    /// Memory; report lines are dropped
+    Buffer { stdout: Vec<u8>, stderr: Vec<u8> },
+}
+
+impl OutputSink {
+    async fn write(&mut self, stream: OutputStream, data: &[u8]) -> std::io::Result<()> {
+        if let OutputSink::Console { redirects, scrollback, .. } = self {
+            if let Some(scrollback) = scrollback {
+                scrollback.record(stream, data);
+            }
+            let index = match stream {
+                OutputStream::Stdout => 0,
+                OutputStream::Stderr => 1,
+            };
+            if let Some(redirect) = &mut redirects[index] {
+                redirect.file.write_all(data).await?;
+                redirect.written += data.len() as u64;
+                // tokio finishes file writes in the background; this waits for them
+                return redirect.file.flush().await;
+            }
+        }
+        match (self, stream) {
+            (OutputSink::Console { stdout, .. }, OutputStream::Stdout) => {
+                stdout.write_all(data).await?;
+                stdout.flush().await
+            }
+            (OutputSink::Console { stderr, .. }, OutputStream::Stderr) => {
+                stderr.write_all(data).await?;
+                stderr.flush().await
+            }
+            (OutputSink::Buffer { stdout, .. }, OutputStream::Stdout) => {
+                stdout.extend_from_slice(data);
+                Ok(())
+            }
+            (OutputSink::Buffer { stderr, .. }, OutputStream::Stderr) => {
+                stderr.extend_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    fn report(&self, message: std::fmt::Arguments) {
+        if let OutputSink::Console { .. } = self {
+            eprintln!("{}", redact_secrets(&message.to_string()));
+        }
+    }
+
+    /// Answers a session's `/search <regex>` on the report channel, one line per match
+    /// with the number of the command that printed it.
+    fn search_scrollback(&self, pattern: &str) {
+        let OutputSink::Console { scrollback: Some(scrollback), .. } = self else {
+            return;
+        };
+        if pattern.is_empty() {
+            self.report(format_args!("[search] Usage: {} <regex>", SEARCH_PREFIX));
+            return;
+        }
+        let pattern = match Regex::new(pattern) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                self.report(format_args!("[search] Invalid regex: {}", e));
+                return;
+            }
+        };
+        let mut found = 0;
+        for (command, line) in scrollback.search(&pattern) {
+            self.report(format_args!("[search] #{}: {}", command, line));
+            found += 1;
+        }
+        self.report(format_args!("[search] {} matching line(s) in the last {} line(s) of output", found, scrollback.lines.len()));
+    }
+}
+
+/// How an `exchange` ended.
+struct ExchangeEnd {
+    /// From the `__EXIT__` line, if one came
+    exit_code: Option<i32>,
+    /// Why the command ended abnormally, from the same line
+    reason: Option<String>,
+    /// Whether the server sent frames; one too old for `OPT framed` sends raw output
+    framed: bool,
+    /// From sending the request to the end of the response
+    duration: Duration,
+    /// How long the command ran on the server, from the `__EXIT__` line (protocol 2)
+    run_time: Option<Duration>,
+    /// From the `__RESUME__` line of a resumable command
+    resume_token: Option<String>,
+    /// Bytes of whole frames received after the `__RESUME__` line, where a resume starts
+    resume_offset: u64,
+}
+
+impl ExchangeEnd {
+    fn new() -> Self {
+        Self {
+            exit_code: None,
+            reason: None,
+            framed: true,
+            duration: Duration::ZERO,
+            run_time: None,
+            resume_token: None,
+            resume_offset: 0,
+        }
+    }
+}
+
+/// Sends `request_line` with the options ahead of it and hands everything the server sends
+/// back to `sink`, until the connection closes.
+async fn exchange(
+    request_line: &str,
+    options: &RequestOptions,
+    checks: &mut OutputChecks,
+    vt_filter: &mut VtFilter,
+    hexdump: bool,
+    sink: &mut OutputSink,
+) -> Result<ExchangeEnd> {
+    let mut socket = HexdumpStream::new(connect_to_server(hexdump).await?, hexdump);
+
+    // Send options and command
+    let request = format!("{}{}\n", options.encode(), request_line);
+    socket.write_all(request.as_bytes()).await?;
+    let sent_at = Instant::now();
+
+    let (socket, socket_writer) = tokio::io::split(socket);
+    if options.stdin {
+        spawn_stdin_forwarder(socket_writer);
+    }
+
+    let buf_size = buf_size_from_env();
+    let mut socket = tokio::io::BufReader::with_capacity(buf_size, socket);
+    let mut end = ExchangeEnd::new();
+    let mut result = read_response(&mut socket, options, checks, vt_filter, sink, &mut end, false).await;
+    // A resumable command outlives a dropped connection: reconnect and carry on where the
+    // output stopped, until its exit line arrives
+    while end.exit_code.is_none() {
+        let Some(token) = end.resume_token.clone() else {
+            break;
+        };
+        match &result {
+            Ok(()) => sink.report(format_args!("[resume] Connection closed before the command ended; reconnecting...")),
+            Err(e) => sink.report(format_args!("[resume] Connection lost ({:#}); reconnecting...", e)),
+        }
+        let mut socket = tokio::io::BufReader::with_capacity(buf_size, resume_connection(&token, end.resume_offset, options, hexdump).await?);
+        result = read_response(&mut socket, options, checks, vt_filter, sink, &mut end, false).await;
+    }
+    result?;
+    end.duration = sent_at.elapsed();
+    Ok(end)
+}
+
+/// Attempts made to reconnect for a resume before giving up.
+const RESUME_ATTEMPTS: u32 = 10;
+/// Wait between reconnection attempts.
+const RESUME_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Reconnects (without bootstrapping: the command runs on the server that went away) and
+/// asks for the output of the resumable command `token` from byte `offset` on.
+async fn resume_connection(token: &str, offset: u64, options: &RequestOptions, hexdump: bool) -> Result<HexdumpStream<Connection>> {
+    let connector = ServerConnector::from_env(hexdump)?;
+    for attempt in 1..=RESUME_ATTEMPTS {
+        tokio::time::sleep(RESUME_RETRY_DELAY).await;
+        if let Probe::Alive(socket, _) = connector.probe().await? {
+            let mut socket = HexdumpStream::new(socket, hexdump);
+            let header = RequestOptions { auth_token: options.auth_token.clone(), framed: true, exit_code: true, ..Default::default() };
+            let request = format!("{}{}{} {}\n", header.encode(), RESUME_PREFIX, token, offset);
+            socket.write_all(request.as_bytes()).await?;
+            eprintln!("[resume] Reconnected, resuming the output at byte {}", offset);
+            return Ok(socket);
+        }
+        eprintln!("[resume] Server not reachable on {} (attempt {}/{})", connector.addr, attempt, RESUME_ATTEMPTS);
+    }
+    anyhow::bail!("Couldn't reconnect to {} to resume the command's output", connector.addr)
+}
+
+/// Reads the server's answer to one request and hands it to `sink`. Everything after the
+/// request arrives in frames (`OPT framed`): output goes to the sink's stdout or stderr,
+/// report lines to its report channel. Stops when the connection closes or, with
+/// `until_exit` (sessions), after the `__EXIT__` line. What it learns goes into `end`, which
+/// a resumed response carries on with.
+async fn read_response<R: AsyncRead + Unpin>(
+    socket: &mut tokio::io::BufReader<R>,
+    options: &RequestOptions,
+    checks: &mut OutputChecks,
+    vt_filter: &mut VtFilter,
+    sink: &mut OutputSink,
+    end: &mut ExchangeEnd,
+    until_exit: bool,
+) -> Result<()> {
+    let mut first_frame = true;
+    loop {
+        let mut header = [0; 5];
+        match socket.read_exact(&mut header[..1]).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        // A server that predates framing sends the output as it is: all of it is stdout
+        if first_frame && !until_exit && !matches!(header[0], FRAME_CONTROL | FRAME_STDOUT | FRAME_STDERR) {
+            end.framed = false;
+            let mut data = header[..1].to_vec();
+            socket.read_to_end(&mut data).await?;
+            let output = vt_filter.filter(OutputStream::Stdout, &data);
+            checks.feed(&output);
+            sink.write(OutputStream::Stdout, &output).await?;
+            break;
+        }
+        first_frame = false;
+        match socket.read_exact(&mut header[1..]).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > MAX_FRAME_LEN {
+            anyhow::bail!("Server sent a {} byte frame, more than the {} allowed", len, MAX_FRAME_LEN);
+        }
+        let mut payload = vec![0; len];
+        socket.read_exact(&mut payload).await?;
+
+        // Every whole frame after the `__RESUME__` line counts toward the resume offset
+        let counted = end.resume_token.is_some();
+        match header[0] {
+            FRAME_STDOUT | FRAME_STDERR => {
+                let stream = if header[0] == FRAME_STDOUT { OutputStream::Stdout } else { OutputStream::Stderr };
+                let output = vt_filter.filter(stream, &payload);
+                checks.feed(&output);
+                sink.write(stream, &output).await?;
+            }
+            FRAME_CONTROL => {
+                let line = String::from_utf8_lossy(&payload);
+                let line = line.trim_end();
+                if let Some(token) = line.strip_prefix(RESUME_TOKEN_PREFIX) {
+                    end.resume_token = Some(token.to_string());
+                } else if let Some(path) = line.strip_prefix(SCRATCH_PREFIX) {
+                    sink.report(format_args!("[scratch] {}", path));
+                } else if let Some(invocation) = line.strip_prefix(INVOCATION_PREFIX) {
+                    sink.report(format_args!("[invocation] {}", invocation));
+                } else if let Some(cwd) = line.strip_prefix(CWD_PREFIX) {
+                    sink.report(format_args!("[cwd] {}", cwd));
+                } else if let Some(limit) = line.strip_prefix(TRUNCATED_PREFIX) {
+                    sink.report(format_args!("[truncated] Output cut at the {} byte limit and the command stopped", limit));
+                } else if let Some(stats) = line.strip_prefix(STATS_PREFIX) {
+                    sink.report(format_args!("[stats] {}{}", format_stats(stats), vt_filter.report()));
+                } else if let Some(exit) = line.strip_prefix(EXIT_PREFIX) {
+                    let mut fields = exit.split_whitespace();
+                    end.exit_code = fields.next().and_then(|code| code.parse::<i32>().ok());
+                    let mut reason = "";
+                    for field in fields {
+                        match field.strip_prefix(RUN_MS_FIELD) {
+                            Some(ms) => end.run_time = ms.parse::<u64>().ok().map(Duration::from_millis),
+                            None => reason = field,
+                        }
+                    }
+                    end.reason = (!reason.is_empty()).then(|| reason.to_string());
+                    if reason == UNAUTHORIZED_REASON {
+                        anyhow::bail!(
+                            "Authentication rejected by the server: check that WINBOAT_AUTH_TOKEN is set to the same token on the client and the server"
+                        );
+                    }
+                    match reason {
+                        // Already reported by the `__TRUNCATED__` line
+                        "" | "OutputLimit" => {}
+                        "TimedOut" => sink.report(format_args!(
+                            "[timeout] The command ran longer than {}s and was killed",
+                            options.timeout.unwrap_or(0)
+                        )),
+                        reason => sink.report(format_args!("[exit] The command ended abnormally: {}", reason)),
+                    }
+                    if let Some(run_time) = end.run_time.filter(|_| options.stats) {
+                        sink.report(format_args!("[time] The command ran for {:.3}s on the server", run_time.as_secs_f64()));
+                    }
+                    if until_exit {
+                        break;
+                    }
+                }
+            }
+            other => anyhow::bail!("Server sent a frame of unknown type {}", other),
+        }
+        if counted {
+            end.resume_offset += (header.len() + len) as u64;
+        }
+    }
+    Ok(())
+}
+
+/// Sends local stdin to the server in stdin frames, and an empty frame once it ends.
+///
+/// Reading stdin blocks and can't be cancelled, so it runs on a plain thread rather than
+/// the runtime's blocking pool, whose shutdown would otherwise wait for more input.
+fn spawn_stdin_forwarder<W: AsyncWrite + Unpin + Send + 'static>(mut socket_writer: W) {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(4);
+    std::thread::spawn(move || {
+        use std::io::Read;
+        let mut stdin = std::io::stdin().lock();
+        let mut buf = [0; 8192];
+        loop {
+            let n = stdin.read(&mut buf).unwrap_or(0);
+            if tx.blocking_send(buf[..n].to_vec()).is_err() || n == 0 {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(data) = rx.recv().await {
+            let frame = encode_frame(FRAME_STDIN, &data);
+            if socket_writer.write_all(&frame).await.is_err() || data.is_empty() {
+                break;
+            }
+        }
+    });
+}
+
+/// Largest frame the client accepts; output frames are far smaller, this only stops a
+/// corrupt length from allocating without bound.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Maps a remote exit code to one this process can exit with. Unix keeps only the low
+/// 8 bits, so codes outside 0-255 (e.g. an NTSTATUS from a crash) become 1 rather than
+/// wrapping, possibly to 0.
+fn local_exit_code(code: i32) -> i32 {
+    if cfg!(unix) && !(0..=255).contains(&code) {
+        1
+    } else {
+        code
+    }
+}
+
+/// Remote exit codes replaced before the client leaves with them (`--map-exit 3010=0,1=2`),
+/// for tools whose codes don't follow the usual 0-is-success rule.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExitMap(Vec<(i32, i32)>);
+
+impl ExitMap {
+    /// The code `code` maps to. When a code is listed more than once the first mapping wins,
+    /// and a mapped code isn't mapped again (`1=2,2=3` turns 1 into 2, not 3).
+    pub fn apply(&self, code: i32) -> i32 {
+        self.0.iter().find(|(from, _)| *from == code).map_or(code, |(_, to)| *to)
+    }
+}
+
+impl std::str::FromStr for ExitMap {
+    type Err = String;
+
+    fn from_str(mappings: &str) -> Result<Self, Self::Err> {
+        mappings
+            .split(',')
+            .map(|mapping| {
+                let (from, to) = mapping.split_once('=').ok_or_else(|| format!("'{}' is not FROM=TO", mapping.trim()))?;
+                let parse = |code: &str| code.trim().parse::<i32>().map_err(|_| format!("'{}' is not an exit code", code.trim()));
+                Ok((parse(from)?, parse(to)?))
+            })
+            .collect::<Result<_, String>>()
+            .map(ExitMap)
+    }
+}
+
+/// What the client does with the remote command's exit code before leaving with it.
+#[derive(Debug, Clone, Default)]
+pub struct ExitHandling {
+    pub map: ExitMap,
+    /// Codes the command is expected to exit with (`--expect-exit 0,3010`), compared after
+    /// `map`. When set, the client exits 0 for one of them and `EXPECT_EXIT_MISMATCH` for any
+    /// other. Empty: the client exits with the command's code.
+    pub expect: Vec<i32>,
+}
+
+/// Exit code of a command that didn't exit with one of the `--expect-exit` codes.
+pub const EXPECT_EXIT_MISMATCH: i32 = 1;
+
+impl ExitHandling {
+    /// The code to leave with for the remote `code`: mapped, then checked against the
+    /// expected codes, or else made fit for this process (see `local_exit_code`). A mismatch
+    /// is reported on stderr.
+    fn exit_code(&self, code: i32) -> i32 {
+        let code = self.map.apply(code);
+        if self.expect.is_empty() {
+            local_exit_code(code)
+        } else if self.expect.contains(&code) {
+            0
+        } else {
+            let expected: Vec<String> = self.expect.iter().map(i32::to_string).collect();
+            eprintln!("[expect-exit] The command exited with {}, expected {}", code, expected.join(" or "));
+            EXPECT_EXIT_MISMATCH
+        }
+    }
+}
+
+/// Socket wrapper that dumps every chunk sent and received to stderr (`--hexdump`), for
+/// debugging other implementations of the protocol. Does nothing when disabled.
+struct HexdumpStream<S> {
+    inner: S,
+    enabled: bool,
+    sent: usize,
+    received: usize,
+}
+
+impl<S> HexdumpStream<S> {
+    fn new(inner: S, enabled: bool) -> Self {
+        // Both counted from after the handshake, which is dumped separately
+        Self { inner, enabled, sent: 0, received: 0 }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HexdumpStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let data = &buf.filled()[before..];
+        if self.enabled && !data.is_empty() {
+            print_hexdump("<< received", self.received, data);
+            self.received += data.len();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HexdumpStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if self.enabled && n > 0 {
+                print_hexdump(">> sent", self.sent, &buf[..n]);
+                self.sent += n;
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Longest READY line the client reads, `READY <version>\n`; anything longer isn't the server.
+const READY_MAX_LEN: usize = 32;
+
+/// The protocol to speak with a server that sent `line`, or `None` if it isn't a READY line.
+fn parse_ready_line(line: &[u8]) -> Option<Protocol> {
+    let rest = line.strip_prefix(b"READY")?.strip_suffix(b"\n")?;
+    if rest.is_empty() {
+        return Some(Protocol::LEGACY);
+    }
+    let version = std::str::from_utf8(rest.strip_prefix(b" ")?).ok()?.parse::<u32>().ok()?;
+    Some(Protocol::negotiate(version))
+}
+
+/// Warns when the server speaks an older protocol than this client, typically a bootstrapped
+/// .exe that wasn't updated along with the client.
+fn warn_if_outdated(protocol: Protocol) {
+    if protocol == Protocol::LEGACY {
+        eprintln!(
+            "[WARNING] The server predates protocol versioning (this client speaks {}): options it doesn't know are ignored instead of refused. Update the server.",
+            PROTOCOL_VERSION
+        );
+    } else if protocol < Protocol::CURRENT {
+        eprintln!("[WARNING] The server speaks protocol {}, this client {}. Update the server.", protocol.0, PROTOCOL_VERSION);
+    }
+}
+
+/// Prints `data` in `xxd` layout; `offset` is the position of its first byte in the stream.
+fn print_hexdump(direction: &str, offset: usize, data: &[u8]) {
+    let mut dump = format!("[hexdump] {} {} bytes\n", direction, data.len());
+    for (row_index, row) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = row
+            .chunks(2)
+            .map(|pair| pair.iter().map(|b| format!("{:02x}", b)).collect())
+            .collect();
+        let ascii: String = row
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        dump.push_str(&format!("{:08x}: {:<39}  {}\n", offset + row_index * 16, hex.join(" "), ascii));
+    }
+    eprint!("{}", dump);
+}
+
+/// Turns `stdout=N stderr=M` into a readable summary.
+fn format_stats(stats: &str) -> String {
+    let count = |name: &str| {
+        stats
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))
+            .and_then(|n| n.parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+    let (stdout, stderr) = (count("stdout"), count("stderr"));
+    format!("{} bytes (stdout {}, stderr {})", stdout + stderr, stdout, stderr)
+}
+
+/// Runs an output generator on the server and reports how fast its output arrives.
+///
+/// This goes through the same request and streaming path as `run_command`, but discards
+/// the output and records timings instead of writing to stdout.
+pub async fn run_bench(size_mb: u64, command: Option<&str>, options: &RequestOptions) -> Result<()> {
+    let expected = size_mb * 1024 * 1024;
+    let command = match command {
+        Some(command) => command.to_string(),
+        None => format!(
+            "powershell -NoProfile -Command \"$c='x'*1048576; for($i=0;$i -lt {};$i++){{[Console]::Out.Write($c)}}\"",
+            size_mb
+        ),
+    };
+
+    let mut socket = connect_to_server(false).await?;
+    println!("Benchmark command: {}", command);
+
+    let start = Instant::now();
+    let request = format!("{}{}{}\n", options.encode(), COMMAND_PREFIX, command);
+    socket.write_all(request.as_bytes()).await?;
+
+    let mut buf = vec![0; buf_size_from_env()];
+    let mut total: u64 = 0;
+    let mut first_byte = None;
+    let mut last_chunk = None;
+    let mut gaps = Vec::new();
+    loop {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let now = Instant::now();
+        match last_chunk {
+            Some(last) => gaps.push(now.duration_since(last)),
+            None => first_byte = Some(now.duration_since(start)),
+        }
+        last_chunk = Some(now);
+        total += n as u64;
+    }
+    let elapsed = start.elapsed();
+
+    let chunks = gaps.len() as u64 + u64::from(first_byte.is_some());
+    let mib = total as f64 / (1024.0 * 1024.0);
+    println!("-------------------------------------");
+    println!("Bytes received:     {} ({:.2} MiB)", total, mib);
+    println!("Total time:         {:.3}s", elapsed.as_secs_f64());
+    match first_byte {
+        Some(ttfb) => println!("Time to first byte: {:.1}ms", ttfb.as_secs_f64() * 1000.0),
+        None => println!("Time to first byte: n/a (no output)"),
+    }
+    println!("Throughput:         {:.2} MiB/s", mib / elapsed.as_secs_f64());
+    println!("Chunks:             {} (avg {} bytes)", chunks, total.checked_div(chunks).unwrap_or(0));
+    if !gaps.is_empty() {
+        gaps.sort();
+        let percentile = |p: usize| gaps[(gaps.len() - 1) * p / 100].as_secs_f64() * 1_000_000.0;
+        println!(
+            "Chunk gap (us):     min {:.0} / p50 {:.0} / p99 {:.0} / max {:.0}",
+            percentile(0),
+            percentile(50),
+            percentile(99),
+            percentile(100)
+        );
+    }
+    if total != expected {
+        println!(
+            "[WARNING] Expected {} bytes but received {}; the command may have failed or been refused.",
+            expected, total
+        );
+    }
+
+    Ok(())
+}
+
+/// Connects to the server and waits for its READY handshake, bootstrapping it when the
+/// port is closed or answers without a handshake.
+async fn connect_to_server(hexdump: bool) -> Result<Connection> {
+    let connector = ServerConnector::from_env(hexdump)?;
+
+    // Progress goes to stderr: stdout carries the command's output, which may be binary
+    eprintln!("Connecting to {}...", connector.addr);
+    match connector.probe().await? {
+        Probe::Alive(socket, protocol) => {
+            eprintln!("Connected and verified.");
+            warn_if_outdated(protocol);
+            return Ok(socket);
+        }
+        // The bootstrap starts a server on the Windows side, never one on a local socket
+        Probe::Unreachable | Probe::ZombiePort(_) if connector.socket_path.is_some() => {
+            anyhow::bail!("No server answering on {} (WINBOAT_SOCKET); start one with the same WINBOAT_SOCKET", connector.addr);
+        }
+        Probe::Unreachable | Probe::ZombiePort(_) if !bootstrap_enabled() => {
+            anyhow::bail!(
+                "Server not reachable on {} and bootstrap is disabled (--no-bootstrap or WINBOAT_BOOTSTRAP=off); start the server on the Windows side",
+                connector.addr
+            );
+        }
+        Probe::Unreachable => eprintln!("Connection failed or timed out. Bootstrapping..."),
+        Probe::ZombiePort(sign) => eprintln!("Connected but {} (likely Docker zombie port). Bootstrapping...", sign),
+    }
+    bootstrap_server().await?;
+
+    // Poll until the server is up instead of sleeping a fixed time: a fast start is picked
+    // up right away, a slow one gets the whole budget
+    let max_attempts = match env::var("WINBOAT_MAX_ATTEMPTS") {
+        Ok(value) => value
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|n| *n > 0)
+            .with_context(|| format!("WINBOAT_MAX_ATTEMPTS must be a positive number, got '{}'", value))?,
+        Err(_) => DEFAULT_MAX_ATTEMPTS,
+    };
+    let budget = env_duration_secs("WINBOAT_BOOTSTRAP_POSTWAIT", 30, true)?;
+    let started = Instant::now();
+    let deadline = started + budget;
+    let mut delay = PROBE_INITIAL_DELAY;
+    let mut attempts = 0;
+    for attempt in 1..=max_attempts {
+        attempts = attempt;
+        tokio::time::sleep(delay.min(deadline.saturating_duration_since(Instant::now()))).await;
+        eprintln!("Waiting for the server on {} (attempt {}/{})...", connector.addr, attempt, max_attempts);
+        if let Probe::Alive(socket, protocol) = connector.probe().await? {
+            eprintln!("Connected and verified.");
+            warn_if_outdated(protocol);
+            return Ok(socket);
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        delay = (delay * 2).min(PROBE_MAX_DELAY);
+    }
+    Err(anyhow::anyhow!(
+        "The server didn't come up on {} after bootstrap ({} attempt(s) in {:.1}s). Check the server log on the Windows side.",
+        connector.addr,
+        attempts,
+        started.elapsed().as_secs_f64()
+    ))
+}
+
+/// Probes made after a bootstrap when WINBOAT_MAX_ATTEMPTS isn't set.
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+/// Wait before the first probe after a bootstrap; doubled after each failed one.
+const PROBE_INITIAL_DELAY: Duration = Duration::from_millis(250);
+/// Longest wait between two probes.
+const PROBE_MAX_DELAY: Duration = Duration::from_secs(2);
+/// Time allowed for the TCP connect of a probe.
+const PROBE_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time allowed for the READY line once connected, TLS handshake included.
+const PROBE_READY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Outcome of one attempt to reach the server.
+enum Probe {
+    /// The server sent READY, and got the client's `PROTO` line if it announced a version;
+    /// the connection is ready for a request
+    Alive(Connection, Protocol),
+    /// Something accepted the connection but isn't the server (e.g. a Docker port proxy
+    /// with nothing behind it); the message says what gave it away
+    ZombiePort(&'static str),
+    /// Nothing accepted the connection
+    Unreachable,
+}
+
+/// What is listening on the server's address, as found by `is_server_alive`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerStatus {
+    /// The server answered with its READY handshake
+    Alive,
+    /// The port accepts connections but no server answers on it: it closed the connection,
+    /// sent something else or stayed silent. Typical of a Docker port mapping whose
+    /// Windows side isn't running
+    ZombiePort,
+    /// Nothing accepted the connection
+    Unreachable,
+}
+
+/// Connects to `addr` and reads the handshake with a short timeout, to tell a running
+/// server from a zombie port or a closed one. Never bootstraps. TLS is used when the
+/// client settings (WINBOAT_TLS) ask for it. `unix:<path>` names a Unix socket.
+pub async fn is_server_alive(addr: &str) -> Result<ServerStatus> {
+    let connector = ServerConnector {
+        addr: addr.to_string(),
+        socket_path: addr.strip_prefix("unix:").map(PathBuf::from),
+        ..ServerConnector::from_env(false)?
+    };
+    Ok(match connector.probe().await? {
+        Probe::Alive(..) => ServerStatus::Alive,
+        Probe::ZombiePort(_) => ServerStatus::ZombiePort,
+        Probe::Unreachable => ServerStatus::Unreachable,
+    })
+}
+
+/// Where and how the client connects.
+struct ServerConnector {
+    addr: String,
+    /// Set when the server listens on a Unix socket (WINBOAT_SOCKET) rather than `addr`'s port
+    socket_path: Option<PathBuf>,
+    hexdump: bool,
+    #[cfg(feature = "tls")]
+    tls: Option<tls::ClientTls>,
+}
+
+impl ServerConnector {
+    fn from_env(hexdump: bool) -> Result<Self> {
+        #[cfg(not(feature = "tls"))]
+        if env::var("WINBOAT_TLS").is_ok_and(|value| value == "1") {
+            anyhow::bail!("WINBOAT_TLS=1, but this client was built without TLS support (feature \"tls\")");
+        }
+        // Port mapped on host: 47330 -> Container: 5330
+        let client_port = env::var("WINBOAT_CLIENT_PORT")
+            .unwrap_or_else(|_| "47330".to_string());
+        let socket_path = socket_path_from_env()?;
+        Ok(Self {
+            addr: match &socket_path {
+                Some(path) => format!("unix:{}", path.display()),
+                None => format!("127.0.0.1:{}", client_port),
+            },
+            socket_path,
+            hexdump,
+            #[cfg(feature = "tls")]
+            tls: tls::ClientTls::from_env()?,
+        })
+    }
+
+    /// Connects and waits for READY. Fails only when trying again can't help.
+    async fn probe(&self) -> Result<Probe> {
+        #[cfg(unix)]
+        if let Some(path) = &self.socket_path {
+            return match tokio::time::timeout(PROBE_CONNECT_TIMEOUT, tokio::net::UnixStream::connect(path)).await {
+                Ok(Ok(s)) => self.read_ready(async { Ok(Connection::Unix(s)) }).await,
+                _ => Ok(Probe::Unreachable),
+            };
+        }
+        let s = match tokio::time::timeout(PROBE_CONNECT_TIMEOUT, TcpStream::connect(self.addr.as_str())).await {
+            Ok(Ok(s)) => s,
+            _ => return Ok(Probe::Unreachable),
+        };
+        if let Err(e) = KeepaliveConfig::from_env().apply(&s) {
+            eprintln!("[WARNING] Failed to enable TCP keepalive: {}", e);
+        }
+
+        // TLS first, when enabled
+        self.read_ready(async {
+            #[cfg(feature = "tls")]
+            let s = match &self.tls {
+                Some(tls) => Connection::Tls(Box::new(tls.connect(s).await?)),
+                None => Connection::Plain(s),
+            };
+            #[cfg(not(feature = "tls"))]
+            let s = Connection::Plain(s);
+            Ok(s)
+        })
+        .await
+    }
+
+    /// Sets up the connection with `connect`, waits for READY on it and answers with the
+    /// protocol version to speak.
+    async fn read_ready(&self, connect: impl std::future::Future<Output = std::io::Result<Connection>>) -> Result<Probe> {
+        let handshake_result = tokio::time::timeout(PROBE_READY_TIMEOUT, async {
+            let mut s = connect.await?;
+            // Byte by byte, so nothing after the line is consumed
+            let mut line = Vec::new();
+            while line.len() < READY_MAX_LEN && line.last() != Some(&b'\n') {
+                line.push(s.read_u8().await?);
+            }
+            if self.hexdump {
+                print_hexdump("<< received", 0, &line);
+            }
+            let protocol = parse_ready_line(&line);
+            // Only a server that announced a version expects the answer: an older one would
+            // run it as a command
+            if let Some(protocol) = protocol.filter(|protocol| *protocol > Protocol::LEGACY) {
+                let reply = format!("{}{}\n", PROTO_PREFIX, protocol.0);
+                s.write_all(reply.as_bytes()).await?;
+                if self.hexdump {
+                    print_hexdump(">> sent", 0, reply.as_bytes());
+                }
+            }
+            Ok::<_, std::io::Error>((s, protocol))
+        })
+        .await;
+
+        match handshake_result {
+            Ok(Ok((s, Some(protocol)))) => Ok(Probe::Alive(s, protocol)),
+            Ok(Ok((_, None))) => Ok(Probe::ZombiePort("it answered something other than READY")),
+            // The server answered, but not in TLS: bootstrapping another one wouldn't help
+            #[cfg(feature = "tls")]
+            Ok(Err(e)) if self.tls.is_some() && e.kind() == ErrorKind::InvalidData => {
+                anyhow::bail!("TLS handshake with the server failed: {}", e);
+            }
+            // A Docker proxy with no backend accepts, then closes as soon as it fails to
+            // reach it: no need to wait for the timeout
+            Ok(Err(e)) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset) => {
+                Ok(Probe::ZombiePort("the connection was closed without READY"))
+            }
+            Ok(Err(_)) => Ok(Probe::ZombiePort("the handshake failed")),
+            Err(_) => Ok(Probe::ZombiePort("no READY signal")),
+        }
+    }
+}
+
+/// The server's whole answer to `CTRL PING`.
+const PONG_REPLY: &str = "PONG\n";
+/// Time allowed for the PONG once the request is sent.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A successful `ping`.
+pub struct Pong {
+    /// Address the server answered on
+    pub addr: String,
+    /// From sending the request to receiving the PONG, handshake excluded
+    pub round_trip: Duration,
+}
+
+/// Checks that the server is up and accepts this client (WINBOAT_AUTH_TOKEN included), without
+/// running a command. Never bootstraps: an unreachable server is an error.
+pub async fn ping() -> Result<Pong> {
+    let connector = ServerConnector::from_env(false)?;
+    let mut socket = match connector.probe().await? {
+        Probe::Alive(socket, _) => socket,
+        Probe::Unreachable => anyhow::bail!("No server reachable on {}", connector.addr),
+        Probe::ZombiePort(sign) => anyhow::bail!("Something accepted the connection on {}, but {} (likely Docker zombie port)", connector.addr, sign),
+    };
+
+    let options = RequestOptions { auth_token: client_auth_token(), ..Default::default() };
+    let start = Instant::now();
+    socket.write_all(format!("{}{}PING\n", options.encode(), CONTROL_PREFIX).as_bytes()).await?;
+    // The server closes the connection after its answer; only the start of anything else
+    // (e.g. an error from an older server) is read
+    let mut reply = Vec::new();
+    let mut socket = socket.take(256);
+    match tokio::time::timeout(PING_TIMEOUT, socket.read_to_end(&mut reply)).await {
+        Ok(result) => result.context("Connection lost while waiting for PONG")?,
+        Err(_) => anyhow::bail!("No answer to PING from {} within {}s", connector.addr, PING_TIMEOUT.as_secs()),
+    };
+    let round_trip = start.elapsed();
+    if reply != PONG_REPLY.as_bytes() {
+        let reply = String::from_utf8_lossy(&reply);
+        if reply.trim() == "UNAUTHORIZED" {
+            anyhow::bail!("The server on {} refused the ping: UNAUTHORIZED (check WINBOAT_AUTH_TOKEN)", connector.addr);
+        }
+        anyhow::bail!("The server on {} answered PING with '{}' instead of PONG", connector.addr, reply.trim());
+    }
+    Ok(Pong { addr: connector.addr, round_trip })
+}
+
+/// Reads a number of seconds from `var`, using `default` when it is unset.
+fn env_duration_secs(var: &str, default: u64, allow_zero: bool) -> Result<Duration> {
+    let secs = match env::var(var) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|secs| allow_zero || *secs > 0)
+            .with_context(|| format!("{} must be a {} number of seconds, got '{}'", var, if allow_zero { "non-negative" } else { "positive" }, value))?,
+        Err(_) => default,
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+/// Set in the environment of a server started by `bootstrap_server` (and so inherited by the
+/// commands it runs), marking any client started from there as part of a bootstrap chain.
+const BOOTSTRAPPED_ENV: &str = "WINBOAT_BOOTSTRAPPED";
+
+/// How the bootstrap reaches Windows to launch the server, chosen with WINBOAT_BOOTSTRAP.
+#[derive(Clone, Copy)]
+enum BootstrapTransport {
+    /// evil-winrm with WINBOAT_HOST, WINBOAT_PORT, WINBOAT_USER and WINBOAT_PASS (default)
+    WinRm,
+    /// OpenSSH with WINBOAT_HOST, WINBOAT_SSH_PORT and WINBOAT_USER; key authentication only
+    Ssh,
+}
+
+/// WINBOAT_BOOTSTRAP value (also set by `--no-bootstrap`) that keeps the client from ever
+/// starting the server itself.
+const BOOTSTRAP_OFF: &str = "off";
+
+/// Whether the client may start the server when it finds none.
+fn bootstrap_enabled() -> bool {
+    !env::var("WINBOAT_BOOTSTRAP").is_ok_and(|value| value.trim().eq_ignore_ascii_case(BOOTSTRAP_OFF))
+}
+
+impl BootstrapTransport {
+    fn from_env() -> Result<Self> {
+        let value = env::var("WINBOAT_BOOTSTRAP").unwrap_or_default();
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "winrm" => Ok(Self::WinRm),
+            "ssh" => Ok(Self::Ssh),
+            BOOTSTRAP_OFF => Err(anyhow::anyhow!("Bootstrapping is disabled (--no-bootstrap or WINBOAT_BOOTSTRAP=off)")),
+            other => Err(anyhow::anyhow!("Invalid WINBOAT_BOOTSTRAP '{}': expected winrm, ssh or off", other)),
+        }
+    }
+
+    fn program(self) -> &'static str {
+        match self {
+            Self::WinRm => "evil-winrm",
+            Self::Ssh => "ssh",
+        }
+    }
+
+    /// Turns a failed spawn into an error saying what to do, which for a missing program
+    /// is installing it (or switching transport), not looking into the connection.
+    fn spawn_error(self, e: std::io::Error) -> anyhow::Error {
+        if e.kind() != ErrorKind::NotFound {
+            return anyhow::Error::new(e).context(format!("Failed to spawn {}", self.program()));
+        }
+        let hint = match self {
+            Self::WinRm => "Install it with `gem install evil-winrm` (it needs Ruby), or set WINBOAT_BOOTSTRAP=ssh to bootstrap over OpenSSH instead",
+            Self::Ssh => "Install the OpenSSH client (e.g. `sudo apt install openssh-client`), or unset WINBOAT_BOOTSTRAP to bootstrap with evil-winrm instead",
+        };
+        anyhow::anyhow!(
+            "Can't bootstrap the server: WINBOAT_BOOTSTRAP={} needs `{}`, which isn't installed or isn't on PATH. {}.",
+            match self {
+                Self::WinRm => "winrm",
+                Self::Ssh => "ssh",
+            },
+            self.program(),
+            hint
+        )
+    }
+
+    /// Arguments of the transport program that run `script` (from `launch_script`) in
+    /// PowerShell on the Windows side, and the text to feed its stdin, if any. With
+    /// `redact`, the password is replaced by asterisks, for showing the invocation.
+    fn invocation(self, script: &str, redact: bool) -> (Vec<String>, Option<String>) {
+        let host = env::var("WINBOAT_HOST")
+            .unwrap_or_else(|_| "127.0.0.1".to_string());
+        let user = env::var("WINBOAT_USER")
+            .unwrap_or_else(|_| "gianca".to_string());
+        match self {
+            Self::WinRm => {
+                let port = env::var("WINBOAT_PORT")
+                    .unwrap_or_else(|_| "47320".to_string());
+                let pass = if redact {
+                    REDACTED.to_string()
+                } else {
+                    env::var("WINBOAT_PASS").unwrap_or_else(|_| "gianca".to_string())
+                };
+
+                // We pipe the command to evil-winrm stdin, similar to how the shell script did it.
+                // This avoids complex escaping issues with passing the command as an argument to evil-winrm directly.
+                // Wrap the command in powershell execution. The line is typed into evil-winrm's
+                // own PowerShell session, so `$` is escaped to reach the inner one intact;
+                // `exit` then ends the session
+                let full_command = format!("powershell -Command \"{}\"\nexit\n", script.replace('$', "`$"));
+                let args = vec!["-i".to_string(), host, "-P".to_string(), port, "-u".to_string(), user, "-p".to_string(), pass];
+                (args, Some(full_command))
+            }
+            Self::Ssh => {
+                let port = env::var("WINBOAT_SSH_PORT")
+                    .unwrap_or_else(|_| "22".to_string());
+                // Encoded, the script gets through whichever shell sshd starts (cmd or
+                // PowerShell) without any quoting
+                let remote = format!("powershell -NoProfile -NonInteractive -EncodedCommand {}", encode_powershell_command(script));
+                let args = vec!["-p".to_string(), port, "-o".to_string(), "BatchMode=yes".to_string(), format!("{}@{}", user, host), remote];
+                (args, None)
+            }
+        }
+    }
+
+    /// Starts the transport running `script` (from `launch_script`) in PowerShell on the
+    /// Windows side, with stdout and stderr piped.
+    async fn spawn(self, script: &str) -> Result<tokio::process::Child> {
+        let (args, input) = self.invocation(script, false);
+        let mut child = Command::new(self.program())
+            .args(&args)
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| self.spawn_error(e))?;
+
+        if let Some(input) = input {
+            let mut stdin = child.stdin.take().with_context(|| format!("Failed to open {} stdin", self.program()))?;
+            stdin.write_all(input.as_bytes()).await?;
+            drop(stdin); // Close stdin to signal we're done sending the command
+        }
+        Ok(child)
+    }
+}
+
+/// Shown instead of a secret wherever one would be printed.
+const REDACTED: &str = "****";
+
+/// Settings whose values must never be printed: users often redirect the output of both
+/// sides to shared log files.
+const SECRET_VARS: [&str; 3] = ["WINBOAT_PASS", "WINBOAT_AUTH_TOKEN", "WINBOAT_ADMIN_TOKEN"];
+
+/// Shortest secret that is masked; shorter values would garble ordinary text.
+const MIN_REDACTED_LEN: usize = 4;
+
+/// `text` with the value of every secret setting (`SECRET_VARS`) replaced by `REDACTED`.
+fn redact_secrets(text: &str) -> String {
+    let mut text = text.to_string();
+    for var in SECRET_VARS {
+        if let Ok(secret) = env::var(var) {
+            let secret = secret.trim();
+            if secret.chars().count() >= MIN_REDACTED_LEN {
+                text = text.replace(secret, REDACTED);
+            }
+        }
+    }
+    text
+}
+
+/// PowerShell that starts the server detached, whatever transport delivers it.
+/// Start-Process with -WindowStyle Hidden hides the window, and output goes to files for
+/// debugging since it can't be seen in detached mode. The server inherits the marker
+/// variable, so it can't bootstrap another one.
+fn launch_script(exe_path: &str, bind_addr: IpAddr, log_path: &str, err_path: &str) -> String {
+    format!(
+        "$env:{}='1'; Start-Process -FilePath '{}' -ArgumentList '--server','--bind','{}' -WindowStyle Hidden -RedirectStandardOutput '{}' -RedirectStandardError '{}'",
+        BOOTSTRAPPED_ENV, exe_path, bind_addr, log_path, err_path
+    )
+}
+
+/// `script` in the form `powershell -EncodedCommand` takes: base64 of its UTF-16LE text.
+fn encode_powershell_command(script: &str) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let bytes: Vec<u8> = script.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (u32::from(*b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(n >> (18 - 6 * i)) as usize & 63]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// The transport the bootstrap uses and the PowerShell it runs, from the settings.
+fn bootstrap_plan() -> Result<(BootstrapTransport, String)> {
+    let transport = BootstrapTransport::from_env()?;
+    let exe_path = env::var("WINBOAT_EXE_PATH")
+        .context("WINBOAT_EXE_PATH must be set in the .env file")?;
+
+    let log_path = env::var("WINBOAT_LOG_PATH")
+        .unwrap_or_else(|_| r"C:\Users\gianca\server.log".to_string());
+    
+    let err_path = env::var("WINBOAT_ERR_PATH")
+        .unwrap_or_else(|_| r"C:\Users\gianca\server.err".to_string());
+    
+    // Forwarded connections reach the VM from its gateway, not from loopback, so the server
+    // listens on every interface unless WINBOAT_BIND_ADDR narrows it
+    let bind_addr = env_bind_addr()?.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    Ok((transport, launch_script(&exe_path, bind_addr, &log_path, &err_path)))
+}
+
+async fn bootstrap_server() -> Result<()> {
+    // Recursion guards: never start a server from inside a bootstrapped one, or next to a
+    // server that is already running on this machine
+    if env::var_os(BOOTSTRAPPED_ENV).is_some() {
+        eprintln!("[guard] Bootstrap refused: this process descends from a bootstrapped server ({} is set).", BOOTSTRAPPED_ENV);
+        return Err(anyhow::anyhow!(
+            "Refusing to bootstrap from inside a bootstrapped server. Check that WINBOAT_EXE_PATH points at the server and that WINBOAT_CLIENT_PORT is right."
+        ));
+    }
+    if let Some((pid, port)) = InstanceMarker::find_live() {
+        eprintln!("[guard] Bootstrap refused: a server (pid {}) is already running on this machine, port {}.", pid, port);
+        return Err(anyhow::anyhow!(
+            "A local winboat-bridge server (pid {}) is listening on port {}; not starting another one. Point WINBOAT_CLIENT_PORT at it instead.",
+            pid,
+            port
+        ));
+    }
+
+    let (transport, script) = bootstrap_plan()?;
+
+    // Time allowed for the transport to run the launch command; waiting for the server to
+    // come up is up to the caller
+    let bootstrap_timeout = env_duration_secs("WINBOAT_BOOTSTRAP_TIMEOUT", 15, false)?;
+    eprintln!("Bootstrap budget: {}s for {}", bootstrap_timeout.as_secs(), transport.program());
+
+    eprintln!("Bootstrapping server via {}...", transport.program());
+    eprintln!("PowerShell Command: {}", redact_secrets(&script));
+    let mut child = transport.spawn(&script).await?;
+
+    // Consume stdout and stderr concurrently to prevent deadlocks
+    let mut stdout = child.stdout.take().context("Failed to open stdout")?;
+    let mut stderr = child.stderr.take().context("Failed to open stderr")?;
+
+    let stdout_handle = tokio::spawn(async move {
+        let mut data = Vec::new();
+        let _ = stdout.read_to_end(&mut data).await;
+        data
+    });
+
+    let stderr_handle = tokio::spawn(async move {
+        let mut data = Vec::new();
+        let _ = stderr.read_to_end(&mut data).await;
+        data
+    });
+
+    // Wait for the transport to exit, with a timeout
+    eprintln!("Waiting for bootstrap command to complete...");
+    let wait_result = tokio::time::timeout(bootstrap_timeout, child.wait()).await;
+
+    match wait_result {
+        Ok(Ok(status)) => {
+            // Wait for I/O to finish
+            let _ = stdout_handle.await; 
+            let stderr_data = stderr_handle.await.unwrap_or_default();
+            
+             if !status.success() {
+                let stderr_str = String::from_utf8_lossy(&stderr_data);
+                eprintln!("Bootstrap returned non-zero. Stderr: {}", redact_secrets(&stderr_str));
+            } else {
+                eprintln!("Bootstrap command executed successfully.");
+            }
+        },
+        Ok(Err(e)) => return Err(anyhow::anyhow!("Failed to wait for {}: {}", transport.program(), e)),
+        Err(_) => {
+            eprintln!("Bootstrap command timed out ({} hang). Killing local process and assuming remote started.", transport.program());
+            let _ = child.kill().await;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_allowlist(entries: &[&str]) -> ServerConfig {
+        ServerConfig {
+            allowlist: Some(entries.iter().map(|entry| entry.to_string()).collect()),
+            ..ServerConfig::from_env()
+        }
+    }
+
+    #[test]
+    fn allowlist_prefix_allows_plain_arguments() {
+        let config = config_with_allowlist(&["ipconfig*", "git status"]);
+        for shell in [Shell::Cmd, Shell::Powershell, Shell::Sh] {
+            assert!(config.is_allowed("ipconfig", shell));
+            assert!(config.is_allowed("IPCONFIG /all", shell));
+            assert!(config.is_allowed("git status", shell));
+            assert!(!config.is_allowed("git status --short", shell));
+            assert!(!config.is_allowed("whoami", shell));
+        }
+    }
+
+    #[test]
+    fn allowlist_prefix_refuses_chained_and_expanded_commands() {
+        let config = config_with_allowlist(&["ipconfig*"]);
+        for command in ["ipconfig & del x", "ipconfig && del x", "ipconfig | del x", "ipconfig > x", "ipconfig < x", "ipconfig\ndel x", "ipconfig ^& del x", "ipconfig %COMSPEC%"] {
+            assert!(!config.is_allowed(command, Shell::Cmd), "cmd allowed {:?}", command);
+        }
+        for command in ["ipconfig; rm x", "ipconfig $(rm x)", "ipconfig `rm x`", "ipconfig || rm x", "ipconfig\nrm x"] {
+            assert!(!config.is_allowed(command, Shell::Sh), "sh allowed {:?}", command);
+            assert!(!config.is_allowed(command, Shell::Bash), "bash allowed {:?}", command);
+        }
+        for command in ["ipconfig; Remove-Item x", "ipconfig $(Remove-Item x)", "ipconfig (Remove-Item x)", "ipconfig & Remove-Item x"] {
+            assert!(!config.is_allowed(command, Shell::Powershell), "powershell allowed {:?}", command);
+            assert!(!config.is_allowed(command, Shell::Pwsh), "pwsh allowed {:?}", command);
+        }
+    }
+
+    #[test]
+    fn allowlist_exact_entries_and_wildcard_are_taken_as_written() {
+        let config = config_with_allowlist(&["dir | sort"]);
+        assert!(config.is_allowed("dir | sort", Shell::Cmd));
+        assert!(!config.is_allowed("dir | sort & del x", Shell::Cmd));
+
+        let config = config_with_allowlist(&["*"]);
+        assert!(config.is_allowed("ipconfig & del x", Shell::Cmd));
+        assert!(config.allows_env("PATH"));
+    }
+
+    #[test]
+    fn allowlist_refuses_path_overrides() {
+        let config = config_with_allowlist(&["ipconfig*"]);
+        assert!(!config.allows_env("PATH"));
+        assert!(!config.allows_env("Path"));
+        assert!(!config.allows_env("pathext"));
+        assert!(config.allows_env("LANG"));
+
+        let config = ServerConfig { allowlist: None, ..config };
+        assert!(config.allows_env("PATH"));
+    }
+
+    #[test]
+    fn allowlist_file_skips_comments_and_denies_all_when_unreadable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("allowlist.txt");
+        std::fs::write(&path, "# read-only commands\n\n  ipconfig*  \r\ngit status\n").unwrap();
+        assert_eq!(load_allowlist(path.to_str().unwrap()), ["ipconfig*", "git status"]);
+
+        let missing = load_allowlist(dir.path().join("missing.txt").to_str().unwrap());
+        assert!(missing.is_empty());
+        let config = ServerConfig { allowlist: Some(missing), ..ServerConfig::from_env() };
+        assert!(!config.is_allowed("ipconfig", Shell::Cmd));
+    }
+
+    #[test]
+    fn vt_filter_keeps_carriage_return_redraws() {
+        // A colored progress bar, with a sequence split between two reads
+        let chunks = [&b"\x1b[32m 10%\x1b[0m\r\x1b[3"[..], b"2m 50%\x1b[0m\r100%\n"];
+        let mut filter = VtFilter::new(true);
+        let stripped: Vec<u8> = chunks.iter().flat_map(|chunk| filter.filter(OutputStream::Stdout, chunk)).collect();
+        assert_eq!(stripped, b" 10%\r 50%\r100%\n");
+
+        let mut filter = VtFilter::new(false);
+        let kept: Vec<u8> = chunks.iter().flat_map(|chunk| filter.filter(OutputStream::Stdout, chunk)).collect();
+        assert_eq!(kept, chunks.concat());
+    }
+
+    #[tokio::test]
+    async fn line_buffering_sends_each_redraw_whole() {
+        let redraws = [&b"[##        ] 20%\r"[..], b"[#####     ] 50%\r", b"[##########] 100%\n"];
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let progress = tokio::spawn(async move {
+            for redraw in redraws {
+                // Written in two parts, as a tool flushing in the middle of a redraw does
+                let (head, tail) = redraw.split_at(5);
+                writer.write_all(head).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                writer.write_all(tail).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        forward_lines(&mut tokio::io::BufReader::new(reader), OutputStream::Stdout, &tx).await;
+        progress.await.unwrap();
+        drop(tx);
+
+        let mut chunks = Vec::new();
+        while let Some((_, chunk)) = rx.recv().await {
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks, redraws);
+    }
+
+    #[test]
+    fn exit_map_replaces_listed_codes_only() {
+        let map: ExitMap = "3010=0, 1=2".parse().unwrap();
+        assert_eq!(map.apply(3010), 0);
+        assert_eq!(map.apply(1), 2);
+        assert_eq!(map.apply(0), 0);
+        assert_eq!(map.apply(5), 5);
+
+        // The first mapping for a code wins, and a mapped code isn't mapped again
+        let map: ExitMap = "1=2,2=3,1=4,-1=0".parse().unwrap();
+        assert_eq!(map.apply(1), 2);
+        assert_eq!(map.apply(2), 3);
+        assert_eq!(map.apply(-1), 0);
+
+        // Mapped before being made fit for this process, so 3010 isn't cut to 1 first
+        let exit = ExitHandling { map: "3010=0".parse().unwrap(), ..Default::default() };
+        assert_eq!(exit.exit_code(3010), 0);
+    }
+
+    #[test]
+    fn expect_exit_passes_only_the_expected_codes() {
+        let exit = ExitHandling { expect: vec![0, 3010], ..Default::default() };
+        assert_eq!(exit.exit_code(0), 0);
+        assert_eq!(exit.exit_code(3010), 0);
+        assert_eq!(exit.exit_code(1), EXPECT_EXIT_MISMATCH);
+
+        // Expecting a failure turns it into success, and success into a mismatch
+        let exit = ExitHandling { expect: vec![2], ..Default::default() };
+        assert_eq!(exit.exit_code(2), 0);
+        assert_eq!(exit.exit_code(0), EXPECT_EXIT_MISMATCH);
+
+        // Compared after the mapping
+        let exit = ExitHandling { map: "5=2".parse().unwrap(), expect: vec![2] };
+        assert_eq!(exit.exit_code(5), 0);
+        assert_eq!(exit.exit_code(2), 0);
+    }
+
+    #[test]
+    fn exit_map_refuses_malformed_mappings() {
+        for mappings in ["", "3010", "3010=", "=0", "3010=0,", "a=0", "1=2=3", "3010:0"] {
+            assert!(mappings.parse::<ExitMap>().is_err(), "accepted {:?}", mappings);
+        }
+    }
+
+    #[test]
+    fn scrollback_keeps_whole_lines_per_command() {
+        let mut scrollback = Scrollback::default();
+        // A line split between two chunks, with stderr in between
+        scrollback.record(OutputStream::Stdout, b"Build sta");
+        scrollback.record(OutputStream::Stderr, b"warning: unused\r\n");
+        scrollback.record(OutputStream::Stdout, b"rted\nerror: no");
+        scrollback.end_command();
+        scrollback.record(OutputStream::Stdout, b"ok\n");
+        scrollback.end_command();
+
+        let lines: Vec<_> = scrollback.lines.iter().map(|(command, line)| (*command, line.as_str())).collect();
+        assert_eq!(lines, [(1, "warning: unused"), (1, "Build started"), (1, "error: no"), (2, "ok")]);
+        let pattern = Regex::new("^(warning|error)").unwrap();
+        let found: Vec<_> = scrollback.search(&pattern).map(|(_, line)| line.as_str()).collect();
+        assert_eq!(found, ["warning: unused", "error: no"]);
+    }
+
+    #[test]
+    fn scrollback_is_bounded() {
+        let mut scrollback = Scrollback::default();
+        for i in 0..SCROLLBACK_LINES + 10 {
+            scrollback.record(OutputStream::Stdout, format!("line {}\n", i).as_bytes());
+        }
+        assert_eq!(scrollback.lines.len(), SCROLLBACK_LINES);
+        assert_eq!(scrollback.lines.front().unwrap().1, "line 10");
+
+        // A huge line keeps its start, even when it arrives in pieces
+        scrollback.record(OutputStream::Stdout, &[b'x'; SCROLLBACK_LINE_LEN]);
+        scrollback.record(OutputStream::Stdout, b"yyy\n");
+        assert_eq!(scrollback.lines.back().unwrap().1, "x".repeat(SCROLLBACK_LINE_LEN));
+    }
+
+    #[tokio::test]
+    async fn request_split_by_a_pause_is_read_whole() {
+        // A versioned client: the pause, longer than the idle timeout, doesn't end the request
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let sender = tokio::spawn(async move {
+            client.write_all(b"PROTO 2\nOPT exit-code\nCMD echo hel").await.unwrap();
+            tokio::time::sleep(REQUEST_IDLE_TIMEOUT * 2).await;
+            client.write_all(b"lo\n\x03\0\0\0\0").await.unwrap();
+            client
+        });
+        let (request, extra) = read_request(&mut server, false).await.unwrap();
+        assert_eq!(request, b"PROTO 2\nOPT exit-code\nCMD echo hello\n");
+        assert_eq!(extra, b"\x03\0\0\0\0");
+        assert_eq!(request_end(&request), Some(request.len()));
+        assert!(!request_unterminated(&request, false));
+        drop(sender.await.unwrap());
+
+        // A session's later request has no `PROTO` line but is waited for all the same
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let sender = tokio::spawn(async move {
+            client.write_all(b"CMD echo hel").await.unwrap();
+            tokio::time::sleep(REQUEST_IDLE_TIMEOUT * 2).await;
+            client.write_all(b"lo\n").await.unwrap();
+            client
+        });
+        assert_eq!(read_request(&mut server, true).await.unwrap().0, b"CMD echo hello\n");
+        drop(sender.await.unwrap());
+
+        // A client from before versioning sends the command bare; the pause completes it
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"echo hello").await.unwrap();
+        let (request, _) = read_request(&mut server, false).await.unwrap();
+        assert_eq!(request, b"echo hello");
+        assert!(!request_unterminated(&request, false));
+        drop(client);
+
+        // A versioned client that leaves partway: the part must not run
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"PROTO 2\nOPT exit-code\nCMD echo hel").await.unwrap();
+        drop(client);
+        let (request, _) = read_request(&mut server, false).await.unwrap();
+        assert!(request_unterminated(&request, false));
+        assert!(request_unterminated(b"CMD echo hel", true));
+        // Unlike one that only answered the handshake (a probe)
+        assert!(!request_unterminated(b"PROTO 2\n", false));
+    }
+
+    fn netstat_listener(line: &str, port: u16) -> Option<(String, u32)> {
+        parse_netstat_listener(line, port).map(|listener| (listener.local_addr, listener.pid))
+    }
+
+    #[test]
+    fn netstat_parser_reads_the_columns_not_the_last_token() {
+        let listening = "  TCP    0.0.0.0:5330           0.0.0.0:0              LISTENING       4242";
+        assert_eq!(netstat_listener(listening, 5330), Some(("0.0.0.0:5330".to_string(), 4242)));
+
+        // With -b, the owning executable comes on a line of its own, and some sockets get a note
+        // instead; neither is a listener
+        for annotation in [" [winboat-bridge.exe]", "  RpcSs", " Can not obtain ownership information", ""] {
+            assert_eq!(netstat_listener(annotation, 5330), None, "{:?}", annotation);
+        }
+
+        // A connection whose remote end is on the port, in whatever state
+        for state in ["ESTABLISHED", "TIME_WAIT", "LISTENING"] {
+            let line = format!("  TCP    127.0.0.1:50123        127.0.0.1:5330         {}     4242", state);
+            assert_eq!(netstat_listener(&line, 5330), None, "{}", line);
+        }
+        // Not TCP, or missing the PID column (netstat without -o)
+        assert_eq!(netstat_listener("  UDP    0.0.0.0:5330           *:*                                    4242", 5330), None);
+        assert_eq!(netstat_listener("  TCP    0.0.0.0:5330           0.0.0.0:0              LISTENING", 5330), None);
+    }
+
+    #[test]
+    fn netstat_parser_matches_the_exact_local_port() {
+        let line = |local: &str| format!("  TCP    {:<22} 0.0.0.0:0              LISTENING       4242", local);
+        assert_eq!(netstat_listener(&line("0.0.0.0:533"), 533), Some(("0.0.0.0:533".to_string(), 4242)));
+        // 533 is a prefix and a suffix of others; only the whole port matches
+        for other in ["0.0.0.0:5330", "0.0.0.0:15330", "0.0.0.0:1533", "0.0.0.0:53"] {
+            assert_eq!(netstat_listener(&line(other), 533), None, "{}", other);
+        }
+        assert_eq!(netstat_listener(&line("127.0.0.1:5330"), 533), None);
+
+        // IPv6: the port follows the last colon, after the bracketed address
+        let v6 = "  TCP    [::]:5330              [::]:0                 LISTENING       4242";
+        assert_eq!(netstat_listener(v6, 5330), Some(("[::]:5330".to_string(), 4242)));
+        assert_eq!(netstat_listener(v6, 533), None);
+        let v6 = "  TCP    [::1]:5330             [::]:0                 LISTENING       77";
+        assert_eq!(netstat_listener(v6, 5330), Some(("[::1]:5330".to_string(), 77)));
+        assert_eq!(netstat_listener(v6, 1), None);
+    }
+
+    fn strip_all(filter: &mut VtFilter, stream: OutputStream, chunks: &[&[u8]]) -> Vec<u8> {
+        chunks.iter().flat_map(|chunk| filter.filter(stream, chunk)).collect()
+    }
+
+    #[test]
+    fn vt_filter_removes_csi_and_osc_sequences() {
+        let mut filter = VtFilter::new(true);
+        let csi = b"\x1b[1;31mred\x1b[0m plain \x1b[2K\x1b[?25hdone";
+        assert_eq!(filter.filter(OutputStream::Stdout, csi), b"red plain done");
+        // A window title ended by BEL, then by ST (`ESC \`)
+        assert_eq!(filter.filter(OutputStream::Stdout, b"a\x1b]0;title\x07b"), b"ab");
+        assert_eq!(filter.filter(OutputStream::Stdout, b"a\x1b]0;title\x1b\\b"), b"ab");
+        // Two-byte and charset sequences
+        assert_eq!(filter.filter(OutputStream::Stdout, b"\x1b7x\x1b(By"), b"xy");
+        assert_eq!(filter.report(), ", VT sequences removed");
+    }
+
+    #[test]
+    fn vt_filter_passes_sequences_through_when_not_stripping() {
+        let mut filter = VtFilter::new(false);
+        assert_eq!(filter.report(), "");
+        let data = b"\x1b[1mbold\x1b[0m\x1b]0;t\x07";
+        assert_eq!(filter.filter(OutputStream::Stdout, data), data);
+        assert_eq!(filter.report(), ", with VT sequences");
+    }
+
+    #[test]
+    fn vt_filter_handles_a_sequence_split_across_chunks() {
+        let mut filter = VtFilter::new(true);
+        assert_eq!(strip_all(&mut filter, OutputStream::Stdout, &[b"red\x1b", b"[31", b"mtext"]), b"redtext");
+        assert_eq!(strip_all(&mut filter, OutputStream::Stdout, &[b"a\x1b]0;ti", b"tle\x1b", b"\\b"]), b"ab");
+    }
+
+    #[test]
+    fn vt_filter_keeps_each_streams_state_apart() {
+        let mut filter = VtFilter::new(true);
+        // stdout stops inside a sequence; stderr's text must not be taken as its rest
+        assert_eq!(filter.filter(OutputStream::Stdout, b"out\x1b[3"), b"out");
+        assert_eq!(filter.filter(OutputStream::Stderr, b"1merr"), b"1merr");
+        assert_eq!(filter.filter(OutputStream::Stdout, b"1mmore"), b"more");
+        // And stderr's own open sequence doesn't swallow stdout's text
+        assert_eq!(filter.filter(OutputStream::Stderr, b"\x1b]0;t"), b"");
+        assert_eq!(filter.filter(OutputStream::Stdout, b"still text"), b"still text");
+        assert_eq!(filter.filter(OutputStream::Stderr, b"itle\x07rest"), b"rest");
+    }
+
+    #[test]
+    fn color_mode_strips_only_when_asked_or_redirected() {
+        for stdout_is_terminal in [true, false] {
+            assert!(!ColorMode::Always.strips(stdout_is_terminal));
+            assert!(ColorMode::Never.strips(stdout_is_terminal));
+        }
+        assert!(!ColorMode::Auto.strips(true));
+        assert!(ColorMode::Auto.strips(false));
+    }
+
+    #[test]
+    fn buf_size_is_clamped_and_rejects_non_numbers() {
+        assert_eq!(parse_buf_size("65536"), Some(65536));
+        assert_eq!(parse_buf_size(" 4096\n"), Some(4096));
+        assert_eq!(parse_buf_size("0"), Some(1024));
+        assert_eq!(parse_buf_size("1"), Some(1024));
+        assert_eq!(parse_buf_size("1073741824"), Some(16 * 1024 * 1024));
+        for value in ["", "64k", "-1", "1.5"] {
+            assert_eq!(parse_buf_size(value), None, "{:?}", value);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Structured request log (WINBOAT_REQUEST_LOG).
+//!
+//! Every connection gets a request id, and what happens to it is written to the file as one
+//! JSON object per line, so one client's requests can be picked out of concurrent traffic
+//! with a simple filter instead of grepping the interleaved console output. The file is
+//! rotated by size; `<path>.1` is the most recent backup.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Size at which the log is rotated.
+const MAX_LOG_BYTES: u64 = 10 * 1024 * 1024;
+/// Rotated files kept next to the log; older ones are deleted.
+const LOG_BACKUPS: u32 = 3;
+
+pub struct RequestLog {
+    path: PathBuf,
+    file: Mutex<LogFile>,
+}
+
+struct LogFile {
+    /// `None` after a failed rotation; reopened on the next record
+    file: Option<File>,
+    len: u64,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    /// When the server wrote the record, by its own wall clock, in UTC
+    ts: String,
+    request: u64,
+    event: &'a str,
+    #[serde(flatten)]
+    fields: Value,
+}
+
+impl RequestLog {
+    /// The log at WINBOAT_REQUEST_LOG, or `None` when it isn't set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(path) = std::env::var_os("WINBOAT_REQUEST_LOG").filter(|path| !path.is_empty()) else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(path);
+        let (file, len) = open_append(&path)?;
+        Ok(Some(Self { path, file: Mutex::new(LogFile { file: Some(file), len }) }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends one line for `event` of `request`. `fields` is a JSON object merged into the
+    /// line. Failures are reported on the console and never reach the client.
+    pub fn record(&self, request: u64, event: &str, fields: Value) {
+        let line = Line { ts: utc_timestamp(SystemTime::now()), request, event, fields };
+        let line = match serde_json::to_string(&line) {
+            Ok(line) => line,
+            Err(e) => {
+                log_eprintln!("[WARNING] Failed to encode request log line: {}", e);
+                return;
+            }
+        };
+        let mut line = crate::redact_secrets(&line).into_bytes();
+        line.push(b'\n');
+
+        let mut log = self.file.lock().unwrap();
+        if log.len > 0 && log.len + line.len() as u64 > MAX_LOG_BYTES {
+            if let Err(e) = self.rotate(&mut log) {
+                log_eprintln!("[WARNING] Failed to rotate request log {}: {:#}", self.path.display(), e);
+            }
+        }
+        if log.file.is_none() {
+            match open_append(&self.path) {
+                Ok((file, len)) => *log = LogFile { file: Some(file), len },
+                Err(e) => {
+                    log_eprintln!("[WARNING] {:#}", e);
+                    return;
+                }
+            }
+        }
+        let written = log.file.as_mut().map(|file| file.write_all(&line));
+        match written {
+            Some(Ok(())) => log.len += line.len() as u64,
+            Some(Err(e)) => log_eprintln!("[WARNING] Failed to write request log {}: {}", self.path.display(), e),
+            None => {}
+        }
+    }
+
+    /// Shifts `<path>.1`.. up by one, dropping the oldest, and moves the log to `<path>.1`.
+    fn rotate(&self, log: &mut LogFile) -> Result<()> {
+        // Windows can't rename a file that is still open
+        log.file = None;
+        for n in (1..LOG_BACKUPS).rev() {
+            let from = backup_path(&self.path, n);
+            if from.exists() {
+                std::fs::rename(&from, backup_path(&self.path, n + 1))
+                    .with_context(|| format!("Failed to rename {}", from.display()))?;
+            }
+        }
+        std::fs::rename(&self.path, backup_path(&self.path, 1))
+            .with_context(|| format!("Failed to rename {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<(File, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open request log {}", path.display()))?;
+    let len = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+    Ok((file, len))
+}
+
+fn backup_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// `time` as an RFC 3339 UTC timestamp with milliseconds, e.g. `2024-05-01T12:34:56.789Z`.
+fn utc_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's days_from_civil, inverted)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
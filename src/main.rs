@@ -1,88 +1,58 @@
+use anyhow::Result;
 use clap::{Parser, Subcommand};
-use anyhow::{Context, Result};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::process::Command;
-use std::process::Stdio;
-use std::sync::Arc;
-use tokio::sync::Notify;
-use std::env;
-use std::io::ErrorKind;
-
-#[cfg(target_os = "windows")]
-mod win_job {
-    use winapi::um::jobapi2::{CreateJobObjectW, AssignProcessToJobObject, SetInformationJobObject};
-    use winapi::um::winnt::{JobObjectExtendedLimitInformation, JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE, HANDLE};
-    use std::ptr;
-    use std::mem;
-    use anyhow::Result;
-
-    // Returns the Job Handle. The Job Object is closed when the handle is dropped (if not leaked),
-    // but we want it to persist until we drop it or the process ends.
-    // Actually, if we drop the handle, and LIMIT_KILL_ON_JOB_CLOSE is set, the process dies?
-    // Yes, "If the job has the JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE flag, closing the last handle to the job object terminates all processes associated with the job."
-    // So we need to keep this handle alive as long as the child is alive.
-    pub struct JobHandle(HANDLE);
-    
-    // Send/Sync for Arc? HANDLE is raw pointer basically.
-    unsafe impl Send for JobHandle {}
-    unsafe impl Sync for JobHandle {}
-
-    impl Drop for JobHandle {
-        fn drop(&mut self) {
-            unsafe { winapi::um::handleapi::CloseHandle(self.0); }
-        }
-    }
-
-    pub fn assign_to_new_job(process_handle: std::os::windows::io::RawHandle) -> Result<JobHandle> {
-        unsafe {
-            let job = CreateJobObjectW(ptr::null_mut(), ptr::null());
-            if job.is_null() {
-                 return Err(anyhow::anyhow!("Failed to create job object"));
-            }
-            
-            let handle_wrapper = JobHandle(job);
-
-            let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
-            info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
-
-            let ret = SetInformationJobObject(
-                job,
-                JobObjectExtendedLimitInformation,
-                &mut info as *mut _ as *mut _,
-                mem::size_of_val(&info) as u32,
-            );
-            
-            if ret == 0 {
-                 return Err(anyhow::anyhow!("Failed to set job info"));
-            }
-
-            let ret = AssignProcessToJobObject(job, process_handle as HANDLE);
-             if ret == 0 {
-                 return Err(anyhow::anyhow!("Failed to assign process to job"));
-            }
-            
-            Ok(handle_wrapper)
-        }
-    }
-}
+use regex::Regex;
+use std::io::IsTerminal;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use winboat_bridge::{
+    client_admin_token, client_auth_token, load_settings, local_username, ping, run_bench, dry_run, run_command, run_command_json, run_control, run_session, run_server, ColorMode, ExitHandling, ExitMap, OutputChecks, OutputFiles, Shell,
+    RequestOptions, Role, ServerOptions, VtFilter, VERSION,
+};
 
 #[derive(Parser)]
 #[command(name = "winboat-bridge")]
+#[command(version = VERSION)]
 #[command(about = "Bridge to execute commands on WinBoat container via TCP")]
 #[command(long_about = "WinBoat Bridge - Remote Command Executor for Windows Containers\n\n\
     This tool allows you to execute commands on a Windows container from Linux.\n\
     It operates in two modes: Server (runs on Windows) and Client (runs on Linux).\n\n\
-    Configuration via Environment Variables:\n\
+    Configuration via Environment Variables (or winboat.toml, see --config; variables win):\n\
       WINBOAT_EXE_PATH      - Path to winboat-bridge.exe on Windows\n\
-      WINBOAT_HOST          - WinRM host (default: 127.0.0.1)\n\
+      WINBOAT_BOOTSTRAP     - How the bootstrap launches the server: winrm (evil-winrm) or ssh (default: winrm)\n\
+      WINBOAT_HOST          - WinRM/SSH host (default: 127.0.0.1)\n\
       WINBOAT_PORT          - WinRM port (default: 47320)\n\
-      WINBOAT_USER          - WinRM username\n\
+      WINBOAT_SSH_PORT      - SSH port (default: 22)\n\
+      WINBOAT_USER          - WinRM/SSH username\n\
       WINBOAT_PASS          - WinRM password\n\
       WINBOAT_LOG_PATH      - Server log output path (default: C:\\\\Users\\\\gianca\\\\server.log)\n\
       WINBOAT_ERR_PATH      - Server error output path (default: C:\\\\Users\\\\gianca\\\\server.err)\n\
       WINBOAT_SERVER_PORT   - Server listening port (default: 5330)\n\
-      WINBOAT_CLIENT_PORT   - Client connection port (default: 47330)")]
+      WINBOAT_BIND_ADDR     - Server listening address (default: 127.0.0.1; bootstrap: 0.0.0.0)\n\
+      WINBOAT_CLIENT_PORT   - Client connection port (default: 47330)\n\
+      WINBOAT_ALIAS_<NAME>  - Client-side alias: `-c name args` runs this command followed by args\n\
+      WINBOAT_DANGEROUS_PATTERNS - Comma-separated patterns the server refuses without --confirm-destructive\n\
+      WINBOAT_MAX_RATE_PER_CONN  - Maximum output rate per connection in bytes/s (default: unlimited)\n\
+      WINBOAT_BUF_SIZE           - Bytes read at a time from command output and sockets, both sides (default: 65536)\n\
+      WINBOAT_ALLOWED_CWDS       - Comma-separated directory roots commands may run in (default: anywhere)\n\
+      WINBOAT_POST_HOOK          - Shell command the server runs after each command (gets WINBOAT_EXIT_CODE etc.)\n\
+      WINBOAT_MAX_CONCURRENT_PER_IDENTITY - Commands one identity (--as, else peer IP) may run at once (default: unlimited)\n\
+      WINBOAT_MAX_CONCURRENT     - Commands the server runs at once, 0 = unlimited (default: 8)\n\
+      WINBOAT_BUSY_WAIT          - Seconds a request over that limit waits for a slot before BUSY, 0 = refuse at once (default: 10)\n\
+      WINBOAT_BOOTSTRAP_TIMEOUT  - Seconds to wait for evil-winrm/ssh during bootstrap (default: 15)\n\
+      WINBOAT_BOOTSTRAP_POSTWAIT - Longest wait, in seconds, for the server to come up after bootstrap (default: 30)\n\
+      WINBOAT_MAX_ATTEMPTS       - Connection attempts after bootstrap, with growing pauses (default: 10)\n\
+      WINBOAT_KEEPALIVE_SECS     - Server: seconds without output before it sends a heartbeat frame, 0 = never (default: 30)\n\
+      WINBOAT_KEEPALIVE_IDLE     - Seconds of silence before TCP keepalive probes start (default: 60)\n\
+      WINBOAT_KEEPALIVE_INTERVAL - Seconds between TCP keepalive probes (default: 10)\n\
+      WINBOAT_KEEPALIVE_COUNT    - Unanswered probes before the peer is considered dead (default: 5, fixed at 10 on Windows)\n\
+      WINBOAT_TLS_CERT / _KEY    - Server: PEM certificate chain and private key; enables TLS (`tls` feature)\n\
+      WINBOAT_TLS                - Client: set to 1 to connect with TLS (`tls` feature)\n\
+      WINBOAT_TLS_CA             - Client: PEM file of the certificate(s) to trust\n\
+      WINBOAT_TLS_SERVER_NAME    - Client: name the server certificate must be valid for (default: localhost)\n\
+      WINBOAT_AUTH_TOKEN         - Shared secret: the server refuses requests without it, the client sends it (default: unset, open server)\n\
+      WINBOAT_ADMIN_TOKEN        - Operator secret for ctrl shutdown/reload/kill/kill-all: the server requires it, `ctrl` sends it (default: unset)\n\
+      WINBOAT_ALLOWLIST          - File of commands the server may run, one per line, `prefix*` allowed (default: unset, no restriction)\n\
+      WINBOAT_REQUEST_LOG        - Server: JSON-lines request log, rotated at 10 MB with 3 backups (default: unset, console only)")]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
@@ -91,9 +61,178 @@ struct Cli {
     #[arg(long, help = "Run in server mode - listens for incoming command requests")]
     server: bool,
 
+    /// Serve a single connection, then exit (Server mode)
+    #[arg(long, global = true, help = "Server mode: handle exactly one connection, then shut down")]
+    once: bool,
+
+    /// TOML settings file (both modes)
+    #[arg(long, global = true, help = "Read settings from this TOML file (default: winboat.toml next to the .env file, if any)", value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on (Server mode)
+    #[arg(long, global = true, help = "Server mode: IP address to listen on, e.g. 0.0.0.0 for all interfaces (default: WINBOAT_BIND_ADDR, else 127.0.0.1)", value_name = "IP")]
+    bind: Option<IpAddr>,
+
+    /// Check at startup that Job Object cleanup works (Server mode, Windows)
+    #[arg(long, global = true, help = "Server mode: at startup, verify that closing a Job Object kills its processes and warn if not")]
+    verify_job: bool,
+
+    /// Free a taken port whatever holds it (Server mode, Windows)
+    #[arg(long, global = true, help = "Server mode: when the port is taken, kill the process listening on it even if it isn't winboat-bridge (default: only an older winboat-bridge is killed)")]
+    force_kill: bool,
+
     /// Command to execute on remote server (Client mode)
-    #[arg(short, long, help = "Execute a command on the remote Windows server", value_name = "COMMAND")]
+    #[arg(short, long, value_parser = parse_command, help = "Execute a command on the remote Windows server", value_name = "COMMAND")]
     cmd: Option<String>,
+
+    /// Allow commands that match the server's dangerous patterns (Client mode)
+    #[arg(long, help = "Confirm execution of commands matching WINBOAT_DANGEROUS_PATTERNS on the server")]
+    confirm_destructive: bool,
+
+    /// Print the exact program and arguments the server spawned (Client mode)
+    #[arg(long, help = "Show the exact program and argument vector the server used to run the command")]
+    show_invocation: bool,
+
+    /// Console window title for the remote command (Client mode, cmd only)
+    #[arg(long, help = "Set the console window title of the remote command (Windows cmd only)", value_name = "TITLE")]
+    title: Option<String>,
+
+    /// Also copy the output into the named pipe \\.\pipe\winboat-<NAME> on the server (Client mode, Windows only)
+    #[arg(long, help = "Also write the command output to the named pipe \\\\.\\pipe\\winboat-<NAME> on the Windows server", value_name = "NAME")]
+    output_pipe: Option<String>,
+
+    /// Name recorded by the server for this request (Client mode, defaults to the local user)
+    #[arg(long = "as", help = "Identity the server records for this request (defaults to the local username; not authenticated)", value_name = "NAME")]
+    identity: Option<String>,
+
+    /// Working directory of the remote command (Client mode)
+    #[arg(long, conflicts_with = "scratch_dir", help = "Run the remote command in this directory on the server (it must exist; with --show-invocation the effective directory is reported)", value_name = "DIR")]
+    cwd: Option<String>,
+
+    /// Run the command in a fresh temporary directory on the server (Client mode)
+    #[arg(long, help = "Run the command in a fresh, uniquely named temp directory that is removed afterwards")]
+    scratch_dir: bool,
+
+    /// Leave the scratch directory in place after the command ends (Client mode)
+    #[arg(long, requires = "scratch_dir", help = "Keep the --scratch-dir directory instead of removing it")]
+    keep_scratch: bool,
+
+    /// Fail if any output line matches this regex, whatever the exit code (Client mode)
+    #[arg(long, help = "Exit non-zero if any line of the remote output matches this regex", value_name = "REGEX")]
+    fail_if_match: Option<Regex>,
+
+    /// Fail unless some output line matches this regex (Client mode)
+    #[arg(long, help = "Exit non-zero unless some line of the remote output matches this regex", value_name = "REGEX")]
+    fail_unless_match: Option<Regex>,
+
+    /// Print how many bytes the command wrote to stdout and stderr, and how long it ran (Client mode)
+    #[arg(long, help = "Print the number of bytes the command produced on stdout and stderr, and how long it ran on the server, after it ends")]
+    stats: bool,
+
+    /// Also put the command's stdout on the server's clipboard (Client mode, Windows only)
+    #[arg(long, help = "Also copy the command's stdout (up to 16 MiB) to the Windows server's clipboard")]
+    to_clipboard: bool,
+
+    /// Cap the command's CPU usage to this percentage of the machine (Client mode, Windows only)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), help = "Hard-cap the remote command's CPU usage to N percent (1-100, Windows servers only)", value_name = "N")]
+    cpu_percent: Option<u8>,
+
+    /// Fail instead of starting the server when it isn't reachable (Client mode)
+    #[arg(long, help = "Never start the server through evil-winrm or ssh: if it isn't reachable, fail right away (same as WINBOAT_BOOTSTRAP=off)")]
+    no_bootstrap: bool,
+
+    /// Dump every byte sent and received to stderr in hex/ASCII (Client mode)
+    #[arg(long, help = "Print all bytes exchanged with the server to stderr as an xxd-style hex dump")]
+    hexdump: bool,
+
+    /// Discard the command's stderr on the server (Client mode)
+    #[arg(long, conflicts_with = "stderr_only", help = "Discard the remote command's stderr instead of streaming it")]
+    no_stderr: bool,
+
+    /// Stream only the command's stderr, discarding stdout on the server (Client mode)
+    #[arg(long, help = "Stream only the remote command's stderr; its stdout is discarded")]
+    stderr_only: bool,
+
+    /// Forward this process's stdin to the remote command (Client mode)
+    #[arg(short = 'i', long, help = "Forward local stdin to the remote command, e.g. for piped input or interactive tools")]
+    stdin: bool,
+
+    /// Set an environment variable for the remote command; repeatable (Client mode)
+    #[arg(short = 'e', long = "env", value_parser = parse_env_assignment, help = "Set KEY=VALUE in the remote command's environment (repeatable; combine with --clean-env to start from a minimal one)", value_name = "KEY=VALUE")]
+    env: Vec<String>,
+
+    /// Start the command with only the essential environment variables (Client mode)
+    #[arg(long, help = "Run the remote command with a minimal environment instead of the server's (PATH, SystemRoot and a few other essentials are kept)")]
+    clean_env: bool,
+
+    /// Kill the command if it runs longer than this many seconds; 0 means no limit (Client mode)
+    #[arg(long, default_value_t = 0, help = "Kill the remote command (and everything it started) after this many seconds; 0 = no timeout", value_name = "SECONDS")]
+    timeout: u64,
+
+    /// Shell the server runs the command with (Client mode)
+    #[arg(long, value_enum, help = "Run the command with this shell on the server instead of its default (cmd on Windows, sh elsewhere); powershell and pwsh take the command as a PowerShell script", value_name = "SHELL")]
+    shell: Option<Shell>,
+
+    /// Stop the command after this much output (Client mode)
+    #[arg(long, help = "Kill the remote command once it has produced this many bytes of output (stdout and stderr together); the output is cut at the limit", value_name = "BYTES")]
+    max_output: Option<u64>,
+
+    /// Whether terminal escape sequences in the output are kept (Client mode)
+    #[arg(long, value_enum, default_value_t = ColorMode::Always, help = "Keep colors and other VT escape sequences in the output: always, never, or auto (only when stdout is a terminal)", value_name = "WHEN")]
+    color: ColorMode,
+
+    /// Print one JSON object with the output, exit code and timing instead of streaming (Client mode)
+    #[arg(long, help = "Collect the output and print a single JSON object (command, stdout, stderr, exit_code, reason, duration_ms, run_ms, streams_separated) once the command ends")]
+    json: bool,
+
+    /// Write the command's stdout to this file instead of the terminal (Client mode)
+    #[arg(long, conflicts_with_all = ["json", "session"], help = "Write the remote command's stdout to this file instead of the terminal (created, or truncated unless --append is given); the bytes written are reported at the end", value_name = "PATH")]
+    stdout_file: Option<PathBuf>,
+
+    /// Write the command's stderr to this file instead of the terminal (Client mode)
+    #[arg(long, conflicts_with_all = ["json", "session"], help = "Write the remote command's stderr to this file instead of the terminal (created, or truncated unless --append is given); the bytes written are reported at the end", value_name = "PATH")]
+    stderr_file: Option<PathBuf>,
+
+    /// Append to the --stdout-file/--stderr-file files instead of truncating them (Client mode)
+    #[arg(long, help = "Append to the --stdout-file and --stderr-file files instead of truncating them")]
+    append: bool,
+
+    /// Replace remote exit codes before exiting with them (Client mode)
+    #[arg(long, help = "Exit with a different code than the remote command's, e.g. \"3010=0,1=2\" treats 3010 (reboot required) as success; when a code is listed twice the first mapping wins, and mapped codes aren't mapped again", value_name = "FROM=TO,...")]
+    map_exit: Option<ExitMap>,
+
+    /// Exit 0 if the command exits with one of these codes, non-zero otherwise (Client mode)
+    #[arg(long, value_delimiter = ',', allow_negative_numbers = true, help = "Exit 0 if the remote command exits with one of these codes (after --map-exit), and 1 with a message on stderr otherwise, e.g. --expect-exit 0,3010 or --expect-exit 2 to check that it fails", value_name = "CODE,...")]
+    expect_exit: Vec<i32>,
+
+    /// Coalesce the first N output chunks into one socket write (Client mode)
+    #[arg(long, help = "Send the first N output chunks of the command as one write, then stream normally (max 256)", value_name = "N")]
+    initial_batch: Option<u16>,
+
+    /// Run commands read from stdin over one connection (Client mode)
+    #[arg(long, visible_alias = "interactive", conflicts_with_all = ["cmd", "stdin", "json", "dry_run"], help = "Read commands from stdin, one per line, and run them one after another over a single connection")]
+    session: bool,
+
+    /// With --session, stop at the first failed command (Client mode)
+    #[arg(long, requires = "session", help = "End the session at the first command that exits with a non-zero code, and exit with that code")]
+    stop_on_error: bool,
+
+    /// Show the request and bootstrap without running anything (Client mode)
+    #[arg(long, help = "Print the request that would be sent and the bootstrap command that would start the server (password and token redacted), without connecting or running anything")]
+    dry_run: bool,
+
+    /// Keep the command running through a dropped connection and resume its output (Client mode)
+    #[arg(long, conflicts_with_all = ["stdin", "session"], help = "Have the server keep the command running if the connection drops, and reconnect to resume its output where it stopped")]
+    resumable: bool,
+
+    /// Send the output in whole lines (Client mode)
+    #[arg(long, help = "Have the server send the output line by line instead of in arbitrary chunks; a partial line (e.g. a prompt) is sent after a short pause")]
+    line_buffered: bool,
+
+    /// Queue the command on the server instead of running it now (Client mode)
+    #[cfg(feature = "queue")]
+    #[arg(long, help = "Add the command to the server's persistent queue and print its job id; check it later with \"ctrl job <id>\"")]
+    enqueue: bool,
 }
 
 #[derive(Subcommand)]
@@ -104,118 +243,151 @@ enum Commands {
         #[arg(short, long, default_value = "5330", help = "TCP port for server to listen on")]
         port: u16,
     },
+    /// Print the client's version and ask the server for its own
+    Version,
+    /// Check that the server is up and answers, without running a command
+    Ping,
+    /// Send a control request to the server instead of running a command
+    Ctrl {
+        /// shutdown, reload, kill <ID>, kill-all, version, ping, or job <ID> (`queue` feature)
+        #[arg(required = true, num_args = 1..=2, value_name = "REQUEST")]
+        request: Vec<String>,
+    },
+    /// Measure end-to-end output throughput against a running server
+    Bench {
+        /// Amount of output to generate, in MiB
+        #[arg(long, default_value = "50", help = "MiB of output the benchmark command produces")]
+        size_mb: u64,
+        /// Command to run instead of the built-in PowerShell generator
+        #[arg(long, help = "Custom output generator (should print exactly --size-mb MiB)")]
+        command: Option<String>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Try loading .env from multiple locations
-    let mut env_loaded = false;
-    let mut tried_paths = Vec::new();
-    
-    // 1. Try current working directory
-    let cwd_env = std::env::current_dir().ok().map(|p| p.join(".env"));
-    if let Some(ref path) = cwd_env {
-        tried_paths.push(path.display().to_string());
-        if path.exists() {
-            match dotenvy::from_path(path) {
-                Ok(_) => {
-                    eprintln!("[DEBUG] Loaded .env from: {}", path.display());
-                    env_loaded = true;
-                }
-                Err(e) => {
-                    eprintln!("[DEBUG] Failed to load .env from {}: {}", path.display(), e);
-                }
-            }
-        }
-    }
-    
-    // 2. Try executable directory
-    if !env_loaded {
-        if let Ok(exe_path) = std::env::current_exe() {
-            if let Some(exe_dir) = exe_path.parent() {
-                let env_path = exe_dir.join(".env");
-                tried_paths.push(env_path.display().to_string());
-                if env_path.exists() {
-                    match dotenvy::from_path(&env_path) {
-                        Ok(_) => {
-                            eprintln!("[DEBUG] Loaded .env from: {}", env_path.display());
-                            env_loaded = true;
-                        }
-                        Err(e) => {
-                            eprintln!("[DEBUG] Failed to load .env from {}: {}", env_path.display(), e);
-                        }
-                    }
-                }
-            }
-        }
-    }
-    
-    // 3. Try project root (parent of target/release or target/debug)
-    if !env_loaded {
-        if let Ok(exe_path) = std::env::current_exe() {
-            if let Some(exe_dir) = exe_path.parent() {
-                // If we're in target/release or target/debug, go up two levels
-                if exe_dir.ends_with("release") || exe_dir.ends_with("debug") {
-                    if let Some(target_dir) = exe_dir.parent() {
-                        if let Some(project_root) = target_dir.parent() {
-                            let env_path = project_root.join(".env");
-                            tried_paths.push(env_path.display().to_string());
-                            if env_path.exists() {
-                                match dotenvy::from_path(&env_path) {
-                                    Ok(_) => {
-                                        eprintln!("[DEBUG] Loaded .env from: {}", env_path.display());
-                                        env_loaded = true;
-                                    }
-                                    Err(e) => {
-                                        eprintln!("[DEBUG] Failed to load .env from {}: {}", env_path.display(), e);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
-    
-    if !env_loaded {
-        eprintln!("[WARNING] No .env file found in any of these locations:");
-        for path in tried_paths {
-            eprintln!("  - {}", path);
-        }
-        eprintln!("Using defaults or system environment variables.");
-    }
-    
     let cli = Cli::parse();
+    let server = cli.server || matches!(cli.command, Some(Commands::Server { .. }));
+    load_settings(cli.config.as_deref(), if server { Role::Server } else { Role::Client })?;
+    // Set after the settings are loaded, so no .env or config file can turn it back on
+    if cli.no_bootstrap {
+        std::env::set_var("WINBOAT_BOOTSTRAP", "off");
+    }
 
-    if cli.server || matches!(cli.command, Some(Commands::Server { .. })) {
+    if server {
         let port = if let Some(Commands::Server { port }) = cli.command {
             port
         } else {
             5330
         };
-        server_mode(port).await?;
-    } else if let Some(cmd) = cli.cmd {
-        client_mode(&cmd).await?;
+        run_server(ServerOptions { port, bind: cli.bind, once: cli.once, verify_job: cli.verify_job, force_kill: cli.force_kill, listening: None }).await?;
+    } else if let Some(Commands::Version) = cli.command {
+        println!("winboat-bridge client {}", VERSION);
+        let options = RequestOptions {
+            identity: cli.identity.or_else(local_username),
+            exit_code: true,
+            framed: true,
+            auth_token: client_auth_token(),
+            ..Default::default()
+        };
+        exit_with(run_control("version", &options).await?);
+    } else if let Some(Commands::Ping) = cli.command {
+        let pong = ping().await?;
+        println!("PONG from {} in {:.1} ms", pong.addr, pong.round_trip.as_secs_f64() * 1000.0);
+    } else if let Some(Commands::Ctrl { request }) = cli.command {
+        let options = RequestOptions {
+            identity: cli.identity.or_else(local_username),
+            exit_code: true,
+            framed: true,
+            auth_token: client_auth_token(),
+            admin_token: client_admin_token(),
+            ..Default::default()
+        };
+        exit_with(run_control(&request.join(" "), &options).await?);
+    } else if let Some(Commands::Bench { size_mb, command }) = cli.command {
+        let options = RequestOptions {
+            identity: cli.identity.or_else(local_username),
+            auth_token: client_auth_token(),
+            ..Default::default()
+        };
+        run_bench(size_mb, command.as_deref(), &options).await?;
+    } else if cli.cmd.is_some() || cli.session {
+        let options = RequestOptions {
+            confirm_destructive: cli.confirm_destructive,
+            show_invocation: cli.show_invocation,
+            title: cli.title,
+            output_pipe: cli.output_pipe,
+            identity: cli.identity.or_else(local_username),
+            auth_token: client_auth_token(),
+            cwd: cli.cwd,
+            scratch_dir: cli.scratch_dir,
+            keep_scratch: cli.keep_scratch,
+            stats: cli.stats,
+            to_clipboard: cli.to_clipboard,
+            cpu_percent: cli.cpu_percent,
+            no_stderr: cli.no_stderr,
+            stderr_only: cli.stderr_only,
+            initial_batch: cli.initial_batch,
+            line_buffered: cli.line_buffered,
+            resumable: cli.resumable,
+            exit_code: true,
+            framed: true,
+            timeout: (cli.timeout > 0).then_some(cli.timeout),
+            max_output: cli.max_output,
+            shell: cli.shell,
+            clean_env: cli.clean_env,
+            env: cli.env,
+            stdin: cli.stdin,
+            #[cfg(feature = "queue")]
+            enqueue: cli.enqueue,
+            ..Default::default()
+        };
+        let checks = OutputChecks::new(cli.fail_if_match, cli.fail_unless_match);
+        let vt_filter = VtFilter::new(cli.color.strips(std::io::stdout().is_terminal() && cli.stdout_file.is_none()));
+        let exit = ExitHandling { map: cli.map_exit.unwrap_or_default(), expect: cli.expect_exit };
+        let cmd = cli.cmd.unwrap_or_default();
+        if cli.session {
+            exit_with(run_session(&options, cli.stop_on_error, checks, vt_filter, cli.hexdump, &exit).await?);
+        } else if cli.dry_run {
+            dry_run(&cmd, &options)?;
+        } else if cli.json {
+            exit_with(run_command_json(&cmd, &options, checks, vt_filter, cli.hexdump, &exit).await?);
+        } else {
+            let files = OutputFiles { stdout: cli.stdout_file, stderr: cli.stderr_file, append: cli.append };
+            exit_with(run_command(&cmd, &options, checks, vt_filter, cli.hexdump, &files, &exit).await?);
+        }
     } else {
         println!("WinBoat Bridge - Remote Command Executor for Windows Containers");
         println!("---------------------------------------------------------------");
         println!("Usage:");
         println!("  winboat-bridge --server          # Run in Server Mode (Windows side)");
         println!("  winboat-bridge -c <COMMAND>      # Execute command remotely (Linux side)");
-        println!("");
+        println!();
         println!("Examples:");
         println!("  1. Check remote IP:");
         println!("     winboat-bridge -c \"ipconfig\"");
-        println!("");
+        println!();
         println!("  2. List remote directory:");
         println!("     winboat-bridge -c \"dir C:\\Users\"");
-        println!("");
+        println!();
         println!("  3. Run PowerShell script:");
         println!("     winboat-bridge -c \"powershell -File C:\\Scripts\\test.ps1\"");
-        println!("");
+        println!();
         println!("  4. Close remote server:");
-        println!("     winboat-bridge -c \"quit\"");
+        println!("     winboat-bridge ctrl shutdown");
+        println!();
+        println!("  5. Reload server configuration (.env, winboat.toml) without restarting:");
+        println!("     winboat-bridge ctrl reload");
+        println!();
+        println!("  6. Stop a running command by the id in the server log (or all of them):");
+        println!("     winboat-bridge ctrl kill 3");
+        println!("     winboat-bridge ctrl kill-all");
+        println!();
+        println!("  7. Measure output throughput (e.g. before/after a tuning change):");
+        println!("     winboat-bridge bench --size-mb 100");
+        println!();
+        println!("  8. Check that the server is up, without running anything:");
+        println!("     winboat-bridge ping");
         println!("-------------------------------------");
         println!("For detailed help on all parameters, run:");
         println!("  winboat-bridge -h");
@@ -224,463 +396,52 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn server_mode(port: u16) -> Result<()> {
-    // Force UTF-8 code page on Windows
-    #[cfg(target_os = "windows")]
-    {
-        let _ = Command::new("cmd").args(&["/C", "chcp 65001"]).output().await;
-    }
-
-    let actual_port = env::var("WINBOAT_SERVER_PORT")
-        .ok()
-        .and_then(|p| p.parse::<u16>().ok())
-        .unwrap_or(port);
-    
-    let addr = format!("0.0.0.0:{}", actual_port);
-
-    // Bind with Windows-friendly recovery on AddrInUse (os error 10048)
-    let listener = match TcpListener::bind(&addr).await {
-        Ok(l) => l,
-        Err(e) if e.kind() == ErrorKind::AddrInUse => {
-            #[cfg(target_os = "windows")]
-            {
-                eprintln!("Port {} already in use. Attempting to terminate existing listener and retry...", actual_port);
-                kill_listener_on_port_windows(actual_port).await?;
-                
-                // Wait a bit more for socket to be fully released
-                println!("Waiting additional 1 second for socket release...");
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                
-                match TcpListener::bind(&addr).await {
-                    Ok(l) => l,
-                    Err(e2) if e2.kind() == ErrorKind::AddrInUse => {
-                        return Err(anyhow::anyhow!(
-                            "Port {} is still in use after kill attempt. Please close the existing process and retry. Underlying error: {}",
-                            actual_port,
-                            e2
-                        ));
-                    }
-                    Err(e2) => return Err(e2.into()),
-                }
-            }
-            #[cfg(not(target_os = "windows"))]
-            {
-                return Err(e.into());
-            }
-        }
-        Err(e) => return Err(e.into()),
-    };
-    println!("Server listening on {}", addr);
-
-    // Persistent Server Mode
-    let shutdown_signal = Arc::new(Notify::new());
-
-    loop {
-        let shutdown_signal = shutdown_signal.clone();
-        tokio::select! {
-            _ = shutdown_signal.notified() => {
-                println!("Shutdown signal received. stopping server.");
-                break;
-            }
-            accept_result = listener.accept() => {
-                match accept_result {
-                    Ok((mut socket, _)) => {
-                        tokio::spawn(async move {
-                            // Handshake: Send READY
-                            if let Err(e) = socket.write_all(b"READY\n").await {
-                                eprintln!("Failed to send handshake: {}", e);
-                                return;
-                            }
-                            let _ = socket.flush().await;
-            
-                            if let Err(e) = handle_connection(socket, shutdown_signal).await {
-                                eprintln!("Connection error: {}", e);
-                            }
-                        });
-                    }
-                    Err(e) => {
-                        eprintln!("Accept error: {}", e);
-                    }
-                }
-            }
-        }
+/// `--cmd` value, without surrounding whitespace. An empty command is refused here rather
+/// than sent to run the remote shell with nothing.
+fn parse_command(cmd: &str) -> Result<String, String> {
+    let cmd = cmd.trim();
+    if cmd.is_empty() {
+        return Err("the command is empty".to_string());
     }
-
-    println!("Server shutting down.");
-    Ok(())
+    Ok(cmd.to_string())
 }
 
-#[cfg(target_os = "windows")]
-async fn kill_listener_on_port_windows(port: u16) -> Result<()> {
-    // Find PID(s) listening on a port and terminate them.
-    // netstat output example:
-    // TCP    0.0.0.0:5330   0.0.0.0:0   LISTENING   12345
-    let find_cmd = format!(
-        "netstat -a -n -o | findstr LISTENING | findstr :{}",
-        port
-    );
-
-    let out = Command::new("cmd")
-        .args(["/C", &find_cmd])
-        .output()
-        .await
-        .context("Failed to run netstat to locate PID")?;
-
-    // If nothing found, maybe the port was released in the meantime.
-    if out.stdout.is_empty() {
-        println!("[kill_listener] netstat returned no LISTENING lines for port {}", port);
-        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
-        return Ok(());
-    }
-
-    let stdout = String::from_utf8_lossy(&out.stdout);
-    println!("[kill_listener] netstat raw output:\n{}", stdout);
-    let mut pids: Vec<u32> = stdout
-        .lines()
-        .filter_map(|line| line.split_whitespace().last())
-        .filter_map(|pid| pid.parse::<u32>().ok())
-        .collect();
-    pids.sort_unstable();
-    pids.dedup();
-
-    if pids.is_empty() {
-        println!("[kill_listener] No PIDs parsed from netstat output for port {}", port);
-        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
-        return Ok(());
+/// `--env` value: `KEY=VALUE` on a single line, as it is sent in an `ENV` line.
+fn parse_env_assignment(assignment: &str) -> Result<String, String> {
+    match assignment.split_once('=') {
+        Some((key, _)) if !key.trim().is_empty() && !key.contains(char::is_whitespace) => {}
+        _ => return Err("expected KEY=VALUE with a non-empty name without spaces".to_string()),
     }
-
-    println!("[kill_listener] PIDs to kill: {:?}", pids);
-    for pid in pids {
-        let kill = Command::new("taskkill")
-            .args(["/F", "/PID", &pid.to_string()])
-            .output()
-            .await
-            .with_context(|| format!("Failed to run taskkill for PID {}", pid))?;
-
-        if !kill.status.success() {
-            let stderr = String::from_utf8_lossy(&kill.stderr);
-            // If it already exited between netstat and taskkill, treat as non-fatal.
-            eprintln!("[kill_listener] Warning: taskkill failed for PID {}: {}", pid, stderr.trim());
-        } else {
-            let stdout_kill = String::from_utf8_lossy(&kill.stdout);
-            println!("[kill_listener] taskkill success for PID {}: {}", pid, stdout_kill.trim());
-        }
+    if assignment.contains(['\n', '\r', '\0']) {
+        return Err("the value can't contain line breaks or NUL".to_string());
     }
-
-    // Give Windows a moment to release the socket
-    println!("[kill_listener] Sleeping 800ms for socket release...");
-    tokio::time::sleep(tokio::time::Duration::from_millis(800)).await;
-    Ok(())
+    Ok(assignment.to_string())
 }
 
-async fn handle_connection(mut socket: TcpStream, shutdown_signal: Arc<Notify>) -> Result<()> {
-    // 1. Read command
-    let mut buf = [0; 1024];
-    let n = socket.read(&mut buf).await?;
-    if n == 0 {
-        return Ok(());
-    }
-    let command_line = String::from_utf8_lossy(&buf[..n]).trim().to_string();
-    println!("Received command: {}", command_line);
-
-    // Check for quit/exit command
-    if command_line.eq_ignore_ascii_case("quit") || command_line.eq_ignore_ascii_case("exit") {
-        println!("Quit command received. notifying shutdown.");
-        shutdown_signal.notify_one();
-        return Ok(());
+/// Leaves with the remote command's exit code when it failed.
+fn exit_with(code: i32) {
+    if code != 0 {
+        std::process::exit(code);
     }
-
-    // 2. Spawn process
-    // ... rest of implementation matches previous logic
-    // Detect OS for shell execution
-    #[cfg(target_os = "windows")]
-    let (shell, flag) = ("cmd", "/C");
-    #[cfg(not(target_os = "windows"))]
-    let (shell, flag) = ("sh", "-c");
-
-    let mut child = Command::new(shell)
-        .arg(flag)
-        .arg(&command_line)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        // .stdin(Stdio::piped()) // Future improvement for interactive
-        .spawn()
-        .context("Failed to spawn command")?;
-
-    // On Windows, assign to Job Object
-    #[cfg(target_os = "windows")]
-    let _job_handle = {
-        if let Some(handle) = child.raw_handle() {
-             win_job::assign_to_new_job(handle)?
-        } else {
-             // Should not happen on Windows unless process already exited
-             return Err(anyhow::anyhow!("Failed to get child process handle"));
-        }
-    };
-
-    let stdout = child.stdout.take().context("Failed to open stdout")?;
-    let stderr = child.stderr.take().context("Failed to open stderr")?;
-
-    // 3. Stream output
-    let (mut socket_reader, mut socket_writer) = socket.into_split();
-    
-    // Notification to kill child if socket drops
-    let kill_notify = Arc::new(Notify::new());
-    let kill_notify_clone_read = kill_notify.clone();
-    let kill_notify_clone_write = kill_notify.clone();
-
-    // Monitor socket for disconnection (Read EOF)
-    tokio::spawn(async move {
-        let mut buf = [0; 1024];
-        // We don't expect any more data from client, so any read returning 0 means EOF (disconnect).
-        loop {
-            match socket_reader.read(&mut buf).await {
-                Ok(0) => {
-                    kill_notify_clone_read.notify_one();
-                    break;
-                }
-                Ok(_) => { } // Ignore extra data
-                Err(_) => {
-                    kill_notify_clone_read.notify_one();
-                    break;
-                }
-            }
-        }
-    });
-
-    // Stream stdout to socket
-    let mut stdout_reader = tokio::io::BufReader::new(stdout);
-    let mut stderr_reader = tokio::io::BufReader::new(stderr);
-    
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(32);
-    let tx_stderr = tx.clone();
-
-    let stdout_handle = tokio::spawn(async move {
-        let mut buf = [0; 1024];
-        loop {
-            match stdout_reader.read(&mut buf).await {
-                Ok(0) => break, // EOF
-                Ok(n) => {
-                    if tx.send(buf[..n].to_vec()).await.is_err() { break; }
-                }
-                Err(_) => break,
-            }
-        }
-    });
-
-    let stderr_handle = tokio::spawn(async move {
-        let mut buf = [0; 1024];
-        loop {
-            match stderr_reader.read(&mut buf).await {
-                Ok(0) => break, // EOF
-                Ok(n) => {
-                    if tx_stderr.send(buf[..n].to_vec()).await.is_err() { break; }
-                }
-                Err(_) => break,
-            }
-        }
-    });
-
-    // Write loop: receive from channel, write to socket
-    let writer_handle = tokio::spawn(async move {
-        while let Some(data) = rx.recv().await {
-            if socket_writer.write_all(&data).await.is_err() {
-                kill_notify_clone_write.notify_one();
-                break;
-            }
-        }
-        let _ = socket_writer.flush().await;
-    });
-
-    // Wait for child to exit OR kill signal
-    tokio::select! {
-        _ = child.wait() => {
-            // Process finished normally
-        }
-        _ = kill_notify.notified() => {
-            println!("Client disconnected, killing process...");
-            let _ = child.kill().await;
-        }
-    }
-
-    // Cleanup
-    let _ = stdout_handle.await;
-    let _ = stderr_handle.await;
-    let _ = writer_handle.await;
-
-    Ok(())
 }
 
-async fn client_mode(cmd: &str) -> Result<()> {
-    // Port mapped on host: 47330 -> Container: 5330
-    let client_port = env::var("WINBOAT_CLIENT_PORT")
-        .unwrap_or_else(|_| "47330".to_string());
-    let addr = format!("127.0.0.1:{}", client_port); 
-    
-    // Attempt connection loop (Connect -> Handshake -> if fail -> Bootstrap -> Retry)
-    let mut attempt = 0;
-    let max_attempts = 2;
-    
-    let mut socket = loop {
-        attempt += 1;
-        println!("Connecting to {} (Attempt {})...", addr, attempt);
-        
-        let connect_result = tokio::time::timeout(
-            tokio::time::Duration::from_secs(2),
-            TcpStream::connect(addr.as_str())
-        ).await;
-
-        let mut s = match connect_result {
-            Ok(Ok(s)) => s,
-            _ => {
-                if attempt >= max_attempts {
-                     return Err(anyhow::anyhow!("Failed to connect to server after bootstrap attempt"));
-                }
-                eprintln!("Connection failed or timed out. Bootstrapping...");
-                bootstrap_server().await?;
-                continue;
-            }
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Handshake Check
-        let mut buf = [0; 6]; // "READY\n"
-        let handshake_result = tokio::time::timeout(
-             tokio::time::Duration::from_millis(1000),
-             s.read_exact(&mut buf)
-        ).await;
-
-        match handshake_result {
-            Ok(Ok(_)) if &buf == b"READY\n" => {
-                println!("Connected and verified.");
-                break s;
-            }
-            _ => {
-                 if attempt >= max_attempts {
-                     return Err(anyhow::anyhow!("Handshake failed (Zombie connection?)"));
-                }
-                println!("Connected but no READY signal (likely Docker zombie port). Bootstrapping...");
-                bootstrap_server().await?;
-                continue;
-            }
+    #[test]
+    fn parse_command_refuses_blank_commands() {
+        for cmd in ["", " ", "\t ", "\n", "\r\n", " \n\t\n "] {
+            assert!(parse_command(cmd).is_err(), "accepted {:?}", cmd);
         }
-    };
-
-    // Send command
-    socket.write_all(cmd.as_bytes()).await?;
-    
-    // Stream output to stdout
-    let mut stdout = tokio::io::stdout();
-    let mut buf = [0; 1024];
-    loop {
-        let n = socket.read(&mut buf).await?;
-        if n == 0 {
-            break;
-        }
-        stdout.write_all(&buf[..n]).await?;
-        stdout.flush().await?;
+        assert_eq!(parse_command("  dir C:\\ \n").unwrap(), "dir C:\\");
     }
 
-    Ok(())
-}
-
-async fn bootstrap_server() -> Result<()> {
-    let exe_path = env::var("WINBOAT_EXE_PATH")
-        .context("WINBOAT_EXE_PATH must be set in the .env file")?;
-    
-    let log_path = env::var("WINBOAT_LOG_PATH")
-        .unwrap_or_else(|_| r"C:\Users\gianca\server.log".to_string());
-    
-    let err_path = env::var("WINBOAT_ERR_PATH")
-        .unwrap_or_else(|_| r"C:\Users\gianca\server.err".to_string());
-    
-    // Use PowerShell Start-Process to spawn the process in a detached state.
-    // -WindowStyle Hidden: Hides the window
-    // -PassThru: Returns the process object (useful for debugging, though we ignore it here)
-    // We direct output to files for debugging since we can't see it easily in detached mode.
-    let ps_command = format!(
-        "Start-Process -FilePath '{}' -ArgumentList '--server' -WindowStyle Hidden -RedirectStandardOutput '{}' -RedirectStandardError '{}'",
-        exe_path, log_path, err_path
-    );
-    
-    // Direct evil-winrm invocation details
-    let host = env::var("WINBOAT_HOST")
-        .unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port = env::var("WINBOAT_PORT")
-        .unwrap_or_else(|_| "47320".to_string());
-    let user = env::var("WINBOAT_USER")
-        .unwrap_or_else(|_| "gianca".to_string());
-    let pass = env::var("WINBOAT_PASS")
-        .unwrap_or_else(|_| "gianca".to_string());
-
-    println!("Bootstrapping server via evil-winrm...");
-    println!("PowerShell Command: {}", ps_command);
-
-    // We pipe the command to evil-winrm stdin, similar to how the shell script did it.
-    // This avoids complex escaping issues with passing the command as an argument to evil-winrm directly.
-    let mut child = Command::new("evil-winrm")
-        .arg("-i").arg(host)
-        .arg("-P").arg(port)
-        .arg("-u").arg(user)
-        .arg("-p").arg(pass)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context("Failed to spawn evil-winrm")?;
-
-    let mut stdin = child.stdin.take().context("Failed to open evil-winrm stdin")?;
-    
-    // Wrap the command in powershell execution
-    let full_command = format!("powershell -Command \"{}\"", ps_command);
-    stdin.write_all(full_command.as_bytes()).await?;
-    stdin.write_all(b"\n").await?; // Add newline to execute command
-    stdin.write_all(b"exit\n").await?; // Ensure shell exits
-    drop(stdin); // Close stdin to signal we're done sending the command
-
-    // Consume stdout and stderr concurrently to prevent deadlocks
-    let mut stdout = child.stdout.take().context("Failed to open stdout")?;
-    let mut stderr = child.stderr.take().context("Failed to open stderr")?;
-
-    let stdout_handle = tokio::spawn(async move {
-        let mut data = Vec::new();
-        let _ = stdout.read_to_end(&mut data).await;
-        data
-    });
-
-    let stderr_handle = tokio::spawn(async move {
-        let mut data = Vec::new();
-        let _ = stderr.read_to_end(&mut data).await;
-        data
-    });
-
-    // Wait for evil-winrm to exit, with a timeout
-    println!("Waiting for bootstrap command to complete...");
-    let wait_result = tokio::time::timeout(
-        tokio::time::Duration::from_secs(15),
-        child.wait()
-    ).await;
-
-    match wait_result {
-        Ok(Ok(status)) => {
-            // Wait for I/O to finish
-            let _ = stdout_handle.await; 
-            let stderr_data = stderr_handle.await.unwrap_or_default();
-            
-             if !status.success() {
-                let stderr_str = String::from_utf8_lossy(&stderr_data);
-                println!("Bootstrap returned non-zero. Stderr: {}", stderr_str);
-            } else {
-                println!("Bootstrap command executed successfully.");
-            }
-        },
-        Ok(Err(e)) => return Err(anyhow::anyhow!("Failed to wait for evil-winrm: {}", e)),
-        Err(_) => {
-            println!("Bootstrap command timed out (evil-winrm hang). Killing local process and assuming remote started.");
-            let _ = child.kill().await;
+    #[test]
+    fn cli_refuses_an_empty_command() {
+        for cmd in ["", "   ", "\n"] {
+            assert!(Cli::try_parse_from(["winboat-bridge", "-c", cmd]).is_err(), "accepted {:?}", cmd);
         }
+        assert!(Cli::try_parse_from(["winboat-bridge", "-c", "ver"]).is_ok());
     }
-
-    println!("Waiting for server to start...");
-    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-    Ok(())
 }
@@ -0,0 +1,214 @@
+//! Persistent command queue (`queue` feature).
+//!
+//! Commands enqueued with `--enqueue` are written to a JSON file and run one at a time, in
+//! order, by a background worker. The file is rewritten after every change, so a restarted
+//! server picks up where it stopped: a job that was running when the server went down is
+//! run again from the start.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use tokio::process::Command;
+use tokio::sync::Notify;
+
+/// Output kept per job; anything beyond it is dropped and marked as truncated.
+const MAX_JOB_OUTPUT: usize = 64 * 1024;
+/// Finished jobs kept in the file for result queries; older ones are dropped.
+const MAX_FINISHED_JOBS: usize = 100;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Pending,
+    Running,
+    Done,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub command: String,
+    pub state: JobState,
+    pub exit_code: Option<i32>,
+    /// Combined stdout and stderr, lossily decoded
+    pub output: String,
+    pub truncated: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct QueueFile {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+pub struct JobQueue {
+    path: PathBuf,
+    file: Mutex<QueueFile>,
+    /// Wakes the worker when a job is enqueued
+    wake: Notify,
+}
+
+impl JobQueue {
+    /// Opens the queue file at `path`, creating an empty queue if it doesn't exist yet.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut file = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice::<QueueFile>(&data)
+                .with_context(|| format!("Queue file {} is corrupt", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => QueueFile { next_id: 1, jobs: Vec::new() },
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("Failed to read queue file {}", path.display()))),
+        };
+
+        // Whatever was running when the server stopped is started over
+        for job in file.jobs.iter_mut().filter(|job| job.state == JobState::Running) {
+            log_eprintln!("[queue] Job #{} was interrupted by a restart, running it again", job.id);
+            job.state = JobState::Pending;
+            job.output.clear();
+            job.truncated = false;
+        }
+        let pending = file.jobs.iter().filter(|job| job.state == JobState::Pending).count();
+        log_println!("[queue] Using {} ({} pending job(s))", path.display(), pending);
+
+        let queue = Self { path, file: Mutex::new(file), wake: Notify::new() };
+        queue.save(&queue.file.lock().unwrap())?;
+        Ok(queue)
+    }
+
+    /// Adds a command to the end of the queue and returns its job id.
+    pub fn enqueue(&self, command: &str) -> Result<u64> {
+        let id = {
+            let mut file = self.file.lock().unwrap();
+            let id = file.next_id;
+            file.next_id += 1;
+            file.jobs.push(Job {
+                id,
+                command: command.to_string(),
+                state: JobState::Pending,
+                exit_code: None,
+                output: String::new(),
+                truncated: false,
+            });
+            self.save(&file)?;
+            id
+        };
+        self.wake.notify_one();
+        Ok(id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        self.file.lock().unwrap().jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Runs queued jobs in order, forever.
+    pub async fn run(&self) -> Result<()> {
+        loop {
+            let next = {
+                let mut file = self.file.lock().unwrap();
+                let next = file.jobs.iter_mut().find(|job| job.state == JobState::Pending).map(|job| {
+                    job.state = JobState::Running;
+                    (job.id, job.command.clone())
+                });
+                if next.is_some() {
+                    self.save(&file)?;
+                }
+                next
+            };
+            let Some((id, command)) = next else {
+                self.wake.notified().await;
+                continue;
+            };
+
+            log_println!("[queue] Running job #{}: {}", id, command);
+            let (exit_code, output, truncated) = run_job(&command).await;
+            log_println!("[queue] Job #{} finished with code {:?}", id, exit_code);
+
+            let mut file = self.file.lock().unwrap();
+            if let Some(job) = file.jobs.iter_mut().find(|job| job.id == id) {
+                job.state = JobState::Done;
+                job.exit_code = exit_code;
+                job.output = output;
+                job.truncated = truncated;
+            }
+            prune_finished(&mut file.jobs);
+            self.save(&file)?;
+        }
+    }
+
+    /// Rewrites the queue file through a temporary file, so a crash never leaves it half written.
+    fn save(&self, file: &QueueFile) -> Result<()> {
+        let data = serde_json::to_vec_pretty(file)?;
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, data).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path).with_context(|| format!("Failed to replace {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Runs one job to completion and returns its exit code and captured output.
+async fn run_job(command: &str) -> (Option<i32>, String, bool) {
+    let (program, shell_args) = crate::Shell::DEFAULT.argv();
+    let result = Command::new(program)
+        .args(shell_args)
+        .arg(command)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await;
+    match result {
+        Ok(output) => {
+            let mut combined = output.stdout;
+            combined.extend_from_slice(&output.stderr);
+            let truncated = combined.len() > MAX_JOB_OUTPUT;
+            combined.truncate(MAX_JOB_OUTPUT);
+            (output.status.code(), String::from_utf8_lossy(&combined).into_owned(), truncated)
+        }
+        Err(e) => (None, format!("Failed to start command: {}", e), false),
+    }
+}
+
+fn prune_finished(jobs: &mut Vec<Job>) {
+    let finished = jobs.iter().filter(|job| job.state == JobState::Done).count();
+    let mut excess = finished.saturating_sub(MAX_FINISHED_JOBS);
+    jobs.retain(|job| {
+        if excess > 0 && job.state == JobState::Done {
+            excess -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+/// Queue file location: WINBOAT_QUEUE_FILE, or `winboat-queue.json` next to the executable.
+pub fn queue_file_path() -> PathBuf {
+    if let Ok(path) = std::env::var("WINBOAT_QUEUE_FILE") {
+        return PathBuf::from(path);
+    }
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        .unwrap_or_default()
+        .join("winboat-queue.json")
+}
+
+/// Human-readable job report sent for `CTRL JOB <id>`.
+pub fn describe_job(job: &Job) -> String {
+    let state = match (job.state, job.exit_code) {
+        (JobState::Pending, _) => "pending".to_string(),
+        (JobState::Running, _) => "running".to_string(),
+        (JobState::Done, Some(code)) => format!("done, exit code {}", code),
+        (JobState::Done, None) => "done, no exit code (killed or failed to start)".to_string(),
+    };
+    let mut report = format!("Job #{}: {}\nCommand: {}\n", job.id, state, job.command);
+    if job.state == JobState::Done {
+        report.push_str("--- output ---\n");
+        report.push_str(&job.output);
+        if !job.output.is_empty() && !job.output.ends_with('\n') {
+            report.push('\n');
+        }
+        if job.truncated {
+            report.push_str(&format!("--- output truncated to {} bytes ---\n", MAX_JOB_OUTPUT));
+        }
+    }
+    report
+}
@@ -0,0 +1,82 @@
+//! TLS for the client-server connection (`tls` feature).
+//!
+//! The server turns it on by pointing WINBOAT_TLS_CERT and WINBOAT_TLS_KEY at a PEM
+//! certificate chain and private key; every connection must then use TLS. Clients opt in
+//! with WINBOAT_TLS=1 and trust the certificates in WINBOAT_TLS_CA, which for a
+//! self-signed setup is the server's own certificate file.
+
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+/// Name checked against the server certificate when WINBOAT_TLS_SERVER_NAME isn't set.
+const DEFAULT_SERVER_NAME: &str = "localhost";
+
+/// Server side: the acceptor for WINBOAT_TLS_CERT and WINBOAT_TLS_KEY, or `None` when
+/// neither is set.
+pub fn acceptor_from_env() -> Result<Option<TlsAcceptor>> {
+    let (cert_path, key_path) = match (env_path("WINBOAT_TLS_CERT"), env_path("WINBOAT_TLS_KEY")) {
+        (None, None) => return Ok(None),
+        (Some(cert), Some(key)) => (cert, key),
+        _ => anyhow::bail!("WINBOAT_TLS_CERT and WINBOAT_TLS_KEY must be set together"),
+    };
+    let certs = load_certs(&cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(&key_path)
+        .with_context(|| format!("Failed to read TLS private key {}", key_path))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or private key")?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Client side of the connection, set up from WINBOAT_TLS, WINBOAT_TLS_CA and
+/// WINBOAT_TLS_SERVER_NAME.
+pub struct ClientTls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl ClientTls {
+    /// `None` unless WINBOAT_TLS=1.
+    pub fn from_env() -> Result<Option<Self>> {
+        if std::env::var("WINBOAT_TLS").ok().as_deref() != Some("1") {
+            return Ok(None);
+        }
+        let ca_path = env_path("WINBOAT_TLS_CA")
+            .context("WINBOAT_TLS=1 needs WINBOAT_TLS_CA, the PEM file of the certificate(s) to trust")?;
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&ca_path)? {
+            roots.add(cert).with_context(|| format!("Unusable certificate in {}", ca_path))?;
+        }
+        let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+
+        let name = env_path("WINBOAT_TLS_SERVER_NAME").unwrap_or_else(|| DEFAULT_SERVER_NAME.to_string());
+        let server_name = ServerName::try_from(name.clone())
+            .with_context(|| format!("Invalid WINBOAT_TLS_SERVER_NAME: {}", name))?;
+        Ok(Some(Self { connector: TlsConnector::from(Arc::new(config)), server_name }))
+    }
+
+    pub async fn connect(&self, stream: TcpStream) -> std::io::Result<TlsStream<TcpStream>> {
+        let stream = self.connector.connect(self.server_name.clone(), stream).await?;
+        Ok(TlsStream::Client(stream))
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read TLS certificates from {}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate found in {}", path);
+    }
+    Ok(certs)
+}
+
+fn env_path(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
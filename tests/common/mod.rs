@@ -0,0 +1,106 @@
+//! Helpers shared by the test binaries: a server on a loopback port and the framing.
+
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use winboat_bridge::{run_server, ServerOptions};
+
+/// Longest any test waits on a socket before failing instead of hanging.
+pub const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A server on a port the OS picked, on a thread and runtime of its own. Dropping it shuts
+/// it down.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    /// Lines sent ahead of the shutdown request, for a server that wants a token
+    pub shutdown_auth: String,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let thread = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let options = ServerOptions { port: 0, listening: Some(tx), ..Default::default() };
+            runtime.block_on(run_server(options)).unwrap();
+        });
+        let addr = rx.blocking_recv().expect("the server stopped before listening");
+        Self { addr, shutdown_auth: String::new(), thread: Some(thread) }
+    }
+
+    /// Connects and reads the server's READY line, which is returned with the socket.
+    pub fn connect(&self) -> (TcpStream, String) {
+        let mut socket = TcpStream::connect(self.addr).unwrap();
+        socket.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        // Byte by byte, as the client does, so nothing after the line is consumed
+        let mut line = Vec::new();
+        let mut byte = [0; 1];
+        while line.last() != Some(&b'\n') {
+            socket.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+        }
+        (socket, String::from_utf8(line).unwrap())
+    }
+
+    /// Answers the handshake with `PROTO 2`, sends `request` and returns everything the
+    /// server sends back until it closes the connection.
+    pub fn request(&self, request: &[u8]) -> Vec<u8> {
+        let (mut socket, _) = self.connect();
+        socket.write_all(b"PROTO 2\n").unwrap();
+        socket.write_all(request).unwrap();
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    /// Sends `CTRL SHUTDOWN` and waits for `run_server` to return.
+    pub fn shutdown(mut self) -> std::thread::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> std::thread::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        let reply = self.request(format!("{}OPT exit-code\nCTRL SHUTDOWN\n", self.shutdown_auth).as_bytes());
+        assert_eq!(String::from_utf8_lossy(&reply), "__EXIT__ 0\n");
+        thread.join()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            self.stop().unwrap();
+        }
+    }
+}
+
+/// Splits a framed response into (stream id, payload) pairs.
+pub fn frames(mut response: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut frames = Vec::new();
+    while !response.is_empty() {
+        assert!(response.len() >= 5, "truncated frame header");
+        let len = u32::from_be_bytes(response[1..5].try_into().unwrap()) as usize;
+        frames.push((response[0], response[5..5 + len].to_vec()));
+        response = &response[5 + len..];
+    }
+    frames
+}
+
+/// What `echo hello` prints with the platform's shell.
+pub const HELLO: &str = if cfg!(windows) { "hello\r\n" } else { "hello\n" };
+
+/// Sleeps for `duration`, failing early if `path` appears.
+pub fn wait_past(duration: Duration, path: &Path) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        assert!(!path.exists(), "{} appeared after {:?}", path.display(), start.elapsed());
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
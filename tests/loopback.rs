@@ -0,0 +1,411 @@
+//! Runs a real server on a loopback port and talks to it the way clients do: raw requests
+//! over TCP, and the library client for a whole command. Commands go through the server's
+//! default shell, so they are `sh -c` lines off Windows and `cmd /C` lines on it.
+
+mod common;
+
+use common::{frames, wait_past, TestServer, HELLO};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use winboat_bridge::{execute_remote, is_server_alive, RequestOptions, ServerStatus};
+
+#[test]
+fn echo_hello_prints_and_exits_zero() {
+    let server = TestServer::start();
+
+    let response = String::from_utf8(server.request(b"OPT exit-code\nCMD echo hello\n")).unwrap();
+    let (output, exit) = response.split_once("__EXIT__ ").expect("no exit line");
+    assert_eq!(output, HELLO);
+    assert!(exit.starts_with("0 run_ms="), "unexpected exit line: {}", exit);
+
+    let response = String::from_utf8(server.request(b"OPT exit-code\nCMD exit 3\n")).unwrap();
+    assert!(response.starts_with("__EXIT__ 3 "), "unexpected response: {}", response);
+}
+
+#[test]
+fn library_client_runs_a_command() {
+    let server = TestServer::start();
+    // The library client finds the server through the environment; no other test uses it
+    std::env::set_var("WINBOAT_CLIENT_PORT", server.addr.port().to_string());
+    std::env::set_var("WINBOAT_BOOTSTRAP", "off");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime.block_on(execute_remote("echo hello", &RequestOptions::default())).unwrap();
+    assert_eq!(String::from_utf8_lossy(&result.stdout), HELLO);
+    assert_eq!(result.exit_code, 0);
+
+    let result = runtime.block_on(execute_remote("exit 5", &RequestOptions::default())).unwrap();
+    assert_eq!(result.exit_code, 5);
+}
+
+#[test]
+fn handshake_announces_and_negotiates_the_protocol() {
+    let server = TestServer::start();
+
+    let (_, ready) = server.connect();
+    let version = ready.strip_prefix("READY ").and_then(|v| v.trim_end().parse::<u32>().ok());
+    assert!(version.is_some_and(|v| v >= 2), "unexpected handshake: {:?}", ready);
+
+    // Protocol 2 adds the command's run time to the exit line
+    let response = String::from_utf8(server.request(b"OPT exit-code\nCMD exit 0\n")).unwrap();
+    assert!(response.starts_with("__EXIT__ 0 run_ms="), "unexpected response: {}", response);
+
+    // A client from before versioning sends no PROTO line and gets the old exit line
+    let (mut socket, _) = server.connect();
+    socket.write_all(b"OPT exit-code\nCMD exit 0\n").unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "__EXIT__ 0\n");
+
+    // From protocol 1 on, an option the server doesn't know is refused
+    let response = String::from_utf8(server.request(b"OPT exit-code\nOPT no-such-option\nCMD echo hello\n")).unwrap();
+    assert!(response.contains("Unknown request option 'no-such-option'"), "unexpected response: {}", response);
+    assert!(response.ends_with("__EXIT__ 1\n"));
+
+    // A probe answers the handshake and leaves; the server carries on
+    let (mut socket, _) = server.connect();
+    socket.write_all(b"PROTO 2\n").unwrap();
+    drop(socket);
+    let response = String::from_utf8(server.request(b"OPT exit-code\nCMD exit 0\n")).unwrap();
+    assert!(response.starts_with("__EXIT__ 0 "));
+}
+
+#[test]
+fn ctrl_shutdown_stops_the_server() {
+    let server = TestServer::start();
+    let addr = server.addr;
+
+    server.shutdown().expect("run_server failed");
+    assert!(TcpStream::connect(addr).is_err(), "the port still accepts connections");
+}
+
+#[test]
+fn client_disconnect_kills_the_command() {
+    let server = TestServer::start();
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("still-running");
+
+    // Creates the marker only if it lives long enough
+    let command = if cfg!(windows) {
+        format!("ping -n 4 127.0.0.1 > nul & echo x > \"{}\"", marker.display())
+    } else {
+        format!("sleep 3; touch '{}'", marker.display())
+    };
+    let (mut socket, _) = server.connect();
+    socket.write_all(format!("PROTO 2\nOPT exit-code\nCMD {}\n", command).as_bytes()).unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    drop(socket);
+
+    wait_past(Duration::from_secs(5), &marker);
+    assert!(!marker.exists(), "the command kept running after its client went away");
+}
+
+#[test]
+fn framed_output_keeps_the_streams_apart() {
+    let server = TestServer::start();
+
+    let response = server.request(b"OPT exit-code\nOPT framed\nCMD echo hello && echo oops 1>&2\n");
+    let frames = frames(&response);
+    let stream = |id: u8| frames.iter().filter(|(i, _)| *i == id).flat_map(|(_, p)| p.clone()).collect::<Vec<_>>();
+    assert_eq!(String::from_utf8_lossy(&stream(1)), HELLO);
+    assert!(String::from_utf8_lossy(&stream(2)).starts_with("oops"));
+    let (id, last) = frames.last().unwrap();
+    assert_eq!(*id, 0);
+    assert!(last.starts_with(b"__EXIT__ 0 "));
+}
+
+#[test]
+fn output_exactly_at_max_output_is_not_cut() {
+    let server = TestServer::start();
+
+    let request = format!("OPT exit-code\nOPT max-output {}\nCMD echo hello\n", HELLO.len());
+    let response = String::from_utf8(server.request(request.as_bytes())).unwrap();
+    assert!(response.starts_with(&format!("{}__EXIT__ 0 ", HELLO)), "unexpected response: {}", response);
+    assert!(!response.contains("__TRUNCATED__"));
+
+    // One byte less and the output is cut
+    let request = format!("OPT exit-code\nOPT max-output {}\nCMD echo hello\n", HELLO.len() - 1);
+    let response = String::from_utf8(server.request(request.as_bytes())).unwrap();
+    assert!(response.starts_with(&format!("{}__TRUNCATED__ ", &HELLO[..HELLO.len() - 1])), "unexpected response: {}", response);
+}
+
+#[cfg(unix)]
+#[test]
+fn resumable_output_waits_for_a_slow_client() {
+    let server = TestServer::start();
+    // More than the server buffers for a resume, sent before the client reads any of it
+    const SIZE: usize = 40 * 1024 * 1024;
+
+    let (mut socket, _) = server.connect();
+    let request = format!("PROTO 2\nOPT exit-code\nOPT framed\nOPT resumable\nCMD head -c {} /dev/zero\n", SIZE);
+    socket.write_all(request.as_bytes()).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    let mut response = Vec::new();
+    socket.read_to_end(&mut response).unwrap();
+
+    let frames = frames(&response);
+    let stdout: usize = frames.iter().filter(|(id, _)| *id == 1).map(|(_, payload)| payload.len()).sum();
+    assert_eq!(stdout, SIZE);
+    let (id, last) = frames.last().unwrap();
+    assert_eq!(*id, 0);
+    assert!(last.starts_with(b"__EXIT__ 0 "), "unexpected end: {}", String::from_utf8_lossy(last));
+}
+
+#[test]
+fn command_reading_part_of_stdin_is_not_killed() {
+    let server = TestServer::start();
+    // Far more input than the pipe to the command holds, one line repeated
+    let line = [b"x".repeat(63), b"\n".to_vec()].concat();
+    let chunk = line.repeat(1024);
+    const CHUNKS: usize = 160;
+
+    // Reads the first line only and exits while the rest is still coming
+    let command = if cfg!(windows) { "set /p LINE=" } else { "head -n 1" };
+    let (mut socket, _) = server.connect();
+    socket.write_all(format!("PROTO 2\nOPT exit-code\nOPT framed\nOPT stdin\nCMD {}\n", command).as_bytes()).unwrap();
+    let mut input = socket.try_clone().unwrap();
+    let sender = std::thread::spawn(move || {
+        for _ in 0..CHUNKS {
+            input.write_all(&[&[3], &(chunk.len() as u32).to_be_bytes()[..], &chunk].concat())?;
+        }
+        input.write_all(&[3, 0, 0, 0, 0])
+    });
+    let mut response = Vec::new();
+    socket.read_to_end(&mut response).unwrap();
+    // The rest of the input may still be on its way when the response ends; the server
+    // doesn't wait for it
+    let _ = sender.join().unwrap();
+
+    let frames = frames(&response);
+    if !cfg!(windows) {
+        let stdout: Vec<u8> = frames.iter().filter(|(id, _)| *id == 1).flat_map(|(_, payload)| payload.clone()).collect();
+        assert_eq!(stdout, line);
+    }
+    let (id, last) = frames.last().unwrap();
+    assert_eq!(*id, 0);
+    assert!(last.starts_with(b"__EXIT__ 0 "), "unexpected end: {}", String::from_utf8_lossy(last));
+}
+
+#[test]
+fn request_split_over_two_writes_runs_whole() {
+    let server = TestServer::start();
+
+    // A pause longer than a client without a protocol version gets; this one has a version
+    let (mut socket, _) = server.connect();
+    socket.write_all(b"PROTO 2\nOPT exit-code\nCMD echo hel").unwrap();
+    std::thread::sleep(Duration::from_millis(600));
+    socket.write_all(b"lo\n").unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with(&format!("{}__EXIT__ 0 ", HELLO)), "unexpected response: {}", response);
+
+    // Cut off by the client: refused, not run as `echo hel`
+    let (mut socket, _) = server.connect();
+    socket.write_all(b"PROTO 2\nOPT exit-code\nCMD echo hel").unwrap();
+    socket.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("Incomplete request"), "unexpected response: {}", response);
+    assert!(response.ends_with("__EXIT__ 1\n"));
+}
+
+/// Listens on a loopback port for one connection and hands it to `answer`, which plays
+/// something other than the server.
+fn impostor(answer: impl FnOnce(TcpStream) + Send + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        if let Ok((socket, _)) = listener.accept() {
+            answer(socket);
+        }
+    });
+    addr
+}
+
+#[test]
+fn is_server_alive_tells_a_server_from_a_zombie_port() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let status = |addr: &str| runtime.block_on(is_server_alive(addr)).unwrap();
+
+    let server = TestServer::start();
+    assert_eq!(status(&server.addr.to_string()), ServerStatus::Alive);
+
+    // Accepts but never sends READY, like a port proxy waiting on a backend
+    let silent = impostor(|socket| {
+        std::thread::sleep(Duration::from_secs(3));
+        drop(socket);
+    });
+    assert_eq!(status(&silent), ServerStatus::ZombiePort);
+
+    // Answers something else
+    let garbage = impostor(|mut socket| {
+        let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+    });
+    assert_eq!(status(&garbage), ServerStatus::ZombiePort);
+    let binary = impostor(|mut socket| {
+        let _ = socket.write_all(&[0xff; 300]);
+    });
+    assert_eq!(status(&binary), ServerStatus::ZombiePort);
+
+    // Closes the connection as soon as it is accepted, like a proxy failing to reach its backend
+    let closing = impostor(drop);
+    assert_eq!(status(&closing), ServerStatus::ZombiePort);
+
+    // Nothing listening
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    assert_eq!(status(&closed.to_string()), ServerStatus::Unreachable);
+}
+
+#[test]
+fn empty_commands_are_refused() {
+    let server = TestServer::start();
+
+    for request in ["OPT exit-code\nCMD\n", "OPT exit-code\nCMD \n", "OPT exit-code\nCMD  \t \n"] {
+        let response = String::from_utf8(server.request(request.as_bytes())).unwrap();
+        assert_eq!(response, "Empty command: there is nothing to run.\n__EXIT__ 1\n", "for {:?}", request);
+    }
+
+    for command in ["", "  \t"] {
+        let request = format!("OPT json\n{}\n", serde_json::json!({ "command": command }));
+        let mut response = &server.request(request.as_bytes())[..];
+        let mut events = Vec::new();
+        while let Some((len, rest)) = response.split_first_chunk::<4>() {
+            let (event, rest) = rest.split_at(u32::from_be_bytes(*len) as usize);
+            events.push(serde_json::from_slice::<serde_json::Value>(event).unwrap());
+            response = rest;
+        }
+        assert_eq!(events, [serde_json::json!({ "event": "error", "message": "Empty command: there is nothing to run." })], "for {:?}", command);
+    }
+}
+
+#[test]
+fn command_longer_than_a_read_buffer_runs_whole() {
+    let server = TestServer::start();
+    // Well past the 4 KiB the server reads at a time, yet under cmd's 8191-character limit
+    let word = "0123456789abcdef".repeat(400);
+
+    let request = format!("OPT exit-code\nCMD echo {}\n", word);
+    let response = String::from_utf8(server.request(request.as_bytes())).unwrap();
+    let (output, exit) = response.split_once("__EXIT__ ").expect("no exit line");
+    assert_eq!(output.trim_end(), word);
+    assert!(exit.starts_with("0 "), "unexpected exit line: {}", exit);
+
+    // Past the 64 KiB request limit: refused rather than run cut off
+    let request = format!("OPT exit-code\nCMD echo {}\n", word.repeat(20));
+    let response = String::from_utf8(server.request(request.as_bytes())).unwrap();
+    assert!(response.starts_with("Request too long"), "unexpected response: {:.100}", response);
+    assert!(response.ends_with("__EXIT__ 1\n"));
+}
+
+#[test]
+fn framed_output_carries_every_byte_value() {
+    let server = TestServer::start();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bytes.bin");
+    // Every value, then all of them again in reverse, so each one has other neighbours too
+    let bytes: Vec<u8> = (0..=255u8).chain((0..=255u8).rev()).collect();
+    std::fs::write(&path, &bytes).unwrap();
+
+    let print = if cfg!(windows) { "type" } else { "cat" };
+    let request = format!("OPT exit-code\nOPT framed\nCMD {0} \"{1}\" && {0} \"{1}\" 1>&2\n", print, path.display());
+    let frames = frames(&server.request(request.as_bytes()));
+    let stream = |id: u8| frames.iter().filter(|(i, _)| *i == id).flat_map(|(_, p)| p.clone()).collect::<Vec<_>>();
+    assert_eq!(stream(1), bytes);
+    assert_eq!(stream(2), bytes);
+    let (id, last) = frames.last().unwrap();
+    assert_eq!(*id, 0);
+    assert!(last.starts_with(b"__EXIT__ 0 "), "unexpected end: {}", String::from_utf8_lossy(last));
+}
+
+#[test]
+fn large_output_then_immediate_exit_arrives_complete() {
+    let server = TestServer::start();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("big.txt");
+    // Several MB, printed by a command that exits as soon as the last byte is written
+    const SIZE: usize = 8 * 1024 * 1024;
+    let line = [b"x".repeat(1023), b"\n".to_vec()].concat();
+    std::fs::write(&path, line.repeat(SIZE / line.len())).unwrap();
+    let print = if cfg!(windows) { "type" } else { "cat" };
+
+    // Plain output: every byte, then the exit line
+    let response = server.request(format!("OPT exit-code\nCMD {} \"{}\"\n", print, path.display()).as_bytes());
+    let exit = response.windows(9).position(|w| w == b"__EXIT__ ").expect("no exit line");
+    assert_eq!(exit, SIZE);
+    assert!(response[exit..].starts_with(b"__EXIT__ 0 "), "unexpected end: {}", String::from_utf8_lossy(&response[exit..]));
+
+    // Framed, with the same on stderr as well
+    let request = format!("OPT exit-code\nOPT framed\nCMD {0} \"{1}\" && {0} \"{1}\" 1>&2\n", print, path.display());
+    let frames = frames(&server.request(request.as_bytes()));
+    let (id, last) = frames.last().unwrap();
+    assert_eq!(*id, 0);
+    assert!(last.starts_with(b"__EXIT__ 0 "), "unexpected end: {}", String::from_utf8_lossy(last));
+    for stream in [1, 2] {
+        let received: usize = frames.iter().filter(|(id, _)| *id == stream).map(|(_, payload)| payload.len()).sum();
+        assert_eq!(received, SIZE, "stream {}", stream);
+    }
+}
+
+#[test]
+fn dropped_connections_leave_nothing_for_shutdown_to_wait_on() {
+    let server = TestServer::start();
+    for i in 0..30 {
+        match i % 4 {
+            // Gone before the READY line, after it, and partway through a request
+            0 => drop(TcpStream::connect(server.addr).unwrap()),
+            1 => drop(server.connect()),
+            2 => {
+                let (mut socket, _) = server.connect();
+                socket.write_all(b"PROTO 2\nOPT exit-code\nCMD ech").unwrap();
+            }
+            _ => assert!(server.request(b"OPT exit-code\nCMD exit 0\n").starts_with(b"__EXIT__ 0 ")),
+        }
+    }
+
+    // Shutdown waits for every active connection, so a count that never got back to 0
+    // would hold it for the whole grace period
+    let started = std::time::Instant::now();
+    server.shutdown().expect("run_server failed");
+    assert!(started.elapsed() < Duration::from_secs(5), "shutdown waited {:?}", started.elapsed());
+}
+
+/// Reads frames off a session until the exit line that ends one command's response.
+fn read_response(socket: &mut TcpStream) -> Vec<(u8, Vec<u8>)> {
+    let mut response = Vec::new();
+    loop {
+        let mut header = [0; 5];
+        socket.read_exact(&mut header).unwrap();
+        let mut payload = vec![0; u32::from_be_bytes(header[1..].try_into().unwrap()) as usize];
+        socket.read_exact(&mut payload).unwrap();
+        let done = header[0] == 0 && payload.starts_with(b"__EXIT__");
+        response.push((header[0], payload));
+        if done {
+            return response;
+        }
+    }
+}
+
+// `cmd` stands in for a shell the server doesn't have; Windows servers always have it
+#[cfg(not(windows))]
+#[test]
+fn session_goes_on_after_a_command_fails_to_start() {
+    let server = TestServer::start();
+    let (mut socket, _) = server.connect();
+    socket.write_all(b"PROTO 2\nOPT exit-code\nOPT framed\nOPT session\nOPT shell cmd\nCMD echo one\n").unwrap();
+    // Each command is answered with why it didn't run and its exit line, and the session
+    // then takes the next one
+    for next in [&b"CMD echo two\n"[..], b"\n"] {
+        let response = read_response(&mut socket);
+        assert_eq!(response.len(), 2, "unexpected response: {:?}", response);
+        let message = String::from_utf8_lossy(&response[0].1);
+        assert_eq!(response[0].0, 2);
+        assert!(message.starts_with("Server error: Failed to start cmd"), "unexpected message: {}", message);
+        assert_eq!(response[1], (0, b"__EXIT__ 1\n".to_vec()));
+        socket.write_all(next).unwrap();
+    }
+    // The empty line ended the session
+    let mut rest = Vec::new();
+    socket.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty(), "unexpected output: {:?}", rest);
+}
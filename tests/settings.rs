@@ -0,0 +1,236 @@
+//! Tests that change the server's or the client's settings. Both read them from the process
+//! environment, which every test in a binary shares, so these live apart from the loopback
+//! tests and take turns.
+
+mod common;
+
+use common::{TestServer, HELLO};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use winboat_bridge::{execute_remote, load_settings, run_command, ExitHandling, EXPECT_EXIT_MISMATCH, OutputChecks, OutputFiles, RequestOptions, Role, VtFilter};
+
+static ENV: Mutex<()> = Mutex::new(());
+
+/// Holds the environment for one test, starting from no token settings.
+fn lock_env() -> MutexGuard<'static, ()> {
+    let guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::remove_var("WINBOAT_AUTH_TOKEN");
+    std::env::remove_var("WINBOAT_ADMIN_TOKEN");
+    std::env::remove_var("WINBOAT_BUF_SIZE");
+    std::env::remove_var("WINBOAT_ALLOWLIST");
+    guard
+}
+
+fn write_config(path: &Path, token: &str) {
+    std::fs::write(path, format!("[server]\nauth_token = \"{}\"\n", token)).unwrap();
+}
+
+fn run_as(server: &TestServer, token: &str) -> String {
+    let request = format!("AUTH {}\nOPT exit-code\nCMD exit 0\n", token);
+    String::from_utf8(server.request(request.as_bytes())).unwrap()
+}
+
+#[test]
+fn reload_picks_up_an_edited_config_file() {
+    let _env = lock_env();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("winboat.toml");
+    write_config(&path, "first");
+    load_settings(Some(&path), Role::Server).unwrap();
+    let mut server = TestServer::start();
+    server.shutdown_auth = "AUTH first\n".to_string();
+    assert!(run_as(&server, "first").starts_with("__EXIT__ 0 "));
+
+    write_config(&path, "second");
+    let reply = String::from_utf8(server.request(b"AUTH first\nOPT exit-code\nCTRL RELOAD\n")).unwrap();
+    assert!(reply.starts_with("Configuration reloaded.\n"), "unexpected reply: {}", reply);
+
+    server.shutdown_auth = "AUTH second\n".to_string();
+    assert!(run_as(&server, "first").contains("UNAUTHORIZED"));
+    assert!(run_as(&server, "second").starts_with("__EXIT__ 0 "));
+
+    // A file that no longer parses leaves the settings as they were
+    std::fs::write(&path, "[server\n").unwrap();
+    let reply = String::from_utf8(server.request(b"AUTH second\nOPT exit-code\nCTRL RELOAD\n")).unwrap();
+    assert!(reply.starts_with("Reload failed"), "unexpected reply: {}", reply);
+    assert!(reply.ends_with("__EXIT__ 1\n"));
+    assert!(run_as(&server, "second").starts_with("__EXIT__ 0 "));
+}
+
+#[test]
+fn environment_wins_over_the_config_file_across_reloads() {
+    let _env = lock_env();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("winboat.toml");
+    write_config(&path, "from-file");
+    std::env::set_var("WINBOAT_AUTH_TOKEN", "from-env");
+    load_settings(Some(&path), Role::Server).unwrap();
+    let mut server = TestServer::start();
+    server.shutdown_auth = "AUTH from-env\n".to_string();
+
+    write_config(&path, "edited");
+    let reply = String::from_utf8(server.request(b"AUTH from-env\nOPT exit-code\nCTRL RELOAD\n")).unwrap();
+    assert!(reply.starts_with("Configuration reloaded.\n"), "unexpected reply: {}", reply);
+    assert!(run_as(&server, "edited").contains("UNAUTHORIZED"));
+    assert!(run_as(&server, "from-env").starts_with("__EXIT__ 0 "));
+}
+
+#[test]
+fn admin_token_guards_the_disruptive_control_requests() {
+    let _env = lock_env();
+    std::env::set_var("WINBOAT_ADMIN_TOKEN", "operator");
+    let mut server = TestServer::start();
+    server.shutdown_auth = "OPT admin-token operator\n".to_string();
+
+    for request in ["KILL 1", "KILL-ALL", "RELOAD", "SHUTDOWN"] {
+        for admin in ["", "OPT admin-token wrong\n"] {
+            let reply = server.request(format!("{}OPT exit-code\nCTRL {}\n", admin, request).as_bytes());
+            let reply = String::from_utf8(reply).unwrap();
+            assert!(reply.starts_with("FORBIDDEN"), "{} with {:?} got: {}", request, admin, reply);
+            assert!(reply.ends_with("__EXIT__ 1\n"));
+        }
+    }
+    let reply = String::from_utf8(server.request(b"OPT admin-token operator\nOPT exit-code\nCTRL KILL-ALL\n")).unwrap();
+    assert_eq!(reply, "Killed 0 running command(s).\n__EXIT__ 0\n");
+
+    // Read-only requests and commands don't need it
+    let reply = String::from_utf8(server.request(b"OPT exit-code\nCTRL PING\n")).unwrap();
+    assert!(reply.ends_with("__EXIT__ 0\n"), "unexpected reply: {}", reply);
+    let reply = String::from_utf8(server.request(b"OPT exit-code\nCMD exit 0\n")).unwrap();
+    assert!(reply.starts_with("__EXIT__ 0 "), "unexpected reply: {}", reply);
+}
+
+#[test]
+fn each_stream_goes_to_its_own_file() {
+    let _env = lock_env();
+    let server = TestServer::start();
+    std::env::set_var("WINBOAT_CLIENT_PORT", server.addr.port().to_string());
+    std::env::set_var("WINBOAT_BOOTSTRAP", "off");
+    let dir = tempfile::tempdir().unwrap();
+    let files = OutputFiles { stdout: Some(dir.path().join("out.log")), stderr: Some(dir.path().join("err.log")), append: false };
+    std::fs::write(files.stdout.as_ref().unwrap(), "left over from an earlier run\n").unwrap();
+
+    let options = RequestOptions { exit_code: true, framed: true, ..Default::default() };
+    let run = |files: &OutputFiles| {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let checks = OutputChecks::new(None, None);
+        runtime.block_on(run_command("echo hello && echo oops 1>&2", &options, checks, VtFilter::new(false), false, files, &ExitHandling::default())).unwrap()
+    };
+    assert_eq!(run(&files), 0);
+    let read = |path: &Option<std::path::PathBuf>| std::fs::read_to_string(path.as_ref().unwrap()).unwrap();
+    assert_eq!(read(&files.stdout), HELLO);
+    assert!(read(&files.stderr).starts_with("oops"), "unexpected stderr: {:?}", read(&files.stderr));
+
+    let files = OutputFiles { append: true, ..files };
+    assert_eq!(run(&files), 0);
+    assert_eq!(read(&files.stdout), HELLO.repeat(2));
+}
+
+#[test]
+fn exit_handling_decides_the_client_exit_code() {
+    let _env = lock_env();
+    let server = TestServer::start();
+    std::env::set_var("WINBOAT_CLIENT_PORT", server.addr.port().to_string());
+    std::env::set_var("WINBOAT_BOOTSTRAP", "off");
+
+    let options = RequestOptions { exit_code: true, framed: true, ..Default::default() };
+    let run = |map: &str, expect: &[i32]| {
+        let exit = ExitHandling { map: map.parse().unwrap(), expect: expect.to_vec() };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let checks = OutputChecks::new(None, None);
+        runtime.block_on(run_command("exit 3", &options, checks, VtFilter::new(false), false, &OutputFiles::default(), &exit)).unwrap()
+    };
+    // --map-exit
+    assert_eq!(run("3=0", &[]), 0);
+    assert_eq!(run("1=0,3=7", &[]), 7);
+    assert_eq!(run("4=0", &[]), 3);
+    // --expect-exit, after the mapping
+    assert_eq!(run("4=0", &[0, 3]), 0);
+    assert_eq!(run("4=0", &[0]), EXPECT_EXIT_MISMATCH);
+    assert_eq!(run("3=7", &[7]), 0);
+}
+
+#[test]
+fn wrong_token_ends_a_session_before_its_first_command() {
+    let _env = lock_env();
+    std::env::set_var("WINBOAT_AUTH_TOKEN", "shared");
+    let mut server = TestServer::start();
+    server.shutdown_auth = "AUTH shared\n".to_string();
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("ran");
+    let touch = if cfg!(windows) { format!("echo x > \"{}\"", marker.display()) } else { format!("touch '{}'", marker.display()) };
+
+    // Refused with a reason the client can tell from a failed command, and nothing runs
+    let request = format!("AUTH wrong\nOPT exit-code\nOPT framed\nOPT session\nCMD {}\nCMD {}\n", touch, touch);
+    let frames = common::frames(&server.request(request.as_bytes()));
+    assert_eq!(frames.len(), 2, "unexpected response: {:?}", frames);
+    assert_eq!(frames[0], (2, b"UNAUTHORIZED\n".to_vec()));
+    assert_eq!(frames[1], (0, b"__EXIT__ 1 Unauthorized\n".to_vec()));
+    assert!(!marker.exists());
+
+    // The client turns that into one clear error instead of a failed command
+    std::env::set_var("WINBOAT_CLIENT_PORT", server.addr.port().to_string());
+    std::env::set_var("WINBOAT_BOOTSTRAP", "off");
+    let options = RequestOptions { auth_token: Some("wrong".to_string()), ..Default::default() };
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let error = runtime.block_on(execute_remote("exit 0", &options)).unwrap_err();
+    assert!(format!("{:#}", error).contains("Authentication rejected by the server"), "unexpected error: {:#}", error);
+    let options = RequestOptions { auth_token: Some("shared".to_string()), ..Default::default() };
+    assert_eq!(runtime.block_on(execute_remote("exit 0", &options)).unwrap().exit_code, 0);
+}
+
+#[test]
+fn buf_size_sets_the_read_size_of_the_output() {
+    let _env = lock_env();
+    std::env::set_var("WINBOAT_BUF_SIZE", "1024");
+    let server = TestServer::start();
+    std::env::remove_var("WINBOAT_BUF_SIZE");
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bytes.bin");
+    let bytes: Vec<u8> = (0..=255u8).cycle().take(300 * 1024).collect();
+    std::fs::write(&path, &bytes).unwrap();
+
+    let print = if cfg!(windows) { "type" } else { "cat" };
+    let frames = common::frames(&server.request(format!("OPT exit-code\nOPT framed\nCMD {} \"{}\"\n", print, path.display()).as_bytes()));
+    let stdout: Vec<_> = frames.iter().filter(|(id, _)| *id == 1).map(|(_, payload)| payload).collect();
+    // Read 1 KiB at a time, and still whole and in order
+    assert!(stdout.iter().all(|payload| payload.len() <= 1024), "a frame is larger than the buffer");
+    assert_eq!(stdout.into_iter().flatten().copied().collect::<Vec<_>>(), bytes);
+}
+
+#[test]
+fn auth_token_is_required_only_when_set() {
+    let _env = lock_env();
+    // An open server runs requests with or without a token
+    let server = TestServer::start();
+    let reply = String::from_utf8(server.request(b"OPT exit-code\nCMD exit 0\n")).unwrap();
+    assert!(reply.starts_with("__EXIT__ 0 "), "unexpected reply: {}", reply);
+    assert!(run_as(&server, "anything").starts_with("__EXIT__ 0 "));
+    server.shutdown().expect("run_server failed");
+
+    std::env::set_var("WINBOAT_AUTH_TOKEN", "shared");
+    let mut server = TestServer::start();
+    server.shutdown_auth = "AUTH shared\n".to_string();
+    let reply = String::from_utf8(server.request(b"OPT exit-code\nCMD exit 0\n")).unwrap();
+    assert_eq!(reply, "UNAUTHORIZED\n__EXIT__ 1 Unauthorized\n");
+    assert_eq!(run_as(&server, "wrong"), "UNAUTHORIZED\n__EXIT__ 1 Unauthorized\n");
+    assert!(run_as(&server, "shared").starts_with("__EXIT__ 0 "));
+}
+
+#[test]
+fn allowlist_denies_commands_it_does_not_cover() {
+    let _env = lock_env();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("allowlist.txt");
+    std::fs::write(&path, "# exact, then a prefix\nexit 0\necho *\n").unwrap();
+    std::env::set_var("WINBOAT_ALLOWLIST", &path);
+    let server = TestServer::start();
+    std::env::remove_var("WINBOAT_ALLOWLIST");
+
+    let run = |command: &str| String::from_utf8(server.request(format!("OPT exit-code\nCMD {}\n", command).as_bytes())).unwrap();
+    assert!(run("exit 0").starts_with("__EXIT__ 0 "));
+    assert!(run("echo hello").starts_with(HELLO));
+    for denied in ["exit 1", "echo hello && exit 1", "whoami"] {
+        assert_eq!(run(denied), "DENIED\n__EXIT__ 1\n", "{}", denied);
+    }
+}